@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use keepass::Database;
+
+fuzz_target!(|data: &[u8]| {
+    // most databases in the corpus use this password, so that mutations can reach the payload
+    let _ = Database::open(&mut &data[..], Some("demopass"), None);
+});
@@ -51,7 +51,7 @@ pub fn main() -> Result<()> {
         let chunk_fn = format!("db-{}.xml", i);
         let mut chunk_file = File::create(chunk_fn).expect("Open chunk XML file");
 
-        chunk_file.write(chunk)?;
+        chunk_file.write_all(chunk)?;
     }
 
     println!("Wrote {} chunks", chunks.len());
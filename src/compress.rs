@@ -1,49 +1,34 @@
 use super::result::Result;
-use flate2::read::GzDecoder;
+use crate::stream::FinishWrite;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use std::io::{Read, Write};
+use std::io::Write;
 
-pub trait Compressible: Decompress + Compress {}
-pub trait Decompress {
-    fn decompress(&self, in_buffer: &[u8]) -> Result<Vec<u8>>;
-}
 pub trait Compress {
     fn compress(&self, in_buffer: &[u8]) -> Result<Vec<u8>>;
 }
 
 pub struct NoCompression;
 
-impl Decompress for NoCompression {
-    fn decompress(&self, in_buffer: &[u8]) -> Result<Vec<u8>> {
-        Ok(in_buffer.to_vec())
-    }
-}
-
 pub struct GZipCompression;
 
-impl Decompress for GZipCompression {
-    fn decompress(&self, in_buffer: &[u8]) -> Result<Vec<u8>> {
-        let mut res = Vec::new();
-        let mut decoder = GzDecoder::new(in_buffer);
-        decoder.read_to_end(&mut res)?;
-        Ok(res)
-    }
-}
-
 impl Compress for NoCompression {
     fn compress(&self, in_buffer: &[u8]) -> Result<Vec<u8>> {
         Ok(in_buffer.to_vec())
     }
 }
-impl Compressible for NoCompression {}
-impl Compressible for GZipCompression {}
 
 impl Compress for GZipCompression {
     fn compress(&self, in_buffer: &[u8]) -> Result<Vec<u8>> {
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
         encoder.write_all(in_buffer)?;
-        Ok(encoder.finish().unwrap())
+        Ok(encoder.finish()?)
+    }
+}
+
+impl<'a> FinishWrite for GzEncoder<Box<dyn FinishWrite + 'a>> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        GzEncoder::finish(*self)?.finish()
     }
 }
 
@@ -52,7 +37,11 @@ fn test_decompress_compress_decompress() -> Result<()> {
     let data = "hi this is a test";
     let bdata: Vec<u8> = data.as_bytes().to_vec();
     let compressed = GZipCompression.compress(&bdata)?;
-    let decompressed = GZipCompression.decompress(&compressed)?;
+    let mut decompressed = Vec::new();
+    std::io::Read::read_to_end(
+        &mut flate2::read::GzDecoder::new(&compressed[..]),
+        &mut decompressed,
+    )?;
     assert_eq!(bdata, decompressed);
     Ok(())
 }
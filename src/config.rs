@@ -1,10 +1,12 @@
 use hex_literal::hex;
 use std::collections::HashMap;
 use std::convert::{From, TryFrom};
+use std::io::Read;
 
 use crate::{
     compress, crypt,
     result::{DatabaseIntegrityError, Error, Result},
+    stream::FinishWrite,
     variant_dictionary::VariantDictionary,
     variant_dictionary::VariantDictionaryValue,
 };
@@ -14,7 +16,7 @@ const CIPHERSUITE_AES256: [u8; 16] = hex!("31c1f2e6bf714350be5805216afc5aff");
 const CIPHERSUITE_TWOFISH: [u8; 16] = hex!("ad68f29f576f4bb9a36ad47af965346c");
 const CIPHERSUITE_CHACHA20: [u8; 16] = hex!("d6038a2b8b6f4cb5a524339a31dbb59a");
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OuterCipherSuite {
     AES256,
     Twofish,
@@ -37,6 +39,63 @@ impl OuterCipherSuite {
     }
 }
 
+impl OuterCipherSuite {
+    /// Wrap a reader of encrypted data into a reader that decrypts it on the fly
+    pub(crate) fn get_decrypt_reader<'a>(
+        &self,
+        key: &[u8],
+        iv: &[u8],
+        source: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>> {
+        match self {
+            OuterCipherSuite::AES256 => Ok(Box::new(crypt::ciphers::CbcDecryptReader::<
+                _,
+                aes::Aes256,
+            >::new(source, key, iv)?)),
+            OuterCipherSuite::Twofish => Ok(Box::new(crypt::ciphers::CbcDecryptReader::<
+                _,
+                twofish::Twofish,
+            >::new(source, key, iv)?)),
+            OuterCipherSuite::ChaCha20 => Ok(Box::new(crypt::ciphers::StreamDecryptReader::new(
+                source,
+                Box::new(crypt::ciphers::ChaCha20Cipher::new_key_iv(key, iv)?),
+            ))),
+        }
+    }
+
+    /// Wrap a writer into a writer that encrypts everything that is written to it
+    pub(crate) fn get_encrypt_writer<'a>(
+        &self,
+        key: &[u8],
+        iv: &[u8],
+        sink: Box<dyn FinishWrite + 'a>,
+    ) -> Result<Box<dyn FinishWrite + 'a>> {
+        match self {
+            OuterCipherSuite::AES256 => Ok(Box::new(crypt::ciphers::CbcEncryptWriter::<
+                _,
+                aes::Aes256,
+            >::new(sink, key, iv)?)),
+            OuterCipherSuite::Twofish => Ok(Box::new(crypt::ciphers::CbcEncryptWriter::<
+                _,
+                twofish::Twofish,
+            >::new(sink, key, iv)?)),
+            OuterCipherSuite::ChaCha20 => Ok(Box::new(crypt::ciphers::StreamEncryptWriter::new(
+                sink,
+                Box::new(crypt::ciphers::ChaCha20Cipher::new_key_iv(key, iv)?),
+            ))),
+        }
+    }
+
+    /// Size of the initialization vector that this cipher suite expects
+    pub(crate) fn iv_size(&self) -> usize {
+        match self {
+            OuterCipherSuite::AES256 => 16,
+            OuterCipherSuite::Twofish => 16,
+            OuterCipherSuite::ChaCha20 => 12,
+        }
+    }
+}
+
 impl From<&OuterCipherSuite> for Vec<u8> {
     fn from(c: &OuterCipherSuite) -> Vec<u8> {
         match c {
@@ -61,7 +120,7 @@ impl TryFrom<&[u8]> for OuterCipherSuite {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InnerCipherSuite {
     Plain,
     Salsa20,
@@ -78,6 +137,17 @@ impl InnerCipherSuite {
     }
 }
 
+impl InnerCipherSuite {
+    /// Size of the random stream key that is generated for a new database
+    pub(crate) fn key_size(&self) -> usize {
+        match self {
+            InnerCipherSuite::Plain => 32,
+            InnerCipherSuite::Salsa20 => 32,
+            InnerCipherSuite::ChaCha20 => 64,
+        }
+    }
+}
+
 impl TryFrom<u32> for InnerCipherSuite {
     type Error = Error;
 
@@ -101,7 +171,7 @@ impl From<&InnerCipherSuite> for u32 {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum KdfSettings {
     Aes {
         seed: Vec<u8>,
@@ -138,6 +208,62 @@ impl KdfSettings {
             }),
         }
     }
+
+    /// The random seed (AES) or salt (Argon2) of the key derivation
+    pub(crate) fn seed(&self) -> &[u8] {
+        match self {
+            KdfSettings::Aes { seed, .. } => seed,
+            KdfSettings::Argon2 { salt, .. } => salt,
+        }
+    }
+
+    /// A copy of the settings with a new random seed / salt of the same size
+    pub(crate) fn with_new_seed(&self) -> Result<KdfSettings> {
+        let mut settings = self.clone();
+        match &mut settings {
+            KdfSettings::Aes { seed, .. } => *seed = crypt::random_bytes(seed.len())?,
+            KdfSettings::Argon2 { salt, .. } => *salt = crypt::random_bytes(salt.len())?,
+        }
+        Ok(settings)
+    }
+}
+
+/// Key derivation parameters for a new database. The salt / seed is generated randomly when the
+/// actual `KdfSettings` are created.
+#[derive(Debug, PartialEq)]
+pub enum KdfConfig {
+    Aes {
+        rounds: u64,
+    },
+    Argon2 {
+        memory: u64,
+        iterations: u64,
+        parallelism: u32,
+        version: argon2::Version,
+    },
+}
+
+impl KdfConfig {
+    pub(crate) fn to_kdf_settings(&self) -> Result<KdfSettings> {
+        match self {
+            KdfConfig::Aes { rounds } => Ok(KdfSettings::Aes {
+                seed: crypt::random_bytes(32)?,
+                rounds: *rounds,
+            }),
+            KdfConfig::Argon2 {
+                memory,
+                iterations,
+                parallelism,
+                version,
+            } => Ok(KdfSettings::Argon2 {
+                memory: *memory,
+                salt: crypt::random_bytes(32)?,
+                iterations: *iterations,
+                parallelism: *parallelism,
+                version: *version,
+            }),
+        }
+    }
 }
 
 const KDF_AES_KDBX3: [u8; 16] = hex!("c9d9f39a628a4460bf740d08c18a4fea");
@@ -208,7 +334,6 @@ impl From<&KdfSettings> for VariantDictionary {
                 parallelism,
                 version,
             } => {
-                let mut data = HashMap::<String, VariantDictionaryValue>::new();
                 data.insert(
                     "$UUID".to_string(),
                     VariantDictionaryValue::ByteArray(KDF_ARGON2.to_vec()),
@@ -235,20 +360,44 @@ impl From<&KdfSettings> for VariantDictionary {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Compression {
     None,
     GZip,
 }
 
 impl Compression {
-    pub(crate) fn get_compression(&self) -> Box<dyn compress::Compressible> {
+    pub(crate) fn get_compression(&self) -> Box<dyn compress::Compress> {
         match self {
-            // This has to return a decompressable/compressable
             Compression::None => Box::new(compress::NoCompression),
             Compression::GZip => Box::new(compress::GZipCompression),
         }
     }
+
+    /// Wrap a reader of compressed data into a reader that decompresses it on the fly
+    pub(crate) fn get_decompress_reader<'a>(
+        &self,
+        source: Box<dyn Read + 'a>,
+    ) -> Box<dyn Read + 'a> {
+        match self {
+            Compression::None => source,
+            Compression::GZip => Box::new(flate2::read::GzDecoder::new(source)),
+        }
+    }
+
+    /// Wrap a writer into a writer that compresses everything that is written to it
+    pub(crate) fn get_compress_writer<'a>(
+        &self,
+        sink: Box<dyn FinishWrite + 'a>,
+    ) -> Box<dyn FinishWrite + 'a> {
+        match self {
+            Compression::None => sink,
+            Compression::GZip => Box::new(flate2::write::GzEncoder::new(
+                sink,
+                flate2::Compression::default(),
+            )),
+        }
+    }
 }
 
 impl TryFrom<u32> for Compression {
@@ -271,3 +420,29 @@ impl From<&Compression> for u32 {
         }
     }
 }
+
+/// Settings used to create a new database with `Database::new`
+#[derive(Debug, PartialEq)]
+pub struct DatabaseConfig {
+    pub outer_cipher: OuterCipherSuite,
+    pub compression: Compression,
+    pub inner_cipher: InnerCipherSuite,
+    pub kdf: KdfConfig,
+}
+
+/// Same defaults as KeePassXC uses for new KDBX4 databases
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            outer_cipher: OuterCipherSuite::AES256,
+            compression: Compression::GZip,
+            inner_cipher: InnerCipherSuite::ChaCha20,
+            kdf: KdfConfig::Argon2 {
+                memory: 64 * 1024 * 1024,
+                iterations: 10,
+                parallelism: 2,
+                version: argon2::Version::Version13,
+            },
+        }
+    }
+}
@@ -1,9 +1,19 @@
-use crate::result::{CryptoError, DatabaseIntegrityError, Error, Result};
+use crate::{
+    result::{CryptoError, DatabaseIntegrityError, Error, Result},
+    stream::FinishWrite,
+};
 
 use aes::Aes256;
-use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
-use cipher::{generic_array::GenericArray, StreamCipher};
+use block_modes::{
+    block_padding::{Padding, Pkcs7},
+    BlockMode, BlockModeError, Cbc,
+};
+use cipher::{
+    generic_array::{typenum::Unsigned, GenericArray},
+    BlockCipher, BlockDecrypt, BlockEncrypt, NewBlockCipher, StreamCipher,
+};
 use salsa20::{cipher::NewCipher, Salsa20};
+use std::io::{Read, Write};
 
 pub(crate) trait Cipher {
     fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>>;
@@ -31,7 +41,7 @@ impl Cipher for AES256Cipher {
             .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
 
         let res = cipher
-            .decrypt_vec(&ciphertext)
+            .decrypt_vec(ciphertext)
             .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
 
         Ok(res)
@@ -65,7 +75,7 @@ impl Cipher for TwofishCipher {
             .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
 
         let res = cipher
-            .decrypt_vec(&ciphertext)
+            .decrypt_vec(ciphertext)
             .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
 
         Ok(res)
@@ -83,11 +93,11 @@ pub(crate) struct Salsa20Cipher {
 
 impl Salsa20Cipher {
     pub(crate) fn new(key: &[u8]) -> Result<Self> {
-        let key = GenericArray::from_slice(key);
-        let iv = GenericArray::from([0xE8, 0x30, 0x09, 0x4B, 0x97, 0x20, 0x5D, 0x2A]);
+        let iv = [0xE8, 0x30, 0x09, 0x4B, 0x97, 0x20, 0x5D, 0x2A];
 
         Ok(Salsa20Cipher {
-            cipher: Salsa20::new(&key, &iv),
+            cipher: Salsa20::new_from_slices(key, &iv)
+                .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?,
         })
     }
 }
@@ -116,14 +126,14 @@ impl ChaCha20Cipher {
         let nonce = GenericArray::from_slice(&iv[32..44]);
 
         Ok(ChaCha20Cipher {
-            cipher: chacha20::ChaCha20::new(&key, &nonce),
+            cipher: chacha20::ChaCha20::new(key, nonce),
         })
     }
 
     /// Create as an outer cipher by separately-specified key and iv
     pub(crate) fn new_key_iv(key: &[u8], iv: &[u8]) -> Result<Self> {
         Ok(ChaCha20Cipher {
-            cipher: chacha20::ChaCha20::new_from_slices(&key, &iv)
+            cipher: chacha20::ChaCha20::new_from_slices(key, iv)
                 .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?,
         })
     }
@@ -156,6 +166,224 @@ impl Cipher for PlainCipher {
     }
 }
 
+/// Size of the chunks that are read from the underlying reader when decrypting a stream
+const DECRYPT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reader that decrypts a CBC-encrypted stream with PKCS7 padding chunk by chunk
+pub(crate) struct CbcDecryptReader<R: Read, C: BlockCipher + BlockEncrypt + BlockDecrypt> {
+    inner: R,
+    cipher: Cbc<C, Pkcs7>,
+    // ciphertext that has not been decrypted yet
+    pending: Vec<u8>,
+    // decrypted data that has not been returned yet
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R, C> CbcDecryptReader<R, C>
+where
+    R: Read,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + NewBlockCipher,
+{
+    pub(crate) fn new(inner: R, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let cipher = Cbc::<C, Pkcs7>::new_from_slices(key, iv)
+            .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
+
+        Ok(CbcDecryptReader {
+            inner,
+            cipher,
+            pending: Vec::new(),
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn decrypt_chunk(&mut self) -> Result<()> {
+        let block_size = C::BlockSize::to_usize();
+
+        let read = (&mut self.inner)
+            .take(DECRYPT_CHUNK_SIZE as u64)
+            .read_to_end(&mut self.pending)?;
+        let eof = read < DECRYPT_CHUNK_SIZE;
+
+        // the last block holds the padding, so keep it back until the stream has ended
+        let decrypt_len = if eof {
+            if self.pending.is_empty() || self.pending.len() % block_size != 0 {
+                return Err(DatabaseIntegrityError::from(CryptoError::from(BlockModeError)).into());
+            }
+            self.pending.len()
+        } else {
+            (self.pending.len().saturating_sub(1) / block_size) * block_size
+        };
+
+        let mut blocks: Vec<_> = self.pending[..decrypt_len]
+            .chunks_exact(block_size)
+            .map(GenericArray::clone_from_slice)
+            .collect();
+        self.cipher.decrypt_blocks(&mut blocks);
+        self.pending.drain(..decrypt_len);
+
+        self.plain = blocks.iter().flat_map(|b| b.iter().copied()).collect();
+        self.pos = 0;
+
+        if eof {
+            let len = Pkcs7::unpad(&self.plain)
+                .map_err(|_| DatabaseIntegrityError::from(CryptoError::from(BlockModeError)))?
+                .len();
+            self.plain.truncate(len);
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R, C> Read for CbcDecryptReader<R, C>
+where
+    R: Read,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + NewBlockCipher,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plain.len() && !self.done {
+            self.decrypt_chunk()?;
+        }
+
+        let n = std::cmp::min(buf.len(), self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Reader that decrypts a stream using a stream cipher such as ChaCha20
+pub(crate) struct StreamDecryptReader<R: Read> {
+    inner: R,
+    cipher: Box<dyn Cipher>,
+}
+
+impl<R: Read> StreamDecryptReader<R> {
+    pub(crate) fn new(inner: R, cipher: Box<dyn Cipher>) -> Self {
+        StreamDecryptReader { inner, cipher }
+    }
+}
+
+impl<R: Read> Read for StreamDecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let plain = self.cipher.decrypt(&buf[..n])?;
+        buf[..n].copy_from_slice(&plain);
+        Ok(n)
+    }
+}
+
+/// Writer that encrypts a stream with CBC and PKCS7 padding, writing out every full block
+pub(crate) struct CbcEncryptWriter<W: FinishWrite, C: BlockCipher + BlockEncrypt + BlockDecrypt> {
+    inner: W,
+    cipher: Cbc<C, Pkcs7>,
+    // plaintext that does not fill a whole block yet
+    pending: Vec<u8>,
+}
+
+impl<W, C> CbcEncryptWriter<W, C>
+where
+    W: FinishWrite,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + NewBlockCipher,
+{
+    pub(crate) fn new(inner: W, key: &[u8], iv: &[u8]) -> Result<Self> {
+        let cipher = Cbc::<C, Pkcs7>::new_from_slices(key, iv)
+            .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
+
+        Ok(CbcEncryptWriter {
+            inner,
+            cipher,
+            pending: Vec::new(),
+        })
+    }
+
+    fn encrypt_blocks(&mut self, len: usize) -> std::io::Result<()> {
+        let block_size = C::BlockSize::to_usize();
+
+        let mut blocks: Vec<_> = self.pending[..len]
+            .chunks_exact(block_size)
+            .map(GenericArray::clone_from_slice)
+            .collect();
+        self.cipher.encrypt_blocks(&mut blocks);
+        self.pending.drain(..len);
+
+        for block in blocks {
+            self.inner.write_all(&block)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W, C> Write for CbcEncryptWriter<W, C>
+where
+    W: FinishWrite,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + NewBlockCipher,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let block_size = C::BlockSize::to_usize();
+
+        self.pending.extend_from_slice(buf);
+        let len = (self.pending.len() / block_size) * block_size;
+        self.encrypt_blocks(len)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W, C> FinishWrite for CbcEncryptWriter<W, C>
+where
+    W: FinishWrite,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + NewBlockCipher,
+{
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        let block_size = C::BlockSize::to_usize();
+
+        // PKCS7 padding always adds between 1 and block_size bytes
+        let padding = block_size - self.pending.len();
+        self.pending.resize(block_size, padding as u8);
+        self.encrypt_blocks(block_size)?;
+
+        Box::new(self.inner).finish()
+    }
+}
+
+/// Writer that encrypts a stream using a stream cipher such as ChaCha20
+pub(crate) struct StreamEncryptWriter<W: FinishWrite> {
+    inner: W,
+    cipher: Box<dyn Cipher>,
+}
+
+impl<W: FinishWrite> StreamEncryptWriter<W> {
+    pub(crate) fn new(inner: W, cipher: Box<dyn Cipher>) -> Self {
+        StreamEncryptWriter { inner, cipher }
+    }
+}
+
+impl<W: FinishWrite> Write for StreamEncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let encrypted = self.cipher.encrypt(buf)?;
+        self.inner.write_all(&encrypted)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for StreamEncryptWriter<W> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        Box::new(self.inner).finish()
+    }
+}
+
 #[test]
 fn test_decrypt_encrypt_plain() -> Result<()> {
     let data = "hi this is a test";
@@ -165,3 +393,99 @@ fn test_decrypt_encrypt_plain() -> Result<()> {
     assert_eq!(bdata, decrypted);
     Ok(())
 }
+
+#[test]
+fn test_cbc_decrypt_reader() -> Result<()> {
+    let key = [1u8; 32];
+    let iv = [2u8; 16];
+
+    // cover an empty plaintext, a full padding block and multiple chunks
+    for len in [
+        0,
+        15,
+        16,
+        17,
+        DECRYPT_CHUNK_SIZE - 1,
+        DECRYPT_CHUNK_SIZE,
+        3 * DECRYPT_CHUNK_SIZE + 5,
+    ] {
+        let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let encrypted = AES256Cipher::new(&key, &iv)?.encrypt(&plain)?;
+
+        let mut decrypted = Vec::new();
+        CbcDecryptReader::<_, Aes256>::new(&encrypted[..], &key, &iv)?
+            .read_to_end(&mut decrypted)?;
+        assert_eq!(decrypted, plain);
+
+        let mut decrypted = Vec::new();
+        let res = CbcDecryptReader::<_, Aes256>::new(&encrypted[..encrypted.len() - 1], &key, &iv)?
+            .read_to_end(&mut decrypted);
+        assert!(res.is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stream_decrypt_reader() -> Result<()> {
+    let key = [1u8; 32];
+    let iv = [2u8; 12];
+    let plain = vec![42u8; DECRYPT_CHUNK_SIZE + 5];
+    let encrypted = ChaCha20Cipher::new_key_iv(&key, &iv)?.encrypt(&plain)?;
+
+    let mut decrypted = Vec::new();
+    StreamDecryptReader::new(
+        &encrypted[..],
+        Box::new(ChaCha20Cipher::new_key_iv(&key, &iv)?),
+    )
+    .read_to_end(&mut decrypted)?;
+    assert_eq!(decrypted, plain);
+    Ok(())
+}
+
+#[test]
+fn test_cbc_encrypt_writer() -> Result<()> {
+    let key = [1u8; 32];
+    let iv = [2u8; 16];
+
+    for len in [0, 15, 16, 17, 1000] {
+        let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+        // write in uneven pieces to cover partial blocks
+        let mut encrypted = Vec::new();
+        let mut writer = Box::new(CbcEncryptWriter::<_, Aes256>::new(
+            &mut encrypted,
+            &key,
+            &iv,
+        )?);
+        for chunk in plain.chunks(7) {
+            writer.write_all(chunk)?;
+        }
+        writer.finish()?;
+
+        assert_eq!(encrypted, AES256Cipher::new(&key, &iv)?.encrypt(&plain)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stream_encrypt_writer() -> Result<()> {
+    let key = [1u8; 32];
+    let iv = [2u8; 12];
+    let plain = vec![42u8; 1000];
+
+    let mut encrypted = Vec::new();
+    let mut writer = Box::new(StreamEncryptWriter::new(
+        &mut encrypted,
+        Box::new(ChaCha20Cipher::new_key_iv(&key, &iv)?),
+    ));
+    for chunk in plain.chunks(7) {
+        writer.write_all(chunk)?;
+    }
+    writer.finish()?;
+
+    assert_eq!(
+        encrypted,
+        ChaCha20Cipher::new_key_iv(&key, &iv)?.encrypt(&plain)?
+    );
+    Ok(())
+}
@@ -20,7 +20,8 @@ impl Kdf for AesKdf {
         &self,
         composite_key: &GenericArray<u8, U32>,
     ) -> Result<GenericArray<u8, U32>> {
-        let cipher = Aes256::new(&GenericArray::clone_from_slice(&self.seed));
+        let cipher = Aes256::new_from_slice(&self.seed)
+            .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
         let mut block1 = GenericArray::clone_from_slice(&composite_key[..16]);
         let mut block2 = GenericArray::clone_from_slice(&composite_key[16..]);
         for _ in 0..self.rounds {
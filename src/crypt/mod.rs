@@ -1,10 +1,11 @@
 use super::result::{CryptoError, DatabaseIntegrityError, Error, Result};
 use cipher::generic_array::{
-    typenum::{U32, U64},
+    typenum::{U20, U32, U64},
     GenericArray,
 };
 
 use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
 use sha2::{Digest, Sha256, Sha512};
 
 pub(crate) mod ciphers;
@@ -23,6 +24,19 @@ pub(crate) fn calculate_hmac(elements: &[&[u8]], key: &[u8]) -> Result<GenericAr
     Ok(result.into_bytes())
 }
 
+pub(crate) fn calculate_hmac_sha1(elements: &[&[u8]], key: &[u8]) -> Result<GenericArray<u8, U20>> {
+    type HmacSha1 = Hmac<Sha1>;
+    let mut mac = HmacSha1::new_from_slice(key)
+        .map_err(|e| Error::from(DatabaseIntegrityError::from(CryptoError::from(e))))?;
+
+    for element in elements {
+        mac.update(element);
+    }
+
+    let result = mac.finalize();
+    Ok(result.into_bytes())
+}
+
 pub(crate) fn calculate_sha256(elements: &[&[u8]]) -> Result<GenericArray<u8, U32>> {
     let mut digest = Sha256::new();
 
@@ -42,3 +56,9 @@ pub(crate) fn calculate_sha512(elements: &[&[u8]]) -> Result<GenericArray<u8, U6
 
     Ok(digest.finalize())
 }
+
+pub(crate) fn random_bytes(size: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; size];
+    getrandom::getrandom(&mut buf).map_err(std::io::Error::from)?;
+    Ok(buf)
+}
@@ -1,15 +1,21 @@
+use indexmap::IndexMap;
 use secstr::SecStr;
 use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::sync::{Mutex, PoisonError};
 
 use crate::{
+    config::DatabaseConfig,
     crypt,
+    key::DatabaseKey,
     otp::TOTP,
     parse::{
         kdb::KDBHeader,
         kdbx3::KDBX3Header,
         kdbx4::{KDBX4Header, KDBX4InnerHeader},
     },
-    result::{DatabaseIntegrityError, Error, Result},
+    result::{DatabaseIntegrityError, Error, Result, SerializationError},
+    uuid::Uuid,
 };
 
 #[derive(Debug, PartialEq)]
@@ -33,7 +39,7 @@ pub enum DBVersion {
 }
 
 /// A decrypted KeePass database
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct Database {
     /// Header information of the KeePass database
     pub header: Header,
@@ -47,94 +53,724 @@ pub struct Database {
     // Metadata of the KeePass database
     pub meta: Meta,
 
+    /// Entries and groups that were deleted, so that synchronization does not bring them back
+    pub deleted_objects: Vec<DeletedObject>,
+
+    /// Elements of the XML `Root` element besides the root group and the deleted objects that
+    /// are not modelled, which are written back unchanged
+    pub unhandled_root_fields: Vec<xmltree::Element>,
+
+    /// Elements of the XML document besides `Meta` and `Root` that are not modelled, which are
+    /// written back unchanged
+    pub unhandled_fields: Vec<xmltree::Element>,
+
     pub version: DBVersion,
+
+    /// The key the database was opened with, or was last given through `change_key`
+    pub(crate) key: Option<DatabaseKey>,
+
+    /// The position of every group and entry, as the indices of the children to descend into
+    /// from the root group
+    ///
+    /// It is updated by the `Database` methods that change the tree. Lookups check that it is
+    /// still correct and build it again otherwise, since the tree can also be changed through
+    /// the public fields, which is why it is behind a lock.
+    pub(crate) uuid_index: Mutex<HashMap<Uuid, Vec<usize>>>,
+}
+
+/// Databases are compared by their contents, regardless of the key they are encrypted with
+impl PartialEq for Database {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header
+            && self.inner_header == other.inner_header
+            && self.root == other.root
+            && self.meta == other.meta
+            && self.deleted_objects == other.deleted_objects
+            && self.unhandled_root_fields == other.unhandled_root_fields
+            && self.unhandled_fields == other.unhandled_fields
+            && self.version == other.version
+    }
 }
 
 impl Database {
-    /// Parse a database from a std::io::Read
-    pub fn open(
-        source: &mut dyn std::io::Read,
-        password: Option<&str>,
-        keyfile: Option<&mut dyn std::io::Read>,
-    ) -> Result<Database> {
-        let mut key_elements: Vec<Vec<u8>> = Vec::new();
+    /// Create a new, empty KDBX4 database
+    ///
+    /// The master seed, encryption IV, KDF salt and inner stream key are generated randomly, so
+    /// the returned database can be passed to `dump` right away.
+    /// ```
+    /// use keepass::{Database, DatabaseConfig};
+    ///
+    /// let mut db = Database::new(DatabaseConfig::default()).unwrap();
+    /// db.root.name = "Service vault".to_string();
+    /// ```
+    pub fn new(config: DatabaseConfig) -> Result<Database> {
+        let header = KDBX4Header {
+            version: 0xb54b_fb67,
+            file_major_version: 4,
+            file_minor_version: 0,
+            outer_iv: crypt::random_bytes(config.outer_cipher.iv_size())?,
+            outer_cipher: config.outer_cipher,
+            compression: config.compression,
+            master_seed: crypt::random_bytes(32)?,
+            kdf: config.kdf.to_kdf_settings()?,
+            body_start: 0,
+        };
 
-        if let Some(p) = password {
-            key_elements.push(
-                crypt::calculate_sha256(&[p.as_bytes()])?
-                    .as_slice()
-                    .to_vec(),
-            );
+        let inner_header = KDBX4InnerHeader {
+            inner_random_stream_key: crypt::random_bytes(config.inner_cipher.key_size())?,
+            inner_random_stream: config.inner_cipher,
+            body_start: 0,
+        };
+
+        let mut meta = Meta {
+            recyclebin_uuid: Uuid::nil(),
+            // the history limits that KeePass uses for new databases
+            history_max_items: Some(10),
+            history_max_size: Some(6 * 1024 * 1024),
+            ..Default::default()
+        };
+        for (field, protect) in &[
+            ("ProtectTitle", "False"),
+            ("ProtectUserName", "False"),
+            ("ProtectPassword", "True"),
+            ("ProtectURL", "False"),
+            ("ProtectNotes", "False"),
+        ] {
+            meta.memory_protection
+                .insert(field.to_string(), protect.to_string());
         }
 
-        if let Some(f) = keyfile {
-            key_elements.push(crate::keyfile::parse(f)?);
+        let root = Group {
+            name: "Root".to_owned(),
+            uuid: Uuid::new_v4()?,
+            is_expanded: true,
+            times: Times::now(),
+            ..Default::default()
+        };
+
+        let mut db = Database {
+            header: Header::KDBX4(header),
+            inner_header: InnerHeader::KDBX4(inner_header),
+            root,
+            meta,
+            deleted_objects: Vec::new(),
+            unhandled_root_fields: Vec::new(),
+            unhandled_fields: Vec::new(),
+            version: DBVersion::KDB4,
+            key: None,
+            uuid_index: Default::default(),
+        };
+        db.rebuild_uuid_index();
+        Ok(db)
+    }
+
+    /// Find an entry or group anywhere in the database by its UUID, including the root group
+    /// ```
+    /// use keepass::{Database, DatabaseConfig, Entry, Node, NodeRef};
+    ///
+    /// let mut db = Database::new(DatabaseConfig::default()).unwrap();
+    /// let entry = Entry::new().unwrap();
+    /// let uuid = entry.uuid;
+    /// db.root.children.push(Node::Entry(entry));
+    ///
+    /// assert!(matches!(db.find_by_uuid(&uuid), Some(NodeRef::Entry(e)) if e.uuid == uuid));
+    /// ```
+    pub fn find_by_uuid(&self, uuid: &Uuid) -> Option<NodeRef<'_>> {
+        self.root.node_at(&self.locate(uuid)?)
+    }
+
+    /// Find an entry or group anywhere in the database by its UUID, including the root group,
+    /// returning a mutable reference
+    pub fn find_by_uuid_mut(&mut self, uuid: &Uuid) -> Option<NodeRefMut<'_>> {
+        let path = self.locate(uuid)?;
+        self.root.node_at_mut(&path)
+    }
+
+    /// Get the group that directly contains an entry or group, which is `None` for the root group
+    pub fn parent_of(&self, uuid: &Uuid) -> Option<&Group> {
+        let path = self.locate(uuid)?;
+        match self.root.node_at(path.split_last()?.1)? {
+            NodeRef::Group(g) => Some(g),
+            NodeRef::Entry(_) => None,
         }
+    }
 
-        let mut data = Vec::new();
-        source.read_to_end(&mut data)?;
+    /// Get the path of an entry or group, made of the names of the groups below the root group
+    /// and the name or title of the node itself
+    ///
+    /// `Database::remove` accepts the path of any node, `Group::get` only that of an entry.
+    pub fn path_of(&self, uuid: &Uuid) -> Option<Vec<&str>> {
+        let path = self.locate(uuid)?;
+        let mut group = &self.root;
+        let mut names = Vec::new();
+        for i in path {
+            match group.children.get(i)? {
+                Node::Group(g) => {
+                    names.push(g.name.as_str());
+                    group = g;
+                }
+                Node::Entry(e) => names.push(e.get_title().unwrap_or_default()),
+            }
+        }
+        Some(names)
+    }
 
-        let (version, file_major_version, file_minor_version) =
-            crate::parse::get_kdbx_version(data.as_ref())?;
+    /// Get the position of a node from the index
+    ///
+    /// If the node is not where the index says, the tree was changed through the public fields
+    /// and the index is built again.
+    fn locate(&self, uuid: &Uuid) -> Option<Vec<usize>> {
+        if self.root.uuid == *uuid {
+            return Some(Vec::new());
+        }
 
-        match version {
-            0xb54bfb65 => crate::parse::kdb::parse(data.as_ref(), &key_elements),
-            // 0xb54bfb66 => alpha/beta kbd 2.x
-            0xb54bfb67 if file_major_version == 3 => {
-                crate::parse::kdbx3::parse(data.as_ref(), &key_elements)
+        let mut index = self
+            .uuid_index
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(path) = index.get(uuid) {
+            if self.root.node_at(path).is_some_and(|n| n.uuid() == *uuid) {
+                return Some(path.clone());
             }
-            0xb54bfb67 if file_major_version == 4 => {
-                crate::parse::kdbx4::parse(data.as_ref(), &key_elements)
+        }
+
+        let mut path = Vec::new();
+        if !self.root.find_path(uuid, &mut path) {
+            return None;
+        }
+        *index = self.root.uuid_index();
+        Some(path)
+    }
+
+    /// Get the position of a node that can be moved, copied or deleted, which the root group
+    /// cannot
+    fn locate_node(&self, uuid: &Uuid) -> Result<Vec<usize>> {
+        self.locate(uuid)
+            .filter(|path| !path.is_empty())
+            .ok_or(Error::NodeNotFound { uuid: *uuid })
+    }
+
+    /// Index the positions of all nodes again
+    fn rebuild_uuid_index(&mut self) {
+        *self
+            .uuid_index
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = self.root.uuid_index();
+    }
+
+    /// Remove a Group or Entry by specifying a path relative to the root Group
+    ///
+    /// The removed node and everything below it is recorded in `deleted_objects`, so that other
+    /// clients synchronizing with this database delete it as well.
+    /// ```
+    /// use keepass::{Database, Node};
+    /// use std::{fs::File, path::Path};
+    ///
+    /// let path = Path::new("tests/resources/test_db_with_password.kdbx");
+    /// let mut db = Database::open(&mut File::open(path).unwrap(), Some("demopass"), None).unwrap();
+    ///
+    /// let entry = match db.remove(&["General", "Sample Entry #2"]) {
+    ///     Some(Node::Entry(e)) => e,
+    ///     _ => panic!("Expected an entry"),
+    /// };
+    /// assert_eq!(db.deleted_objects.last().unwrap().uuid, entry.uuid);
+    /// ```
+    pub fn remove(&mut self, path: &[&str]) -> Option<Node> {
+        let (name, group_names) = path.split_last()?;
+
+        let mut group = &self.root;
+        let mut positions = Vec::with_capacity(path.len());
+        for group_name in group_names {
+            let (i, g) = group
+                .children
+                .iter()
+                .enumerate()
+                .find_map(|(i, n)| match n {
+                    Node::Group(g) if g.name == *group_name => Some((i, g)),
+                    _ => None,
+                })?;
+            positions.push(i);
+            group = g;
+        }
+        positions.push(group.children.iter().position(|n| match n {
+            Node::Group(g) => g.name == *name,
+            Node::Entry(e) => e.get_title() == Some(*name),
+        })?);
+
+        let node = self.take_node(&positions)?;
+        self.record_deleted(&node);
+        Some(node)
+    }
+
+    /// Record a node and all nodes below it as deleted
+    fn record_deleted(&mut self, node: &Node) {
+        let deletion_time = now();
+        let mut uuids = Vec::new();
+        node.collect_uuids(&mut uuids);
+        self.deleted_objects
+            .extend(uuids.into_iter().map(|uuid| DeletedObject {
+                uuid,
+                deletion_time,
+            }));
+    }
+
+    /// Move an entry or group into another group
+    ///
+    /// The group the node was in is kept as its previous parent group, and its location changed
+    /// time is updated. The root group is not a node of the database, so it cannot be moved,
+    /// copied or deleted.
+    pub fn move_node(&mut self, uuid: &Uuid, target_group: &Uuid) -> Result<()> {
+        let path = self.locate_node(uuid)?;
+        let target_path = self.check_target(uuid, target_group)?;
+        let parent_path = &path[..path.len() - 1];
+        if parent_path == target_path.as_slice() {
+            return Ok(());
+        }
+        // a group cannot be moved into itself or one of its subgroups
+        if target_path.starts_with(&path) {
+            return Err(Error::InvalidMove {
+                uuid: *uuid,
+                target: *target_group,
+            });
+        }
+
+        let parent = match self.root.node_at(parent_path) {
+            Some(NodeRef::Group(g)) => g.uuid,
+            _ => return Err(Error::NodeNotFound { uuid: *uuid }),
+        };
+        let mut node = self
+            .take_node(&path)
+            .ok_or(Error::NodeNotFound { uuid: *uuid })?;
+        let now = Some(now());
+        match &mut node {
+            Node::Group(g) => {
+                g.previous_parent_group = Some(parent);
+                g.times.location_changed = now;
             }
-            _ => Err(DatabaseIntegrityError::InvalidKDBXVersion {
-                version,
-                file_major_version,
-                file_minor_version,
+            Node::Entry(e) => {
+                e.previous_parent_group = Some(parent);
+                e.times.location_changed = now;
             }
-            .into()),
         }
+        self.push_node(target_group, node)
     }
 
-    pub(crate) fn get_decryptor(&self) -> Result<Box<dyn crypt::ciphers::Cipher>> {
-        let cipher = match self.version {
-            DBVersion::KDB3 => {
-                if let Header::KDBX3(h) = &self.header {
-                    h.decryptor()?
-                } else {
-                    Box::new(crypt::ciphers::PlainCipher::new(&Vec::new())?)
+    /// Copy an entry or group into another group, returning the UUID of the copy
+    ///
+    /// The copy and all nodes below it get new UUIDs. With `add_copy_suffix`, " - Copy" is
+    /// appended to the title of a copied entry or the name of a copied group, like KeePass does.
+    pub fn copy_node(
+        &mut self,
+        uuid: &Uuid,
+        target_group: &Uuid,
+        add_copy_suffix: bool,
+    ) -> Result<Uuid> {
+        self.check_target(uuid, target_group)?;
+        let path = self.locate_node(uuid)?;
+        let mut node = match self.root.node_at(&path) {
+            Some(NodeRef::Group(g)) => Node::Group(g.clone()),
+            Some(NodeRef::Entry(e)) => Node::Entry(e.clone()),
+            None => return Err(Error::NodeNotFound { uuid: *uuid }),
+        };
+
+        node.assign_new_uuids()?;
+        match &mut node {
+            Node::Group(g) => {
+                if add_copy_suffix {
+                    g.name.push_str(" - Copy");
                 }
+                g.previous_parent_group = None;
+                g.times.location_changed = Some(now());
             }
-            DBVersion::KDB4 => {
-                if let InnerHeader::KDBX4(header) = &self.inner_header {
-                    header.decryptor()?
-                } else {
-                    Box::new(crypt::ciphers::PlainCipher::new(&Vec::new())?)
+            Node::Entry(e) => {
+                if add_copy_suffix {
+                    let title = format!("{} - Copy", e.get_title().unwrap_or_default());
+                    e.set_title(&self.meta, &title);
                 }
+                e.previous_parent_group = None;
+                e.times.location_changed = Some(now());
+            }
+        }
+
+        let copy = node.uuid();
+        self.push_node(target_group, node)?;
+        Ok(copy)
+    }
+
+    /// Delete an entry or group by moving it into the recycle bin
+    ///
+    /// The recycle bin group is created if the database does not have one yet. Nodes are deleted
+    /// permanently, and recorded in `deleted_objects`, if they are in the recycle bin already or
+    /// if the recycle bin is disabled in the metadata.
+    /// ```
+    /// use keepass::{Database, DatabaseConfig, Entry, Node};
+    ///
+    /// let mut db = Database::new(DatabaseConfig::default()).unwrap();
+    /// let entry = Entry::new().unwrap();
+    /// let uuid = entry.uuid;
+    /// db.root.children.push(Node::Entry(entry));
+    ///
+    /// db.delete_node(&uuid).unwrap();
+    /// db.empty_recycle_bin();
+    /// assert_eq!(db.deleted_objects[0].uuid, uuid);
+    /// ```
+    pub fn delete_node(&mut self, uuid: &Uuid) -> Result<()> {
+        let path = self.locate_node(uuid)?;
+        let in_recycle_bin = self
+            .recycle_bin_path()
+            .is_some_and(|bin| path.starts_with(&bin));
+
+        if !self.meta.recyclebin_enabled.unwrap_or(true) || in_recycle_bin {
+            let node = self
+                .take_node(&path)
+                .ok_or(Error::NodeNotFound { uuid: *uuid })?;
+            if !self.meta.recyclebin_uuid.is_nil() && node.uuid() == self.meta.recyclebin_uuid {
+                self.meta.recyclebin_uuid = Uuid::nil();
+                self.meta.recyclebin_changed = Some(now());
             }
-            _ => Box::new(crypt::ciphers::PlainCipher::new(&Vec::new())?),
+            self.record_deleted(&node);
+            return Ok(());
+        }
+
+        let recycle_bin = self.recycle_bin()?;
+        self.move_node(uuid, &recycle_bin)
+    }
+
+    /// Permanently delete everything in the recycle bin
+    pub fn empty_recycle_bin(&mut self) {
+        let bin_path = match self.recycle_bin_path() {
+            Some(path) => path,
+            None => return,
         };
-        Ok(cipher)
+        let nodes = match self.root.node_at_mut(&bin_path) {
+            Some(NodeRefMut::Group(bin)) => std::mem::take(&mut bin.children),
+            _ => return,
+        };
+
+        let index = self
+            .uuid_index
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for node in &nodes {
+            node.unindex_uuids(index);
+        }
+        for node in &nodes {
+            self.record_deleted(node);
+        }
     }
+
+    /// Move an entry or group back into the group it was in before it was last moved, like out
+    /// of the recycle bin
+    ///
+    /// Nodes are restored into the root group if that group does not exist anymore or is in the
+    /// recycle bin itself.
+    pub fn restore(&mut self, uuid: &Uuid) -> Result<()> {
+        let path = self.locate_node(uuid)?;
+        let previous_parent = match self.root.node_at(&path) {
+            Some(NodeRef::Group(g)) => g.previous_parent_group,
+            Some(NodeRef::Entry(e)) => e.previous_parent_group,
+            None => return Err(Error::NodeNotFound { uuid: *uuid }),
+        };
+
+        let bin = self.recycle_bin_path();
+        let target = previous_parent
+            .filter(|p| match self.locate(p) {
+                Some(p) => {
+                    matches!(self.root.node_at(&p), Some(NodeRef::Group(_)))
+                        && !bin.as_ref().is_some_and(|bin| p.starts_with(bin))
+                }
+                None => false,
+            })
+            .unwrap_or(self.root.uuid);
+        self.move_node(uuid, &target)
+    }
+
+    /// Get the position of the recycle bin group, if the database has one
+    ///
+    /// Groups of KDB databases all have the nil UUID, which must not be taken for the recycle bin
+    /// that databases without one refer to.
+    fn recycle_bin_path(&self) -> Option<Vec<usize>> {
+        if self.meta.recyclebin_uuid.is_nil() {
+            return None;
+        }
+        self.locate(&self.meta.recyclebin_uuid).filter(|path| {
+            !path.is_empty() && matches!(self.root.node_at(path), Some(NodeRef::Group(_)))
+        })
+    }
+
+    /// Get the UUID of the recycle bin group, creating the group if it does not exist
+    fn recycle_bin(&mut self) -> Result<Uuid> {
+        if self.recycle_bin_path().is_some() {
+            return Ok(self.meta.recyclebin_uuid);
+        }
+
+        let mut bin = Group::new("Recycle Bin")?;
+        bin.icon_id = Some(43);
+        bin.enable_autotype = Some(false);
+        bin.enable_searching = Some(false);
+        self.meta.recyclebin_uuid = bin.uuid;
+        self.meta.recyclebin_changed = Some(now());
+        let root = self.root.uuid;
+        self.push_node(&root, Node::Group(bin))?;
+        Ok(self.meta.recyclebin_uuid)
+    }
+
+    /// Check that a node can be moved or copied into a group, which must exist and not be an
+    /// entry, returning the position of the group
+    fn check_target(&self, uuid: &Uuid, target_group: &Uuid) -> Result<Vec<usize>> {
+        let path = self.locate(target_group).ok_or(Error::NodeNotFound {
+            uuid: *target_group,
+        })?;
+        match self.root.node_at(&path) {
+            Some(NodeRef::Group(_)) => Ok(path),
+            _ => Err(Error::InvalidMove {
+                uuid: *uuid,
+                target: *target_group,
+            }),
+        }
+    }
+
+    /// Remove the node at a position from its group and from the index
+    ///
+    /// Only the nodes after it in the same group change their position, so only these are
+    /// indexed again.
+    fn take_node(&mut self, path: &[usize]) -> Option<Node> {
+        let (&position, parent_path) = path.split_last()?;
+        let parent = match self.root.node_at_mut(parent_path)? {
+            NodeRefMut::Group(g) => g,
+            NodeRefMut::Entry(_) => return None,
+        };
+        if position >= parent.children.len() {
+            return None;
+        }
+        let node = parent.children.remove(position);
+
+        let index = self
+            .uuid_index
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        node.unindex_uuids(index);
+        let mut sibling_path = parent_path.to_vec();
+        for (i, sibling) in parent.children.iter().enumerate().skip(position) {
+            sibling_path.push(i);
+            sibling.index_uuids(&mut sibling_path, index);
+            sibling_path.pop();
+        }
+        Some(node)
+    }
+
+    /// Add a node to the children of a group and to the index
+    fn push_node(&mut self, target_group: &Uuid, node: Node) -> Result<()> {
+        let not_found = || Error::NodeNotFound {
+            uuid: *target_group,
+        };
+        let mut path = self.locate(target_group).ok_or_else(not_found)?;
+        let target = match self.root.node_at_mut(&path) {
+            Some(NodeRefMut::Group(g)) => g,
+            _ => return Err(not_found()),
+        };
+
+        path.push(target.children.len());
+        node.index_uuids(
+            &mut path,
+            self.uuid_index
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        target.children.push(node);
+        Ok(())
+    }
+
+    /// Choose whether a KDBX4 database is saved as KDBX 4.0 or KDBX 4.1
+    ///
+    /// Databases keep the minor version they were opened with. The KDBX 4.1 additions, like tags
+    /// of groups or the previous parent group of entries and groups, are only written for KDBX 4.1.
+    pub fn set_kdbx4_minor_version(&mut self, minor_version: u16) -> Result<()> {
+        match &mut self.header {
+            Header::KDBX4(h) if minor_version <= 1 => {
+                h.file_minor_version = minor_version;
+                Ok(())
+            }
+            _ => Err(SerializationError::UnsupportedDatabaseVersion.into()),
+        }
+    }
+
+    /// Remove a custom icon, and the references of all entries and groups to it
+    pub fn remove_custom_icon(&mut self, uuid: &Uuid) -> Option<CustomIcon> {
+        let position = self
+            .meta
+            .custom_icons
+            .iter()
+            .position(|i| i.uuid == *uuid)?;
+        self.root.clear_custom_icon(uuid);
+        Some(self.meta.custom_icons.remove(position))
+    }
+
+    /// Remove all custom icons that no entry or group refers to, returning the removed icons
+    pub fn remove_unused_custom_icons(&mut self) -> Vec<CustomIcon> {
+        let mut used = std::collections::HashSet::new();
+        for node in &self.root {
+            match node {
+                NodeRef::Group(g) => used.extend(g.custom_icon_uuid),
+                NodeRef::Entry(e) => {
+                    used.extend(e.custom_icon_uuid);
+                    used.extend(e.history.iter().filter_map(|h| h.custom_icon_uuid));
+                }
+            }
+        }
+
+        let (kept, removed) = std::mem::take(&mut self.meta.custom_icons)
+            .into_iter()
+            .partition(|i| used.contains(&i.uuid));
+        self.meta.custom_icons = kept;
+        removed
+    }
+
+    /// Remove all binary attachments that no entry refers to, returning the removed attachments
+    ///
+    /// The references of the remaining attachments are updated to their new positions in
+    /// `Meta::binaries`.
+    pub fn remove_unused_attachments(&mut self) -> Vec<BinaryAttachment> {
+        let mut used = std::collections::HashSet::new();
+        for node in &self.root {
+            if let NodeRef::Entry(e) = node {
+                used.extend(e.binary_refs.values().copied());
+                for h in &e.history {
+                    used.extend(h.binary_refs.values().copied());
+                }
+            }
+        }
+
+        let mut new_positions = Vec::new();
+        let mut kept = Vec::new();
+        let mut removed = Vec::new();
+        for (i, binary) in std::mem::take(&mut self.meta.binaries)
+            .into_iter()
+            .enumerate()
+        {
+            if used.contains(&i) {
+                new_positions.push(Some(kept.len()));
+                kept.push(binary);
+            } else {
+                new_positions.push(None);
+                removed.push(binary);
+            }
+        }
+        self.meta.binaries = kept;
+        self.root.update_binary_refs(&new_positions);
+        removed
+    }
+
+    /// Parse a database from a std::io::Read
+    pub fn open(
+        source: &mut dyn std::io::Read,
+        password: Option<&str>,
+        keyfile: Option<&mut dyn std::io::Read>,
+    ) -> Result<Database> {
+        let key = DatabaseKey::from_password_and_keyfile(password, keyfile)?;
+        Database::open_with_key(source, &key)
+    }
+
+    /// Parse a database from a std::io::Read, using a `DatabaseKey`
+    ///
+    /// The database keeps a copy of the key, so that it can be saved again with
+    /// `save_with_current_key`.
+    pub fn open_with_key(source: &mut dyn std::io::Read, key: &DatabaseKey) -> Result<Database> {
+        let (prefix, (version, file_major_version, file_minor_version)) =
+            crate::parse::read_kdbx_version(source)?;
+        let mut source = (&prefix[..]).chain(source);
+
+        let mut db = match version {
+            0xb54bfb65 => {
+                let mut data = Vec::new();
+                source.read_to_end(&mut data)?;
+                crate::parse::kdb::parse(data.as_ref(), key)
+            }
+            // 0xb54bfb66 => alpha/beta kbd 2.x
+            0xb54bfb67 if file_major_version == 3 => crate::parse::kdbx3::parse(&mut source, key),
+            0xb54bfb67 if file_major_version == 4 => crate::parse::kdbx4::parse(&mut source, key),
+            _ => Err(DatabaseIntegrityError::InvalidKDBXVersion {
+                version,
+                file_major_version,
+                file_minor_version,
+            }
+            .into()),
+        }?;
+        db.rebuild_uuid_index();
+        Ok(db)
+    }
+
+    /// Change the key that `save_with_current_key` encrypts the database with
+    ///
+    /// The master seed, encryption IV, KDF salt and inner stream key are regenerated as well, so
+    /// that nothing derived from the old key carries over. Saving always generates new ones anyway.
+    /// ```
+    /// use keepass::{Database, DatabaseKey};
+    /// use std::fs::File;
+    ///
+    /// let path = std::path::Path::new("tests/resources/test_db_with_password.kdbx");
+    /// let old_key = DatabaseKey::new().with_password("demopass");
+    /// let mut db = Database::open_with_key(&mut File::open(path)?, &old_key)?;
+    ///
+    /// db.change_key(DatabaseKey::new().with_password("new password"))?;
+    /// let mut rekeyed = Vec::new();
+    /// db.save_with_current_key(&mut rekeyed)?;
+    /// # Ok::<(), keepass::Error>(())
+    /// ```
+    pub fn change_key(&mut self, new_key: DatabaseKey) -> Result<()> {
+        match &mut self.header {
+            Header::KDB(_) => {}
+            Header::KDBX3(h) => *h = h.with_new_seeds()?,
+            Header::KDBX4(h) => *h = h.with_new_seeds()?,
+        }
+
+        if let InnerHeader::KDBX4(ih) = &mut self.inner_header {
+            *ih = ih.with_new_seeds()?;
+        }
+
+        self.key = Some(new_key);
+        Ok(())
+    }
+
     pub fn dump(
         &self,
         password: Option<&str>,
         keyfile: Option<&mut dyn std::io::Read>,
     ) -> Result<Vec<u8>> {
-        let mut key_elements: Vec<Vec<u8>> = Vec::new();
+        let key = DatabaseKey::from_password_and_keyfile(password, keyfile)?;
+        match self.header {
+            Header::KDB(_) => Err(SerializationError::UnsupportedDatabaseVersion.into()),
+            Header::KDBX3(_) => crate::parse::kdbx3::encrypt_xml(self, &key),
+            Header::KDBX4(_) => {
+                let mut data = Vec::new();
+                crate::parse::kdbx4::encrypt_xml(self, &mut data, &key)?;
+                Ok(data)
+            }
+        }
+    }
 
-        if let Some(p) = password {
-            key_elements.push(
-                crypt::calculate_sha256(&[p.as_bytes()])?
-                    .as_slice()
-                    .to_vec(),
-            );
+    /// Save a KDBX database to a std::io::Write
+    ///
+    /// The payload of a KDBX4 database is compressed, encrypted and written out as it is generated,
+    /// instead of being assembled in memory first like `dump` does. Every save uses a new master
+    /// seed, encryption IV, KDF salt and inner stream key.
+    pub fn save(&self, writer: &mut dyn std::io::Write, key: &DatabaseKey) -> Result<()> {
+        match self.header {
+            Header::KDB(_) => Err(SerializationError::UnsupportedDatabaseVersion.into()),
+            Header::KDBX3(_) => {
+                Ok(writer.write_all(&crate::parse::kdbx3::encrypt_xml(self, key)?)?)
+            }
+            Header::KDBX4(_) => crate::parse::kdbx4::encrypt_xml(self, writer, key),
         }
+    }
 
-        if let Some(f) = keyfile {
-            key_elements.push(crate::keyfile::parse(f)?);
+    /// Save a KDBX database to a std::io::Write, using the key it was opened with or the one
+    /// last given through `change_key`
+    pub fn save_with_current_key(&self, writer: &mut dyn std::io::Write) -> Result<()> {
+        match &self.key {
+            Some(key) => self.save(writer, key),
+            None => Err(SerializationError::MissingKey.into()),
         }
-        let key_elements: Vec<u8> = key_elements.into_iter().flatten().collect();
-        Ok(crate::parse::kdbx4::encrypt_xml(self, key_elements).unwrap())
     }
 
     /// Helper function to load a database into its internal XML chunks
@@ -143,34 +779,20 @@ impl Database {
         password: Option<&str>,
         keyfile: Option<&mut dyn std::io::Read>,
     ) -> Result<Vec<Vec<u8>>> {
-        let mut key_elements: Vec<Vec<u8>> = Vec::new();
-
-        if let Some(p) = password {
-            key_elements.push(
-                crypt::calculate_sha256(&[p.as_bytes()])?
-                    .as_slice()
-                    .to_vec(),
-            );
-        }
-
-        if let Some(f) = keyfile {
-            key_elements.push(crate::keyfile::parse(f)?);
-        }
-
-        let mut data = Vec::new();
-        source.read_to_end(&mut data)?;
+        let key = DatabaseKey::from_password_and_keyfile(password, keyfile)?;
 
-        let (version, file_major_version, file_minor_version) =
-            crate::parse::get_kdbx_version(data.as_ref())?;
+        let (prefix, (version, file_major_version, file_minor_version)) =
+            crate::parse::read_kdbx_version(source)?;
+        let mut source = (&prefix[..]).chain(source);
 
         let data = match version {
-            0xb54bfb65 => panic!("Dumping XML from KDB databases not supported"),
+            // 0xb54bfb65 => KDB databases do not contain XML
             // 0xb54bfb66 => alpha/beta kbd 2.x
             0xb54bfb67 if file_major_version == 3 => {
-                crate::parse::kdbx3::decrypt_xml(data.as_ref(), &key_elements)?.1
+                crate::parse::kdbx3::decrypt_xml(&mut source, &key)?.1
             }
             0xb54bfb67 if file_major_version == 4 => {
-                vec![crate::parse::kdbx4::decrypt_xml(data.as_ref(), &key_elements)?.2]
+                vec![crate::parse::kdbx4::decrypt_xml(&mut source, &key)?.2]
             }
             _ => {
                 return Err(Error::DatabaseIntegrity {
@@ -188,17 +810,162 @@ impl Database {
 }
 
 /// Database metadata
+///
+/// Fields that are missing from a database are `None`, and are not written out either.
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct Meta {
-    pub recyclebin_uuid: String,
-    pub binaries: Vec<Vec<u8>>,
-    pub unhandled_fields: HashMap<String, String>,
-    pub custom_data: HashMap<String, String>,
-    pub memory_protection: HashMap<String, String>,
+    /// The program that last saved the database
+    pub generator: Option<String>,
+
+    pub database_name: Option<String>,
+    pub database_name_changed: Option<chrono::DateTime<chrono::Utc>>,
+    pub database_description: Option<String>,
+    pub database_description_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The username that new entries are created with
+    pub default_username: Option<String>,
+    pub default_username_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Number of days after which history entries are removed during maintenance
+    pub maintenance_history_days: Option<u32>,
+
+    /// Color of the database in the user interface, as an HTML color like `#FF0000`
+    pub color: Option<String>,
+
+    pub master_key_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Number of days after which a change of the master key is recommended, or -1 to disable
+    pub master_key_change_rec: Option<i64>,
+
+    /// Number of days after which a change of the master key is enforced, or -1 to disable
+    pub master_key_change_force: Option<i64>,
+
+    pub memory_protection: IndexMap<String, String>,
+
+    /// Icons that entries and groups can show instead of one of the standard icons
+    pub custom_icons: Vec<CustomIcon>,
+
+    pub recyclebin_enabled: Option<bool>,
+    pub recyclebin_uuid: Uuid,
+    pub recyclebin_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The group that contains templates for new entries
+    pub entry_templates_group: Option<Uuid>,
+    pub entry_templates_group_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The group that was last selected and the group that was last scrolled to the top in the
+    /// user interface
+    pub last_selected_group: Option<Uuid>,
+    pub last_top_visible_group: Option<Uuid>,
+
+    /// Maximum number of history entries per entry, or -1 for no limit
+    pub history_max_items: Option<i32>,
+
+    /// Maximum size of the history of an entry in bytes, or -1 for no limit
+    pub history_max_size: Option<i64>,
+
+    pub settings_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The binary attachments that entries refer to by their position
+    ///
+    /// KDBX3 stores these in the metadata, KDBX4 in the inner header of the file.
+    pub binaries: Vec<BinaryAttachment>,
+
+    pub custom_data: IndexMap<String, CustomDataItem>,
+
+    /// Elements that are not modelled, kept as they were read so that they are written back
+    /// unchanged
+    pub unhandled_fields: Vec<xmltree::Element>,
+}
+
+impl Meta {
+    /// Add a custom icon from the data of a PNG image, returning the UUID to refer to it by
+    ///
+    /// If an icon with the same data exists already, it is reused instead.
+    pub fn add_custom_icon(&mut self, data: Vec<u8>) -> Result<Uuid> {
+        if let Some(icon) = self.custom_icons.iter().find(|i| i.data == data) {
+            return Ok(icon.uuid);
+        }
+
+        let uuid = Uuid::new_v4()?;
+        self.custom_icons.push(CustomIcon {
+            uuid,
+            data,
+            name: None,
+            last_modification_time: None,
+        });
+        Ok(uuid)
+    }
+
+    /// Get a custom icon by its UUID
+    pub fn get_custom_icon(&self, uuid: &Uuid) -> Option<&CustomIcon> {
+        self.custom_icons.iter().find(|i| i.uuid == *uuid)
+    }
+
+    /// Whether the memory protection settings protect a standard field, like "Password"
+    pub fn is_protected_field(&self, field_name: &str) -> bool {
+        self.memory_protection
+            .get(&format!("Protect{}", field_name))
+            .is_some_and(|p| p == "True")
+    }
+}
+
+/// A binary attachment in the pool of the database, which can be shared by several entries
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BinaryAttachment {
+    pub(crate) flags: u8,
+    pub content: Vec<u8>,
+}
+
+impl BinaryAttachment {
+    /// Flag of attachments that KeePass keeps protected in memory
+    const PROTECTED: u8 = 0x01;
+
+    pub fn new(content: Vec<u8>, protected: bool) -> BinaryAttachment {
+        let mut binary = BinaryAttachment { flags: 0, content };
+        binary.set_protected(protected);
+        binary
+    }
+
+    /// Whether the attachment is protected in memory by KeePass, which also encrypts it with the
+    /// inner stream cipher in KDBX3 files
+    pub fn is_protected(&self) -> bool {
+        self.flags & BinaryAttachment::PROTECTED != 0
+    }
+
+    pub fn set_protected(&mut self, protected: bool) {
+        if protected {
+            self.flags |= BinaryAttachment::PROTECTED;
+        } else {
+            self.flags &= !BinaryAttachment::PROTECTED;
+        }
+    }
+}
+
+/// A value that plugins and other clients store in the metadata, a group or an entry
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CustomDataItem {
+    pub value: String,
+
+    /// Only stored by KDBX 4.1
+    pub last_modification_time: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A custom icon stored in the metadata of the database
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CustomIcon {
+    pub uuid: Uuid,
+
+    /// The icon as a PNG image
+    pub data: Vec<u8>,
+
+    /// Name and last modification time of the icon, which are only stored by KDBX 4.1
+    pub name: Option<String>,
+    pub last_modification_time: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// A database group with child groups and entries
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct Group {
     /// The name of the group
     pub name: String,
@@ -206,25 +973,57 @@ pub struct Group {
     /// The list of child nodes (Groups or Entries)
     pub children: Vec<Node>,
 
-    /// The list of time fields for this group
-    ///
-    /// Using chrono::NaiveDateTime which does not include timezone
-    /// or UTC offset because KeePass clients typically store timestamps
-    /// relative to the local time on the machine writing the data without
-    /// including accurate UTC offset or timezone information.
-    pub times: HashMap<String, chrono::NaiveDateTime>,
-
-    /// Does this group expire
-    pub expires: bool,
-    pub usage_count: String,
+    /// The timestamps and usage information of this group
+    pub times: Times,
 
     /// The unique identifier of the group
-    pub uuid: String,
+    pub uuid: Uuid,
+
+    pub notes: Option<String>,
+
+    /// The index of one of the standard KeePass icons
+    pub icon_id: Option<usize>,
+
+    /// A custom icon from the metadata, which is shown instead of the standard icon
+    pub custom_icon_uuid: Option<Uuid>,
+
+    /// Whether the group is expanded in the user interface
+    pub is_expanded: bool,
+
+    /// The auto-type sequence for entries in this group that do not have one of their own
+    pub default_autotype_sequence: Option<String>,
 
-    pub unhandled_fields: HashMap<String, String>,
+    /// Whether auto-type and searching are enabled for entries in this group, with `None` meaning
+    /// that the setting is inherited from the parent group
+    pub enable_autotype: Option<bool>,
+    pub enable_searching: Option<bool>,
+
+    /// The entry that was last scrolled to the top in the user interface
+    pub last_top_visible_entry: Option<Uuid>,
+
+    /// The group this group was in before it was moved, which is only stored by KDBX 4.1
+    pub previous_parent_group: Option<Uuid>,
+
+    /// Tags of the group, which are only stored by KDBX 4.1
+    pub tags: Vec<String>,
+
+    pub custom_data: IndexMap<String, CustomDataItem>,
+
+    /// Elements that are not modelled, which are written back unchanged
+    pub unhandled_fields: Vec<xmltree::Element>,
 }
 
-impl Group {
+impl Group {
+    /// Create an empty group with a new UUID, with all timestamps set to the current time
+    pub fn new(name: &str) -> Result<Group> {
+        Ok(Group {
+            name: name.to_owned(),
+            uuid: Uuid::new_v4()?,
+            times: Times::now(),
+            ..Default::default()
+        })
+    }
+
     /// Recursively get a Group or Entry reference by specifying a path relative to the current Group
     /// ```
     /// use keepass::{Database, NodeRef};
@@ -294,31 +1093,132 @@ impl Group {
         }
     }
 
-    /// Get a timestamp field by name
-    ///
-    /// Returning the chrono::NaiveDateTime which does not include timezone
-    /// or UTC offset because KeePass clients typically store timestamps
-    /// relative to the local time on the machine writing the data without
-    /// including accurate UTC offset or timezone information.
-    pub fn get_time(&self, key: &str) -> Option<&chrono::NaiveDateTime> {
-        self.times.get(key)
+    /// Convenience method for getting the value of the 'ExpiryTime' timestamp
+    /// This value is usually only meaningful/useful when times.expires == true
+    pub fn get_expiry_time(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.times.expiry.as_ref()
     }
 
-    /// Convenience method for getting the value of the 'ExpiryTime' timestamp
-    pub fn get_expiry_time(&self) -> Option<&chrono::NaiveDateTime> {
-        self.get_time("ExpiryTime")
+    /// Get the custom icon of the group from the metadata of the database, if it has one
+    pub fn get_custom_icon<'b>(&self, db: &'b Database) -> Option<&'b CustomIcon> {
+        db.meta.get_custom_icon(self.custom_icon_uuid.as_ref()?)
+    }
+
+    /// Get the node at a position below this group, given as the indices of the children to
+    /// descend into, or this group for an empty path
+    fn node_at(&self, path: &[usize]) -> Option<NodeRef<'_>> {
+        match path.split_first() {
+            None => Some(NodeRef::Group(self)),
+            Some((i, tail)) => match self.children.get(*i)? {
+                Node::Group(g) => g.node_at(tail),
+                Node::Entry(e) if tail.is_empty() => Some(NodeRef::Entry(e)),
+                Node::Entry(_) => None,
+            },
+        }
+    }
+
+    /// Get the node below this group at a path of child indices, returning a mutable reference
+    fn node_at_mut(&mut self, path: &[usize]) -> Option<NodeRefMut<'_>> {
+        match path.split_first() {
+            None => Some(NodeRefMut::Group(self)),
+            Some((i, tail)) => match self.children.get_mut(*i)? {
+                Node::Group(g) => g.node_at_mut(tail),
+                Node::Entry(e) if tail.is_empty() => Some(NodeRefMut::Entry(e)),
+                Node::Entry(_) => None,
+            },
+        }
+    }
+
+    /// Recursively search for a node, adding the indices of the children to descend into to
+    /// `path`
+    fn find_path(&self, uuid: &Uuid, path: &mut Vec<usize>) -> bool {
+        for (i, child) in self.children.iter().enumerate() {
+            path.push(i);
+            if child.uuid() == *uuid {
+                return true;
+            }
+            if let Node::Group(g) = child {
+                if g.find_path(uuid, path) {
+                    return true;
+                }
+            }
+            path.pop();
+        }
+        false
+    }
+
+    /// Index the positions of this group, which has the empty path, and of all nodes below it
+    fn uuid_index(&self) -> HashMap<Uuid, Vec<usize>> {
+        let mut index = HashMap::new();
+        index.insert(self.uuid, Vec::new());
+        self.index_uuids(&mut Vec::new(), &mut index);
+        index
+    }
+
+    /// Recursively add the positions of all nodes below this group to the index
+    fn index_uuids(&self, path: &mut Vec<usize>, index: &mut HashMap<Uuid, Vec<usize>>) {
+        for (i, child) in self.children.iter().enumerate() {
+            path.push(i);
+            child.index_uuids(path, index);
+            path.pop();
+        }
+    }
+
+    /// Recursively update the binary references of all entries, given the new position of each
+    /// attachment
+    fn update_binary_refs(&mut self, new_positions: &[Option<usize>]) {
+        for child in &mut self.children {
+            match child {
+                Node::Group(g) => g.update_binary_refs(new_positions),
+                Node::Entry(e) => {
+                    e.update_binary_refs(new_positions);
+                    for h in e.history.iter_mut() {
+                        h.update_binary_refs(new_positions);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Recursively clear all references to a custom icon
+    fn clear_custom_icon(&mut self, uuid: &Uuid) {
+        if self.custom_icon_uuid == Some(*uuid) {
+            self.custom_icon_uuid = None;
+        }
+
+        for child in &mut self.children {
+            match child {
+                Node::Group(g) => g.clear_custom_icon(uuid),
+                Node::Entry(e) => {
+                    if e.custom_icon_uuid == Some(*uuid) {
+                        e.custom_icon_uuid = None;
+                    }
+                    for h in e.history.iter_mut() {
+                        if h.custom_icon_uuid == Some(*uuid) {
+                            h.custom_icon_uuid = None;
+                        }
+                    }
+                }
+            }
+        }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// The value of a field of an entry
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Value {
+    /// Binary data, which only KDB databases have
+    ///
+    /// KDBX documents cannot hold arbitrary bytes, so these values are written as base64 with a
+    /// `Bytes="True"` attribute on the `Value` element. This attribute is an extension of this
+    /// crate: KeePass and KeePassXC ignore it and read the base64 text as a string.
     Bytes(Vec<u8>),
     Unprotected(String),
     Protected(SecStr),
 }
 
 /// An AutoType setting associated with an Entry
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct AutoType {
     pub enabled: bool,
     pub sequence: Option<String>,
@@ -327,19 +1227,127 @@ pub struct AutoType {
 }
 
 /// A window association associated with an AutoType setting
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct AutoTypeAssociation {
     pub window: Option<String>,
     pub sequence: Option<String>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Node {
     Group(Group),
     Entry(Entry),
 }
 
+/// The timestamps and usage information of a group or an entry
+///
+/// KeePass stores these in UTC, with a resolution of seconds.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Times {
+    pub creation: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_modification: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_access: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// When the group or entry expires, which only applies if `expires` is set
+    pub expiry: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// When the group or entry was last moved to another group
+    pub location_changed: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Whether the group or entry expires at `expiry`
+    pub expires: bool,
+
+    /// How often the group or entry was used
+    pub usage_count: u64,
+}
+
+/// The current time, with the resolution of seconds that timestamps are stored with
+fn now() -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+
+    chrono::Utc.timestamp(chrono::Utc::now().timestamp(), 0)
+}
+
+impl Times {
+    /// Create times with all timestamps set to the current time
+    pub fn now() -> Times {
+        let now = Some(now());
+        Times {
+            creation: now,
+            last_modification: now,
+            last_access: now,
+            expiry: now,
+            location_changed: now,
+            expires: false,
+            usage_count: 0,
+        }
+    }
+}
+
+/// An entry or group that was deleted from the database
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeletedObject {
+    pub uuid: Uuid,
+    pub deletion_time: chrono::DateTime<chrono::Utc>,
+}
+
 impl Node {
+    fn uuid(&self) -> Uuid {
+        match self {
+            Node::Group(g) => g.uuid,
+            Node::Entry(e) => e.uuid,
+        }
+    }
+
+    /// Give this node and all nodes below it new UUIDs, including the history of entries
+    fn assign_new_uuids(&mut self) -> Result<()> {
+        match self {
+            Node::Entry(e) => {
+                e.uuid = Uuid::new_v4()?;
+                for h in e.history.iter_mut() {
+                    h.uuid = e.uuid;
+                }
+            }
+            Node::Group(g) => {
+                g.uuid = Uuid::new_v4()?;
+                for child in g.children.iter_mut() {
+                    child.assign_new_uuids()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the position of this node and, for groups, of all nodes below it to the index
+    fn index_uuids(&self, path: &mut Vec<usize>, index: &mut HashMap<Uuid, Vec<usize>>) {
+        index.insert(self.uuid(), path.clone());
+        if let Node::Group(g) = self {
+            g.index_uuids(path, index);
+        }
+    }
+
+    /// Remove this node and, for groups, all nodes below it from the index
+    fn unindex_uuids(&self, index: &mut HashMap<Uuid, Vec<usize>>) {
+        let mut uuids = Vec::new();
+        self.collect_uuids(&mut uuids);
+        for uuid in uuids {
+            index.remove(&uuid);
+        }
+    }
+
+    /// Collect the UUIDs of this node and, for groups, of all nodes below it
+    fn collect_uuids(&self, uuids: &mut Vec<Uuid>) {
+        match self {
+            Node::Entry(e) => uuids.push(e.uuid),
+            Node::Group(g) => {
+                uuids.push(g.uuid);
+                for child in &g.children {
+                    child.collect_uuids(uuids);
+                }
+            }
+        }
+    }
+
     pub fn to_ref<'a>(&'a self) -> NodeRef<'a> {
         self.into()
     }
@@ -355,6 +1363,15 @@ pub enum NodeRef<'a> {
     Entry(&'a Entry),
 }
 
+impl NodeRef<'_> {
+    fn uuid(&self) -> Uuid {
+        match self {
+            NodeRef::Group(g) => g.uuid,
+            NodeRef::Entry(e) => e.uuid,
+        }
+    }
+}
+
 impl<'a> std::convert::From<&'a Node> for NodeRef<'a> {
     fn from(n: &'a Node) -> Self {
         match n {
@@ -379,28 +1396,98 @@ impl<'a> std::convert::From<&'a mut Node> for NodeRefMut<'a> {
     }
 }
 
+/// An RGB color, as used for the foreground and background of entries
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// Parse a color in the HTML notation that KeePass uses, like `#FF0000`
+impl std::str::FromStr for Color {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Color> {
+        let invalid = || Error::InvalidColor {
+            color: s.to_owned(),
+        };
+
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok(Color {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+}
+
 /// A database entry containing several key-value fields.
-#[derive(Debug, Default, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct Entry {
-    pub fields: HashMap<String, Value>,
+    pub fields: IndexMap<String, Value>,
     pub autotype: Option<AutoType>,
-    pub expires: bool,
-    pub usage_count: String,
-    pub times: HashMap<String, chrono::NaiveDateTime>,
-    pub uuid: String,
+    pub times: Times,
+    pub uuid: Uuid,
+
+    /// The index of one of the standard KeePass icons
+    pub icon_id: Option<usize>,
+
+    /// A custom icon from the metadata, which is shown instead of the standard icon
+    pub custom_icon_uuid: Option<Uuid>,
+
+    pub foreground_color: Option<Color>,
+    pub background_color: Option<Color>,
+
+    /// A command that is run instead of opening the URL of the entry
+    pub override_url: Option<String>,
+
+    /// Tags of the entry, stored separated by `;` (or `,` by some clients)
+    pub tags: Vec<String>,
+
+    /// Whether the password quality of the entry is estimated, with `None` meaning it was not
+    /// stored, as only KDBX 4.1 stores it
+    pub quality_check: Option<bool>,
+
+    /// The group this entry was in before it was moved, which is only stored by KDBX 4.1
+    pub previous_parent_group: Option<Uuid>,
+
     pub history: Vec<Entry>,
-    pub unhandled_fields: HashMap<String, String>,
-    pub custom_data: HashMap<String, String>,
-    pub binary_refs: HashMap<String, usize>,
+
+    /// Elements that are not modelled, which are written back unchanged
+    pub unhandled_fields: Vec<xmltree::Element>,
+
+    pub custom_data: IndexMap<String, CustomDataItem>,
+    pub binary_refs: IndexMap<String, usize>,
 }
 
 impl<'a> Entry {
+    /// Create an empty entry with a new UUID, with all timestamps set to the current time
+    pub fn new() -> Result<Entry> {
+        Ok(Entry {
+            uuid: Uuid::new_v4()?,
+            times: Times::now(),
+            ..Default::default()
+        })
+    }
+
     /// Get a field by name, taking care of unprotecting Protected values automatically
     pub fn get(&'a self, key: &str) -> Option<&'a str> {
         match self.fields.get(key) {
             Some(&Value::Bytes(_)) => None,
-            Some(&Value::Protected(ref pv)) => std::str::from_utf8(pv.unsecure()).ok(),
-            Some(&Value::Unprotected(ref uv)) => Some(&uv),
+            Some(Value::Protected(pv)) => std::str::from_utf8(pv.unsecure()).ok(),
+            Some(Value::Unprotected(uv)) => Some(uv),
             None => None,
         }
     }
@@ -408,36 +1495,24 @@ impl<'a> Entry {
     /// Get a bytes field by name
     pub fn get_bytes(&'a self, key: &str) -> Option<&'a [u8]> {
         match self.fields.get(key) {
-            Some(&Value::Bytes(ref b)) => Some(&b),
+            Some(Value::Bytes(b)) => Some(b),
             Some(&Value::Protected(_)) => None,
             Some(&Value::Unprotected(_)) => None,
             None => None,
         }
     }
 
-    /// Get a timestamp field by name
-    ///
-    /// Returning the chrono::NaiveDateTime which does not include timezone
-    /// or UTC offset because KeePass clients typically store timestamps
-    /// relative to the local time on the machine writing the data without
-    /// including accurate UTC offset or timezone information.
-    pub fn get_time(&self, key: &str) -> Option<&chrono::NaiveDateTime> {
-        self.times.get(key)
-    }
-
     /// Convenience method for getting the value of the 'ExpiryTime' timestamp
-    /// This value is usually only meaningful/useful when expires == true
-    pub fn get_expiry_time(&self) -> Option<&chrono::NaiveDateTime> {
-        self.get_time("ExpiryTime")
+    /// This value is usually only meaningful/useful when times.expires == true
+    pub fn get_expiry_time(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.times.expiry.as_ref()
     }
 
     /// Convenience method for getting a TOTP from this entry
     pub fn get_otp(&'a self) -> Option<TOTP> {
         let otp = self.get("otp");
-        if otp.is_none() {
-            return None;
-        }
-        return TOTP::parse_from_str(otp.unwrap());
+        otp?;
+        TOTP::parse_from_str(otp.unwrap())
     }
 
     /// Convenience method for getting the raw value of the 'otp' field
@@ -461,27 +1536,210 @@ impl<'a> Entry {
     }
 
     pub fn get_binary<'b>(&'a self, key: &str, db: &'b Database) -> Option<&'b [u8]> {
-        let opt_key = self.binary_refs.get(key)?;
-
-        match db.version {
-            DBVersion::KDB3 => {
-                let b = db.meta.binaries.get(opt_key.clone())?;
-                Some(&b)
-            }
-            DBVersion::KDB4 => {
-                if let InnerHeader::KDBX4(header) = &db.inner_header {
-                    let b = header.binaries.get(opt_key.clone())?;
-                    Some(&b.content)
-                } else {
-                    None
-                }
+        let b = db.meta.binaries.get(*self.binary_refs.get(key)?)?;
+        Some(&b.content)
+    }
+
+    /// List the attachments of the entry by name, from the binary pool in the metadata of the
+    /// database
+    pub fn attachments<'b>(
+        &'a self,
+        meta: &'b Meta,
+    ) -> impl Iterator<Item = (&'a str, &'b BinaryAttachment)> {
+        self.binary_refs
+            .iter()
+            .filter_map(move |(name, i)| Some((name.as_str(), meta.binaries.get(*i)?)))
+    }
+
+    /// Attach data to the entry under a name, replacing any attachment with the same name
+    ///
+    /// Attachments with the same content and protection share one binary in the pool of the
+    /// metadata. Since the metadata is borrowed separately from the entry, this can be used on an
+    /// entry of the same database:
+    /// ```
+    /// use keepass::{Database, DatabaseConfig, Entry, Node, NodeRefMut};
+    ///
+    /// let mut db = Database::new(DatabaseConfig::default()).unwrap();
+    /// let mut entry = Entry::default();
+    /// entry.fields.insert("Title".to_string(), keepass::Value::Unprotected("Key".to_string()));
+    /// db.root.children.push(Node::Entry(entry));
+    ///
+    /// if let Some(NodeRefMut::Entry(e)) = db.root.get_mut(&["Key"]) {
+    ///     e.add_attachment(&mut db.meta, "id_ed25519", b"secret".to_vec(), true);
+    /// }
+    /// ```
+    pub fn add_attachment(&mut self, meta: &mut Meta, name: &str, data: Vec<u8>, protected: bool) {
+        let binary = BinaryAttachment::new(data, protected);
+        let position = match meta.binaries.iter().position(|b| *b == binary) {
+            Some(position) => position,
+            None => {
+                meta.binaries.push(binary);
+                meta.binaries.len() - 1
+            }
+        };
+        self.binary_refs.insert(name.to_owned(), position);
+    }
+
+    /// Remove an attachment from the entry, returning whether it had one with that name
+    ///
+    /// The binary stays in the pool, as other entries or the history may still refer to it. Use
+    /// `Database::remove_unused_attachments` to drop binaries that are no longer referred to.
+    pub fn remove_attachment(&mut self, name: &str) -> bool {
+        self.binary_refs.shift_remove(name).is_some()
+    }
+
+    /// Rename an attachment of the entry, keeping its position among the attachments
+    ///
+    /// Returns false if there is no attachment named `old_name` or one named `new_name` exists
+    /// already.
+    pub fn rename_attachment(&mut self, old_name: &str, new_name: &str) -> bool {
+        if self.binary_refs.contains_key(new_name) {
+            return false;
+        }
+        let index = match self.binary_refs.get_index_of(old_name) {
+            Some(index) => index,
+            None => return false,
+        };
+        let position = self.binary_refs[index];
+        self.binary_refs.insert(new_name.to_owned(), position);
+        // move the renamed attachment from the end back to where it was
+        let last = self.binary_refs.len() - 1;
+        self.binary_refs.swap_indices(index, last);
+        self.binary_refs.pop();
+        true
+    }
+
+    /// Update the binary references, given the new position of each attachment in the pool
+    fn update_binary_refs(&mut self, new_positions: &[Option<usize>]) {
+        self.binary_refs = std::mem::take(&mut self.binary_refs)
+            .into_iter()
+            .filter_map(|(name, i)| Some((name, (*new_positions.get(i)?)?)))
+            .collect();
+    }
+
+    /// Set a field, protecting it in memory if the memory protection settings of the metadata
+    /// protect the field or if it was protected already
+    pub fn set_field(&mut self, meta: &Meta, key: &str, value: &str) {
+        let protected = meta.is_protected_field(key)
+            || matches!(self.fields.get(key), Some(Value::Protected(_)));
+        let value = if protected {
+            Value::Protected(value.as_bytes().into())
+        } else {
+            Value::Unprotected(value.to_owned())
+        };
+        self.fields.insert(key.to_owned(), value);
+    }
+
+    /// Remove a field, returning its value if the entry had it
+    pub fn remove_field(&mut self, key: &str) -> Option<Value> {
+        self.fields.shift_remove(key)
+    }
+
+    /// Convenience method for setting the value of the 'Title' field
+    pub fn set_title(&mut self, meta: &Meta, title: &str) {
+        self.set_field(meta, "Title", title)
+    }
+
+    /// Convenience method for setting the value of the 'UserName' field
+    pub fn set_username(&mut self, meta: &Meta, username: &str) {
+        self.set_field(meta, "UserName", username)
+    }
+
+    /// Convenience method for setting the value of the 'Password' field
+    pub fn set_password(&mut self, meta: &Meta, password: &str) {
+        self.set_field(meta, "Password", password)
+    }
+
+    /// Edit the entry, keeping the previous version in its history
+    ///
+    /// If `f` changes the entry, the previous version is added to the history, the modification
+    /// and access times are updated and the history is trimmed to the `HistoryMaxItems` and
+    /// `HistoryMaxSize` limits of the metadata, dropping the oldest versions first.
+    /// ```
+    /// use keepass::{Database, DatabaseConfig, Entry};
+    ///
+    /// let db = Database::new(DatabaseConfig::default()).unwrap();
+    /// let mut entry = Entry::new().unwrap();
+    /// entry.edit(&db.meta, |e| e.set_title(&db.meta, "Mail"));
+    /// entry.edit(&db.meta, |e| e.set_password(&db.meta, "hunter2"));
+    ///
+    /// assert_eq!(entry.get_password(), Some("hunter2"));
+    /// assert_eq!(entry.history.len(), 2);
+    /// ```
+    pub fn edit<F: FnOnce(&mut Entry)>(&mut self, meta: &Meta, f: F) {
+        let mut history = std::mem::take(&mut self.history);
+        let previous = self.clone();
+        f(self);
+        history.append(&mut self.history);
+
+        if *self != previous {
+            let now = Some(now());
+            self.times.last_modification = now;
+            self.times.last_access = now;
+            history.push(previous);
+        }
+        self.history = history;
+        self.trim_history(meta);
+    }
+
+    /// Drop the oldest versions from the history until it fits the limits of the metadata
+    fn trim_history(&mut self, meta: &Meta) {
+        if let Some(max_items) = meta.history_max_items.filter(|m| *m >= 0) {
+            let excess = self.history.len().saturating_sub(max_items as usize);
+            self.history.drain(..excess);
+        }
+
+        if let Some(max_size) = meta.history_max_size.filter(|m| *m >= 0) {
+            let mut size: usize = self.history.iter().map(|h| h.size(meta)).sum();
+            while size > max_size as usize && !self.history.is_empty() {
+                size -= self.history.remove(0).size(meta);
             }
-            _ => None,
         }
     }
+
+    /// Estimate the size of the entry in bytes, like KeePass does to limit the history size
+    fn size(&self, meta: &Meta) -> usize {
+        let value_size = |v: &Value| match v {
+            Value::Bytes(b) => b.len(),
+            Value::Unprotected(u) => u.len(),
+            Value::Protected(p) => p.unsecure().len(),
+        };
+        let fields: usize = self
+            .fields
+            .iter()
+            .map(|(k, v)| k.len() + value_size(v))
+            .sum();
+        let binaries: usize = self
+            .attachments(meta)
+            .map(|(name, b)| name.len() + b.content.len())
+            .sum();
+        let autotype = self.autotype.as_ref().map_or(0, |at| {
+            at.sequence.as_ref().map_or(0, String::len)
+                + at.associations
+                    .iter()
+                    .map(|a| {
+                        a.window.as_ref().map_or(0, String::len)
+                            + a.sequence.as_ref().map_or(0, String::len)
+                    })
+                    .sum::<usize>()
+        });
+        let custom_data: usize = self
+            .custom_data
+            .iter()
+            .map(|(k, item)| k.len() + item.value.len())
+            .sum();
+        let tags: usize = self.tags.iter().map(String::len).sum();
+
+        fields + binaries + autotype + custom_data + tags
+    }
+
+    /// Get the custom icon of the entry from the metadata of the database, if it has one
+    pub fn get_custom_icon<'b>(&'a self, db: &'b Database) -> Option<&'b CustomIcon> {
+        db.meta.get_custom_icon(self.custom_icon_uuid.as_ref()?)
+    }
 }
 
-/// An iterator over Groups and Entries
+/// An iterator over Groups and Entries in breadth-first order
 pub struct NodeIter<'a> {
     queue: VecDeque<NodeRef<'a>>,
 }
@@ -492,7 +1750,7 @@ impl<'a> Iterator for NodeIter<'a> {
     fn next(&mut self) -> Option<NodeRef<'a>> {
         let head = self.queue.pop_front()?;
 
-        if let NodeRef::Group(ref g) = head {
+        if let NodeRef::Group(g) = head {
             self.queue.extend(g.children.iter().map(|n| n.into()))
         }
 
@@ -502,7 +1760,7 @@ impl<'a> Iterator for NodeIter<'a> {
 
 impl<'a> Group {
     pub fn iter(&'a self) -> NodeIter<'a> {
-        (&self).into_iter()
+        self.into_iter()
     }
 }
 
@@ -517,3 +1775,385 @@ impl<'a> IntoIterator for &'a Group {
         NodeIter { queue }
     }
 }
+
+/// A node visited while walking a group, together with where it is below that group
+#[derive(Debug, Eq, PartialEq)]
+pub struct WalkItem<T> {
+    pub node: T,
+
+    /// The names of the groups between the group that is walked and the node, which together
+    /// with the name or title of the node make up a path that `Group::get` accepts
+    pub path: Vec<String>,
+
+    /// How many levels the node is below the group that is walked, which is 0 for that group
+    pub depth: usize,
+}
+
+/// A depth-first iterator over Groups and Entries, which visits each group before its children
+/// like the KeePass user interface lists them
+pub struct Walk<'a> {
+    start: Option<&'a Group>,
+    stack: Vec<(std::slice::Iter<'a, Node>, Vec<String>)>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = WalkItem<NodeRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(group) = self.start.take() {
+            self.stack.push((group.children.iter(), Vec::new()));
+            return Some(WalkItem {
+                node: NodeRef::Group(group),
+                path: Vec::new(),
+                depth: 0,
+            });
+        }
+
+        loop {
+            let (children, path) = self.stack.last_mut()?;
+            let path = path.clone();
+            let depth = path.len() + 1;
+            match children.next() {
+                Some(Node::Entry(e)) => {
+                    return Some(WalkItem {
+                        node: NodeRef::Entry(e),
+                        path,
+                        depth,
+                    });
+                }
+                Some(Node::Group(g)) => {
+                    let mut group_path = path.clone();
+                    group_path.push(g.name.clone());
+                    self.stack.push((g.children.iter(), group_path));
+                    return Some(WalkItem {
+                        node: NodeRef::Group(g),
+                        path,
+                        depth,
+                    });
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Walk<'a> {
+    /// Only visit the entries
+    pub fn entries(self) -> impl Iterator<Item = WalkItem<&'a Entry>> {
+        self.filter_map(|item| match item.node {
+            NodeRef::Entry(e) => Some(WalkItem {
+                node: e,
+                path: item.path,
+                depth: item.depth,
+            }),
+            NodeRef::Group(_) => None,
+        })
+    }
+
+    /// Only visit the groups, including the group that is walked
+    pub fn groups(self) -> impl Iterator<Item = WalkItem<&'a Group>> {
+        self.filter_map(|item| match item.node {
+            NodeRef::Group(g) => Some(WalkItem {
+                node: g,
+                path: item.path,
+                depth: item.depth,
+            }),
+            NodeRef::Entry(_) => None,
+        })
+    }
+}
+
+/// Mutable references to all fields of a group except its children
+///
+/// While iterating mutably, the children of a group are handed out on their own, so they cannot
+/// be changed through the group as well. Use `Group::walk_mut` to add or remove children.
+#[derive(Debug)]
+pub struct GroupMut<'a> {
+    pub name: &'a mut String,
+    pub times: &'a mut Times,
+    pub uuid: &'a mut Uuid,
+    pub notes: &'a mut Option<String>,
+    pub icon_id: &'a mut Option<usize>,
+    pub custom_icon_uuid: &'a mut Option<Uuid>,
+    pub is_expanded: &'a mut bool,
+    pub default_autotype_sequence: &'a mut Option<String>,
+    pub enable_autotype: &'a mut Option<bool>,
+    pub enable_searching: &'a mut Option<bool>,
+    pub last_top_visible_entry: &'a mut Option<Uuid>,
+    pub previous_parent_group: &'a mut Option<Uuid>,
+    pub tags: &'a mut Vec<String>,
+    pub custom_data: &'a mut IndexMap<String, CustomDataItem>,
+    pub unhandled_fields: &'a mut Vec<xmltree::Element>,
+}
+
+/// A group or entry visited by `IterMut`
+#[derive(Debug)]
+pub enum NodeMut<'a> {
+    Group(GroupMut<'a>),
+    Entry(&'a mut Entry),
+}
+
+/// A depth-first iterator over mutable references to Groups and Entries, which visits each group
+/// before its children
+///
+/// The entries and groups below a group are handed out on their own, so groups are visited as a
+/// `GroupMut` that cannot change their children. Use `Group::walk_mut` to add, remove or reorder
+/// children while walking.
+pub struct IterMut<'a> {
+    start: Option<&'a mut Group>,
+    stack: Vec<(std::slice::IterMut<'a, Node>, Vec<String>)>,
+}
+
+impl<'a> Iterator for IterMut<'a> {
+    type Item = WalkItem<NodeMut<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(group) = self.start.take() {
+            let (group, children) = group.split_children_mut();
+            self.stack.push((children.iter_mut(), Vec::new()));
+            return Some(WalkItem {
+                node: NodeMut::Group(group),
+                path: Vec::new(),
+                depth: 0,
+            });
+        }
+
+        loop {
+            let (children, path) = self.stack.last_mut()?;
+            let path = path.clone();
+            let depth = path.len() + 1;
+            match children.next() {
+                Some(Node::Entry(e)) => {
+                    return Some(WalkItem {
+                        node: NodeMut::Entry(e),
+                        path,
+                        depth,
+                    });
+                }
+                Some(Node::Group(g)) => {
+                    let (group, children) = g.split_children_mut();
+                    let mut group_path = path.clone();
+                    group_path.push(group.name.clone());
+                    self.stack.push((children.iter_mut(), group_path));
+                    return Some(WalkItem {
+                        node: NodeMut::Group(group),
+                        path,
+                        depth,
+                    });
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl<'a> IterMut<'a> {
+    /// Only visit the entries
+    pub fn entries(self) -> impl Iterator<Item = WalkItem<&'a mut Entry>> {
+        self.filter_map(|item| match item.node {
+            NodeMut::Entry(e) => Some(WalkItem {
+                node: e,
+                path: item.path,
+                depth: item.depth,
+            }),
+            NodeMut::Group(_) => None,
+        })
+    }
+
+    /// Only visit the groups, including the group that is iterated over
+    pub fn groups(self) -> impl Iterator<Item = WalkItem<GroupMut<'a>>> {
+        self.filter_map(|item| match item.node {
+            NodeMut::Group(g) => Some(WalkItem {
+                node: g,
+                path: item.path,
+                depth: item.depth,
+            }),
+            NodeMut::Entry(_) => None,
+        })
+    }
+}
+
+impl Group {
+    /// Walk this group and all nodes below it in depth-first pre-order
+    /// ```
+    /// use keepass::{Database, NodeRef};
+    /// use std::{fs::File, path::Path};
+    ///
+    /// let path = Path::new("tests/resources/test_db_with_password.kdbx");
+    /// let db = Database::open(&mut File::open(path).unwrap(), Some("demopass"), None).unwrap();
+    ///
+    /// for item in db.root.walk().entries() {
+    ///     println!("{}/{:?}", item.path.join("/"), item.node.get_title());
+    /// }
+    /// ```
+    pub fn walk(&self) -> Walk<'_> {
+        Walk {
+            start: Some(self),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Iterate over mutable references to this group and all nodes below it in depth-first
+    /// pre-order
+    ///
+    /// Groups are visited as a `GroupMut`, which gives access to all fields except the children,
+    /// since those are visited on their own. The path of a node holds the names its groups had
+    /// when they were visited, before any changes made to them after that.
+    /// ```
+    /// use keepass::{Database, DatabaseConfig, Entry, Group, Node};
+    ///
+    /// let mut db = Database::new(DatabaseConfig::default()).unwrap();
+    /// let mut servers = Group::new("Servers").unwrap();
+    /// servers.children.push(Node::Entry(Entry::new().unwrap()));
+    /// db.root.children.push(Node::Group(servers));
+    ///
+    /// let below_servers = |path: &[String]| path.first().is_some_and(|g| g == "Servers");
+    /// for item in db.root.iter_mut().entries().filter(|item| below_servers(&item.path)) {
+    ///     item.node.set_password(&db.meta, "correct horse battery staple");
+    /// }
+    /// ```
+    pub fn iter_mut(&mut self) -> IterMut<'_> {
+        IterMut {
+            start: Some(self),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Call a function with mutable references to this group and all nodes below it, in
+    /// depth-first pre-order
+    ///
+    /// Unlike with `iter_mut`, the function gets the whole group including its children. Each
+    /// group is visited before its children, so changes to the children of a group made by the
+    /// function are taken into account when walking on.
+    pub fn walk_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(WalkItem<NodeRefMut<'_>>),
+    {
+        self.walk_mut_from(&mut Vec::new(), 0, &mut f)
+    }
+
+    fn walk_mut_from<F>(&mut self, path: &mut Vec<String>, depth: usize, f: &mut F)
+    where
+        F: FnMut(WalkItem<NodeRefMut<'_>>),
+    {
+        f(WalkItem {
+            node: NodeRefMut::Group(self),
+            path: path.clone(),
+            depth,
+        });
+
+        if depth > 0 {
+            path.push(self.name.clone());
+        }
+        for child in self.children.iter_mut() {
+            match child {
+                Node::Entry(e) => f(WalkItem {
+                    node: NodeRefMut::Entry(e),
+                    path: path.clone(),
+                    depth: depth + 1,
+                }),
+                Node::Group(g) => g.walk_mut_from(path, depth + 1, f),
+            }
+        }
+        if depth > 0 {
+            path.pop();
+        }
+    }
+
+    /// Split the group into its children and mutable references to its other fields
+    fn split_children_mut(&mut self) -> (GroupMut<'_>, &mut Vec<Node>) {
+        let Group {
+            name,
+            children,
+            times,
+            uuid,
+            notes,
+            icon_id,
+            custom_icon_uuid,
+            is_expanded,
+            default_autotype_sequence,
+            enable_autotype,
+            enable_searching,
+            last_top_visible_entry,
+            previous_parent_group,
+            tags,
+            custom_data,
+            unhandled_fields,
+        } = self;
+        let group = GroupMut {
+            name,
+            times,
+            uuid,
+            notes,
+            icon_id,
+            custom_icon_uuid,
+            is_expanded,
+            default_autotype_sequence,
+            enable_autotype,
+            enable_searching,
+            last_top_visible_entry,
+            previous_parent_group,
+            tags,
+            custom_data,
+            unhandled_fields,
+        };
+        (group, children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::DatabaseConfig,
+        db::{Database, Entry, Group, Node},
+        result::Result,
+    };
+
+    fn assert_index_is_current(db: &Database) {
+        assert_eq!(*db.uuid_index.lock().unwrap(), db.root.uuid_index());
+    }
+
+    #[test]
+    fn test_uuid_index_updates() -> Result<()> {
+        let mut db = Database::new(DatabaseConfig::default())?;
+        let mut groups = Vec::new();
+        for name in &["A", "B", "C"] {
+            let mut group = Group::new(name)?;
+            for _ in 0..2 {
+                group.children.push(Node::Entry(Entry::new()?));
+            }
+            groups.push(group.uuid);
+            db.root.children.push(Node::Group(group));
+        }
+        db.rebuild_uuid_index();
+        let entry = match &db.root.children[0] {
+            Node::Group(g) => g.children[0].uuid(),
+            Node::Entry(_) => unreachable!(),
+        };
+
+        db.move_node(&groups[0], &groups[2])?;
+        assert_index_is_current(&db);
+        db.copy_node(&groups[2], &groups[1], true)?;
+        assert_index_is_current(&db);
+        db.delete_node(&entry)?;
+        assert_index_is_current(&db);
+        db.restore(&entry)?;
+        assert_index_is_current(&db);
+        db.delete_node(&groups[1])?;
+        db.delete_node(&entry)?;
+        db.empty_recycle_bin();
+        assert_index_is_current(&db);
+        db.remove(&["C", "A"]);
+        assert_index_is_current(&db);
+
+        // lookups repair the index after the tree was changed through the public fields
+        db.root.children.reverse();
+        assert!(db.find_by_uuid(&groups[2]).is_some());
+        assert_index_is_current(&db);
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    crypt, parse,
+    result::{DatabaseIntegrityError, Result},
+};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::io::Read;
+
+/// Maximum size of a block in the hashed block stream, same as KeePass uses
+const HASHED_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Reader that verifies and unpacks a KDBX3 hashed block stream one block at a time
+///
+/// Each block is a tuple of size (40 + block_size) with structure:
+///
+/// (
+///   block_id: u32,                                 // a numeric block ID (starts at 0)
+///   block_hash: [u8, 32],                          // SHA256 of block_buffer
+///   block_size: u32,                               // block_size size in bytes
+///   block_buffer: [u8, block_size]                 // Block data, possibly compressed
+/// )
+pub(crate) struct HashedBlockStreamReader<R: Read> {
+    inner: R,
+    block_index: u64,
+    block: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> HashedBlockStreamReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        HashedBlockStreamReader {
+            inner,
+            block_index: 0,
+            block: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Read and verify the next block, returning false once the terminating block was read
+    fn read_block(&mut self) -> Result<bool> {
+        let block_header = parse::read_bytes(&mut self.inner, 40)?;
+        let block_hash = &block_header[4..36];
+        let block_size = LittleEndian::read_u32(&block_header[36..40]) as usize;
+
+        // A block with size 0 means we have hit EOF
+        if block_size == 0 {
+            return Ok(false);
+        }
+
+        let block = parse::read_bytes(&mut self.inner, block_size)?;
+
+        // Test block hash
+        if block_hash != crypt::calculate_sha256(&[&block])?.as_slice() {
+            return Err(DatabaseIntegrityError::BlockHashMismatch {
+                block_index: self.block_index,
+            }
+            .into());
+        }
+
+        self.block_index += 1;
+        self.block = block;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for HashedBlockStreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.block.len() && !self.done {
+            self.done = !self.read_block()?;
+        }
+
+        let n = std::cmp::min(buf.len(), self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Split a buffer into a SHA256-hashed block stream, terminated by an empty block
+pub(crate) fn write_hashed_block_stream(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut block_index: u32 = 0;
+
+    for block in data.chunks(HASHED_BLOCK_SIZE) {
+        out.write_u32::<LittleEndian>(block_index)?;
+        out.extend_from_slice(crypt::calculate_sha256(&[block])?.as_slice());
+        out.write_u32::<LittleEndian>(block.len() as u32)?;
+        out.extend_from_slice(block);
+        block_index += 1;
+    }
+
+    // final block has size 0 and a zeroed-out hash
+    out.write_u32::<LittleEndian>(block_index)?;
+    out.extend_from_slice(&[0; 32]);
+    out.write_u32::<LittleEndian>(0)?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        hashed_block_stream::*,
+        result::{DatabaseIntegrityError, Error},
+    };
+
+    #[test]
+    fn test_write_hashed_block_stream() -> Result<()> {
+        let data = vec![42u8; HASHED_BLOCK_SIZE + 10];
+        let stream = write_hashed_block_stream(&data)?;
+
+        // two data blocks and one terminating block, 40 bytes of overhead each
+        assert_eq!(stream.len(), data.len() + 3 * 40);
+        assert_eq!(LittleEndian::read_u32(&stream[80 + data.len()..]), 2);
+
+        let mut dec = Vec::new();
+        HashedBlockStreamReader::new(&stream[..]).read_to_end(&mut dec)?;
+        assert_eq!(dec, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_broken_hashed_block_stream() -> Result<()> {
+        let data = "this is a test string".as_bytes().to_vec();
+        let mut stream = write_hashed_block_stream(&data)?;
+
+        let mut dec = Vec::new();
+        let res = HashedBlockStreamReader::new(&stream[..stream.len() - 1]).read_to_end(&mut dec);
+        assert!(matches!(
+            res.map_err(Error::from),
+            Err(Error::DatabaseIntegrity {
+                e: DatabaseIntegrityError::UnexpectedEndOfData
+            })
+        ));
+
+        stream[40] ^= 1;
+        let res = HashedBlockStreamReader::new(&stream[..]).read_to_end(&mut dec);
+        assert!(matches!(
+            res.map_err(Error::from),
+            Err(Error::DatabaseIntegrity {
+                e: DatabaseIntegrityError::BlockHashMismatch { block_index: 0 }
+            })
+        ));
+        Ok(())
+    }
+}
@@ -1,45 +1,81 @@
-use crate::result::{DatabaseIntegrityError, Result};
+use crate::{
+    parse,
+    result::{DatabaseIntegrityError, Result},
+    stream::FinishWrite,
+};
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::io::{Read, Write};
 
 use cipher::generic_array::{typenum::U64, GenericArray};
 
-/// Read from a HMAC block stream into a raw buffer
-pub(crate) fn read_hmac_block_stream(data: &[u8], key: &GenericArray<u8, U64>) -> Result<Vec<u8>> {
+/// Reader that verifies and unpacks a HMAC block stream one block at a time
+pub(crate) struct HmacBlockStreamReader<R: Read> {
     // keepassxc src/streams/HmacBlockStream.cpp
+    inner: R,
+    key: GenericArray<u8, U64>,
+    block_index: u64,
+    block: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
 
-    let mut out = Vec::new();
-
-    let mut pos = 0;
-    let mut block_index: u64 = 0;
+impl<R: Read> HmacBlockStreamReader<R> {
+    pub(crate) fn new(inner: R, key: &GenericArray<u8, U64>) -> Self {
+        HmacBlockStreamReader {
+            inner,
+            key: *key,
+            block_index: 0,
+            block: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
 
-    while pos < data.len() {
-        let hmac = &data[pos..(pos + 32)];
-        let size_bytes = &data[(pos + 32)..(pos + 36)];
-        let size = LittleEndian::read_u32(size_bytes) as usize;
-        let block = &data[(pos + 36)..(pos + 36 + size)];
+    /// Read and verify the next block, returning false once the terminating block was read
+    fn read_block(&mut self) -> Result<bool> {
+        let hmac = parse::read_bytes(&mut self.inner, 32)?;
+        let size_bytes = parse::read_bytes(&mut self.inner, 4)?;
+        let size = LittleEndian::read_u32(&size_bytes) as usize;
+        let block = parse::read_bytes(&mut self.inner, size)?;
 
         // verify block hmac
-        let hmac_block_key = get_hmac_block_key(block_index, key)?;
+        let hmac_block_key = get_hmac_block_key(self.block_index, &self.key)?;
         let mut block_index_buf = [0u8; 8];
-        LittleEndian::write_u64(&mut block_index_buf, block_index as u64);
+        LittleEndian::write_u64(&mut block_index_buf, self.block_index);
 
         if hmac
             != crate::crypt::calculate_hmac(
-                &[&block_index_buf, size_bytes, &block],
+                &[&block_index_buf, &size_bytes, &block],
                 &hmac_block_key,
             )?
             .as_slice()
         {
-            return Err(DatabaseIntegrityError::BlockHashMismatch { block_index }.into());
+            return Err(DatabaseIntegrityError::BlockHashMismatch {
+                block_index: self.block_index,
+            }
+            .into());
         }
 
-        pos += 36 + size;
-        block_index += 1;
+        self.block_index += 1;
+        self.block = block;
+        self.pos = 0;
 
-        out.extend_from_slice(block);
+        // the stream is terminated by an empty block
+        Ok(size > 0)
     }
+}
 
-    Ok(out)
+impl<R: Read> Read for HmacBlockStreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.block.len() && !self.done {
+            self.done = !self.read_block()?;
+        }
+
+        let n = std::cmp::min(buf.len(), self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
 }
 
 pub(crate) fn get_hmac_block_key(
@@ -47,42 +83,94 @@ pub(crate) fn get_hmac_block_key(
     key: &GenericArray<u8, U64>,
 ) -> Result<GenericArray<u8, U64>> {
     let mut buf = [0u8; 8];
-    LittleEndian::write_u64(&mut buf, block_index as u64);
+    LittleEndian::write_u64(&mut buf, block_index);
     crate::crypt::calculate_sha512(&[&buf, key])
 }
 
-pub(crate) fn write_hmac_block_stream(data: &[u8], key: &GenericArray<u8, U64>) -> Result<Vec<u8>> {
-    assert!(data.len() < u32::max_value() as usize);
-    // by asserting that we have at most 2^32-1 bytes
-    // we know that there is only 1 block to encode
-    let mut out = Vec::new();
-    let mut block_index = 0;
+/// Maximum size of a block in the HMAC block stream, same as KeePassXC uses
+const HMAC_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Writer that packs a stream into HMAC-verified blocks of up to `HMAC_BLOCK_SIZE` bytes
+pub(crate) struct HmacBlockStreamWriter<W: Write> {
+    inner: W,
+    key: GenericArray<u8, U64>,
+    block_index: u64,
+    block: Vec<u8>,
+}
+
+impl<W: Write> HmacBlockStreamWriter<W> {
+    pub(crate) fn new(inner: W, key: &GenericArray<u8, U64>) -> Self {
+        HmacBlockStreamWriter {
+            inner,
+            key: *key,
+            block_index: 0,
+            block: Vec::new(),
+        }
+    }
 
-    for _data in [data, &[]] {
+    /// Write out the buffered data as the next block
+    fn write_block(&mut self) -> Result<()> {
         let mut block_index_buf = [0u8; 8];
-        LittleEndian::write_u64(&mut block_index_buf, block_index as u64);
-        let hmac_block_key = get_hmac_block_key(block_index, key)?;
+        LittleEndian::write_u64(&mut block_index_buf, self.block_index);
+        let hmac_block_key = get_hmac_block_key(self.block_index, &self.key)?;
 
         let mut size_bytes = vec![];
-        size_bytes.write_u32::<LittleEndian>(_data.len() as u32)?;
+        size_bytes.write_u32::<LittleEndian>(self.block.len() as u32)?;
 
         let hmac = crate::crypt::calculate_hmac(
-            &[&block_index_buf, &size_bytes, &_data],
+            &[&block_index_buf, &size_bytes, &self.block],
             &hmac_block_key,
         )?;
 
-        out.extend_from_slice(&hmac);
-        out.extend_from_slice(&size_bytes);
-        out.extend_from_slice(_data);
-        block_index += 1;
+        self.inner.write_all(&hmac)?;
+        self.inner.write_all(&size_bytes)?;
+        self.inner.write_all(&self.block)?;
+
+        self.block_index += 1;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for HmacBlockStreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = std::cmp::min(buf.len(), HMAC_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == HMAC_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> FinishWrite for HmacBlockStreamWriter<W> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+
+        // the stream is terminated by an empty block
+        self.write_block()?;
+        Ok(self.inner.flush()?)
     }
-    Ok(out)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::hmac_block_stream::*;
 
+    fn write_hmac_block_stream(data: &[u8], key: &GenericArray<u8, U64>) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut writer = Box::new(HmacBlockStreamWriter::new(&mut out, key));
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(out)
+    }
+
     #[test]
     fn test_write_read_block_stream() -> Result<()> {
         let key = vec![
@@ -93,9 +181,65 @@ mod tests {
         ];
         let val = "this is a test string".as_bytes().to_vec();
         let gkey = GenericArray::from_slice(&key);
-        let enc = write_hmac_block_stream(&val, &gkey).unwrap();
-        let dec = read_hmac_block_stream(&enc, &gkey).unwrap();
+        let enc = write_hmac_block_stream(&val, gkey).unwrap();
+        let mut dec = Vec::new();
+        HmacBlockStreamReader::new(&enc[..], gkey).read_to_end(&mut dec)?;
         assert_eq!(val, dec);
         Ok(())
     }
+
+    #[test]
+    fn test_write_read_block_stream_multiple_blocks() -> Result<()> {
+        let key = vec![42u8; 64];
+        let val = vec![7u8; 2 * HMAC_BLOCK_SIZE + 100];
+        let gkey = GenericArray::from_slice(&key);
+        let enc = write_hmac_block_stream(&val, gkey)?;
+
+        // three data blocks and one terminating block with 36 bytes of overhead each
+        assert_eq!(enc.len(), val.len() + 4 * 36);
+
+        let mut dec = Vec::new();
+        HmacBlockStreamReader::new(&enc[..], gkey).read_to_end(&mut dec)?;
+        assert_eq!(val, dec);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_truncated_block_stream() -> Result<()> {
+        let key = vec![42u8; 64];
+        let val = "this is a test string".as_bytes().to_vec();
+        let gkey = GenericArray::from_slice(&key);
+        let enc = write_hmac_block_stream(&val, gkey)?;
+
+        for len in [0, 1, 32, 35, 40, enc.len() - 1] {
+            let mut dec = Vec::new();
+            let res = HmacBlockStreamReader::new(&enc[..len], gkey).read_to_end(&mut dec);
+            assert!(matches!(
+                res.map_err(crate::result::Error::from),
+                Err(crate::result::Error::DatabaseIntegrity {
+                    e: DatabaseIntegrityError::UnexpectedEndOfData
+                })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_tampered_block_stream() -> Result<()> {
+        let key = vec![42u8; 64];
+        let val = "this is a test string".as_bytes().to_vec();
+        let gkey = GenericArray::from_slice(&key);
+        let mut enc = write_hmac_block_stream(&val, gkey)?;
+        enc[40] ^= 1;
+
+        let mut dec = Vec::new();
+        let res = HmacBlockStreamReader::new(&enc[..], gkey).read_to_end(&mut dec);
+        assert!(matches!(
+            res.map_err(crate::result::Error::from),
+            Err(crate::result::Error::DatabaseIntegrity {
+                e: DatabaseIntegrityError::BlockHashMismatch { block_index: 0 }
+            })
+        ));
+        Ok(())
+    }
 }
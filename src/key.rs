@@ -0,0 +1,286 @@
+use cipher::generic_array::{typenum::U32, GenericArray};
+use secstr::SecStr;
+use sha2::{Digest, Sha256};
+
+use crate::result::{Error, Result};
+use std::{convert::TryInto, sync::Arc};
+
+/// A key component that answers a challenge derived from the database, such as a YubiKey slot
+/// configured for HMAC-SHA1 challenge-response
+///
+/// Like KeePassXC, KDBX4 databases use the seed / salt of the key derivation as the challenge,
+/// while KDBX3 databases use the master seed. Either is regenerated on every save, so the response
+/// cannot simply be stored alongside the database.
+pub trait ChallengeResponseProvider: std::fmt::Debug {
+    /// Compute the response to a challenge
+    fn challenge(&self, challenge: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// A software challenge-response key component that computes HMAC-SHA1 responses with a local
+/// secret, giving the same responses as a YubiKey in variable length HMAC-SHA1 mode programmed
+/// with that secret
+#[derive(Debug, Clone)]
+pub struct LocalChallengeResponse {
+    secret: SecStr,
+}
+
+impl LocalChallengeResponse {
+    pub fn new(secret: &[u8]) -> Self {
+        LocalChallengeResponse {
+            secret: SecStr::from(secret),
+        }
+    }
+}
+
+impl ChallengeResponseProvider for LocalChallengeResponse {
+    fn challenge(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        Ok(crate::crypt::calculate_hmac_sha1(&[challenge], self.secret.unsecure())?.to_vec())
+    }
+}
+
+/// The key of a KeePass database, made up of a password, a keyfile, extra key components and/or a
+/// challenge-response provider
+///
+/// The composite key that the key derivation is run on is computed whenever a component is
+/// added, so that the same `DatabaseKey` can be used to open and save any number of databases.
+///
+/// ```
+/// use keepass::{Database, DatabaseKey};
+/// use std::fs::File;
+///
+/// let key = DatabaseKey::new().with_password("demopass");
+/// let path = std::path::Path::new("tests/resources/test_db_with_password.kdbx");
+/// let db = Database::open_with_key(&mut File::open(path)?, &key)?;
+/// # Ok::<(), keepass::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct DatabaseKey {
+    password: Option<SecStr>,
+    keyfile: Option<SecStr>,
+    extra_components: Vec<SecStr>,
+    challenge_response: Option<Arc<dyn ChallengeResponseProvider>>,
+    composite_key: SecStr,
+}
+
+impl Default for DatabaseKey {
+    fn default() -> Self {
+        let mut key = DatabaseKey {
+            password: None,
+            keyfile: None,
+            extra_components: Vec::new(),
+            challenge_response: None,
+            composite_key: SecStr::new(Vec::new()),
+        };
+        key.update_composite_key();
+        key
+    }
+}
+
+impl DatabaseKey {
+    /// Create an empty key, to be extended with a password, a keyfile and/or extra components
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a password to the key
+    pub fn with_password(mut self, password: &str) -> Self {
+        self.password = Some(SecStr::from(password));
+        self.update_composite_key();
+        self
+    }
+
+    /// Add a keyfile to the key, reading it from a std::io::Read
+    pub fn with_keyfile(mut self, keyfile: &mut dyn std::io::Read) -> Result<Self> {
+        self.keyfile = Some(SecStr::new(crate::keyfile::parse(keyfile)?));
+        self.update_composite_key();
+        Ok(self)
+    }
+
+    /// Add an extra component to the key, which is hashed in after the password and keyfile
+    pub fn with_key_component(mut self, component: &[u8]) -> Self {
+        self.extra_components.push(SecStr::from(component));
+        self.update_composite_key();
+        self
+    }
+
+    /// Add a challenge-response provider to the key, which is asked for a response whenever the
+    /// key is used to open or save a KDBX database. Legacy KDB databases do not support it.
+    pub fn with_challenge_response<P: ChallengeResponseProvider + 'static>(
+        mut self,
+        provider: P,
+    ) -> Self {
+        self.challenge_response = Some(Arc::new(provider));
+        self
+    }
+
+    /// Build a key from the optional password and keyfile that `Database::open` and friends take
+    pub(crate) fn from_password_and_keyfile(
+        password: Option<&str>,
+        keyfile: Option<&mut dyn std::io::Read>,
+    ) -> Result<Self> {
+        let mut key = DatabaseKey::new();
+
+        if let Some(p) = password {
+            key = key.with_password(p);
+        }
+
+        if let Some(f) = keyfile {
+            key = key.with_keyfile(f)?;
+        }
+
+        Ok(key)
+    }
+
+    /// The elements of the key, in the order in which they are hashed into the composite key
+    fn key_elements(&self) -> Vec<SecStr> {
+        let mut elements = Vec::new();
+
+        if let Some(p) = &self.password {
+            elements.push(SecStr::new(Sha256::digest(p.unsecure()).to_vec()));
+        }
+
+        if let Some(f) = &self.keyfile {
+            elements.push(f.clone());
+        }
+
+        elements.extend(self.extra_components.iter().cloned());
+        elements
+    }
+
+    fn update_composite_key(&mut self) {
+        let mut digest = Sha256::new();
+        for element in self.key_elements() {
+            digest.update(element.unsecure());
+        }
+        self.composite_key = SecStr::new(digest.finalize().to_vec());
+    }
+
+    /// The composite key of a KDBX database, a SHA256 hash of all key elements
+    ///
+    /// KDBX4 databases hash in the challenge-response result for the KDF seed as well, so use
+    /// `kdbx4_composite_key` for these.
+    pub(crate) fn composite_key(&self) -> GenericArray<u8, U32> {
+        GenericArray::clone_from_slice(self.composite_key.unsecure())
+    }
+
+    /// The composite key of a KDBX4 database, including the challenge-response result for the
+    /// seed of its key derivation
+    pub(crate) fn kdbx4_composite_key(&self, kdf_seed: &[u8]) -> Result<GenericArray<u8, U32>> {
+        match self.challenge_response(kdf_seed)? {
+            Some(response) => {
+                let mut digest = Sha256::new();
+                for element in self.key_elements() {
+                    digest.update(element.unsecure());
+                }
+                digest.update(response);
+                Ok(digest.finalize())
+            }
+            None => Ok(self.composite_key()),
+        }
+    }
+
+    /// A SHA256 hash of the response to `challenge`, if the key has a challenge-response provider
+    pub(crate) fn challenge_response(
+        &self,
+        challenge: &[u8],
+    ) -> Result<Option<GenericArray<u8, U32>>> {
+        match &self.challenge_response {
+            Some(provider) => Ok(Some(Sha256::digest(&provider.challenge(challenge)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The composite key of a KDB database, which uses a single key element without rehashing it
+    pub(crate) fn kdb_composite_key(&self) -> Result<GenericArray<u8, U32>> {
+        match &self.key_elements()[..] {
+            // single pass of SHA256, already done when the key element was created
+            [element] => {
+                let element: [u8; 32] = element
+                    .unsecure()
+                    .try_into()
+                    .map_err(|_| Error::InvalidKeyFile)?;
+                Ok(GenericArray::from(element))
+            }
+            // second pass of SHA256
+            _ => Ok(self.composite_key()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::*;
+
+    #[test]
+    fn test_composite_key() -> Result<()> {
+        let keyfile = [7u8; 32];
+        let key = DatabaseKey::new()
+            .with_password("demopass")
+            .with_keyfile(&mut &keyfile[..])?;
+
+        let password_hash = Sha256::digest(b"demopass");
+        let expected = Sha256::digest(&[&password_hash[..], &keyfile[..]].concat());
+        assert_eq!(key.composite_key(), expected);
+
+        // the components are always hashed in the same order
+        let reordered = DatabaseKey::new()
+            .with_keyfile(&mut &keyfile[..])?
+            .with_password("demopass");
+        assert_eq!(reordered.composite_key(), key.composite_key());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_components() {
+        let key = DatabaseKey::new()
+            .with_password("demopass")
+            .with_key_component(b"extra");
+
+        let password_hash = Sha256::digest(b"demopass");
+        let expected = Sha256::digest(&[&password_hash[..], b"extra"].concat());
+        assert_eq!(key.composite_key(), expected);
+        assert_ne!(
+            key.composite_key(),
+            DatabaseKey::new().with_password("demopass").composite_key()
+        );
+    }
+
+    #[test]
+    fn test_challenge_response() -> Result<()> {
+        let key = DatabaseKey::new().with_password("demopass");
+        assert_eq!(key.challenge_response(b"seed")?, None);
+        assert_eq!(key.kdbx4_composite_key(b"seed")?, key.composite_key());
+
+        let key = key.with_challenge_response(LocalChallengeResponse::new(b"secret"));
+
+        // RFC 2202 test case 2
+        let response =
+            LocalChallengeResponse::new(b"Jefe").challenge(b"what do ya want for nothing?")?;
+        assert_eq!(
+            response,
+            hex_literal::hex!("effcdf6ae5eb2fa2d27416d5f184df9c259a7c79")
+        );
+
+        let response = LocalChallengeResponse::new(b"secret").challenge(b"seed")?;
+        let password_hash = Sha256::digest(b"demopass");
+        let expected =
+            Sha256::digest(&[&password_hash[..], &Sha256::digest(&response)[..]].concat());
+        assert_eq!(key.kdbx4_composite_key(b"seed")?, expected);
+        assert_ne!(key.kdbx4_composite_key(b"other seed")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kdb_composite_key() -> Result<()> {
+        let key = DatabaseKey::new().with_password("demopass");
+        assert_eq!(key.kdb_composite_key()?, Sha256::digest(b"demopass"));
+        assert_eq!(DatabaseKey::new().kdb_composite_key()?, Sha256::digest(b""));
+
+        let key = DatabaseKey::new().with_key_component(b"too short");
+        assert!(matches!(
+            key.kdb_composite_key(),
+            Err(Error::InvalidKeyFile)
+        ));
+        Ok(())
+    }
+}
@@ -1,42 +1,77 @@
 use crate::crypt;
 use crate::result::{Error, Result};
-use xml::name::OwnedName;
-use xml::reader::{EventReader, XmlEvent};
+use xmltree::Element;
 
-fn parse_xml_keyfile(xml: &[u8]) -> Result<Vec<u8>> {
-    let parser = EventReader::new(xml);
+/// Decode a hex string, ignoring any whitespace in between
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
 
-    let mut tag_stack = Vec::new();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
 
-    for ev in parser {
-        match ev.map_err(|_e| Error::InvalidKeyFile)? {
-            XmlEvent::StartElement {
-                name: OwnedName { ref local_name, .. },
-                ..
-            } => {
-                tag_stack.push(local_name.clone());
-            }
-            XmlEvent::EndElement { .. } => {
-                tag_stack.pop();
-            }
-            XmlEvent::Characters(s) => {
-                // Check if we are at KeyFile/Key/Data
-                if tag_stack == ["KeyFile", "Key", "Data"] {
-                    let key_base64 = s.as_bytes().to_vec();
-
-                    // Check if the key is base64-encoded. If yes, return decoded bytes
-                    return if let Ok(key) = ::base64::decode(&key_base64) {
-                        Ok(key)
-                    } else {
-                        Ok(key_base64)
-                    };
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| (pair[0] << 4) | pair[1])
+            .collect(),
+    )
+}
+
+/// The hash that version 2.0 keyfiles store along with the key: the first 4 bytes of its SHA256
+fn key_hash(key: &[u8]) -> Result<String> {
+    Ok(crypt::calculate_sha256(&[key])?[..4]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect())
+}
+
+/// Parse a KeePass XML keyfile, returning `None` if the data is not a KeePass XML keyfile at all
+///
+/// Version 1.0 keyfiles store the key in base64, version 2.0 keyfiles store it in hex, along with
+/// a hash to detect typos when the keyfile was copied by hand.
+fn parse_xml_keyfile(xml: &[u8]) -> Result<Option<Vec<u8>>> {
+    let root = match Element::parse(xml) {
+        Ok(root) if root.name == "KeyFile" => root,
+        _ => return Ok(None),
+    };
+
+    let version = root
+        .get_child("Meta")
+        .and_then(|meta| meta.get_child("Version"))
+        .and_then(|version| version.get_text())
+        .unwrap_or_default();
+
+    let data = root
+        .get_child("Key")
+        .and_then(|key| key.get_child("Data"))
+        .ok_or(Error::InvalidKeyFile)?;
+    let text = data.get_text().unwrap_or_default();
+
+    match version.trim().split('.').next() {
+        // keyfiles without a version are treated as version 1.0
+        Some("1") | Some("") => {
+            // Check if the key is base64-encoded. If yes, return decoded bytes
+            let key = text.trim().as_bytes().to_vec();
+            Ok(Some(base64::decode(&key).unwrap_or(key)))
+        }
+        Some("2") => {
+            let key = decode_hex(&text).ok_or(Error::InvalidKeyFile)?;
+
+            if let Some(hash) = data.attributes.get("Hash") {
+                if !hash.trim().eq_ignore_ascii_case(&key_hash(&key)?) {
+                    return Err(Error::InvalidKeyFile);
                 }
             }
-            _ => {}
+
+            Ok(Some(key))
         }
+        _ => Err(Error::InvalidKeyFile),
     }
-
-    Err(Error::InvalidKeyFile)
 }
 
 pub fn parse(source: &mut dyn std::io::Read) -> Result<Vec<u8>> {
@@ -44,12 +79,162 @@ pub fn parse(source: &mut dyn std::io::Read) -> Result<Vec<u8>> {
     source.read_to_end(&mut buffer)?;
 
     // try to parse the buffer as XML, if successful, use that data instead of full file
-    if let Ok(v) = parse_xml_keyfile(&buffer) {
+    if let Some(v) = parse_xml_keyfile(&buffer)? {
         Ok(v)
     } else if buffer.len() == 32 {
         // legacy binary key format
         Ok(buffer.to_vec())
+    } else if let Some(key) = std::str::from_utf8(&buffer)
+        .ok()
+        .filter(|s| s.len() == 64)
+        .and_then(decode_hex)
+    {
+        // legacy hex key format
+        Ok(key)
     } else {
         Ok(crypt::calculate_sha256(&[&buffer])?.as_slice().to_vec())
     }
 }
+
+/// Write a new KeePass keyfile (version 2.0) with a random 256-bit key
+///
+/// ```
+/// use keepass::{generate_keyfile, DatabaseKey};
+///
+/// let mut keyfile = Vec::new();
+/// generate_keyfile(&mut keyfile)?;
+/// let key = DatabaseKey::new().with_keyfile(&mut keyfile.as_slice())?;
+/// # Ok::<(), keepass::Error>(())
+/// ```
+pub fn generate_keyfile(writer: &mut dyn std::io::Write) -> Result<()> {
+    let key = crypt::random_bytes(32)?;
+
+    // the same layout KeePass uses: lines of four groups of eight hex digits
+    let lines: Vec<String> = key
+        .chunks(16)
+        .map(|line| {
+            line.chunks(4)
+                .map(|group| group.iter().map(|b| format!("{:02X}", b)).collect())
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+        .collect();
+
+    write!(
+        writer,
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\r\n\
+         <KeyFile>\r\n\
+         \t<Meta>\r\n\
+         \t\t<Version>2.0</Version>\r\n\
+         \t</Meta>\r\n\
+         \t<Key>\r\n\
+         \t\t<Data Hash=\"{}\">\r\n\
+         \t\t\t{}\r\n\
+         \t\t</Data>\r\n\
+         \t</Key>\r\n\
+         </KeyFile>",
+        key_hash(&key)?,
+        lines.join("\r\n\t\t\t")
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keyfile::*;
+
+    const KEYFILE_V2: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<KeyFile>
+    <Meta>
+        <Version>2.0</Version>
+    </Meta>
+    <Key>
+        <Data Hash="EA943DB3">
+            1F3BB3E4 4D7C8A6A 9E2B6C4F 0D1E2F30
+            41526374 8596A7B8 C9DAEBFC 0D1E2F30
+        </Data>
+    </Key>
+</KeyFile>"#;
+
+    fn key_v2() -> Vec<u8> {
+        decode_hex(
+            "1F3BB3E44D7C8A6A9E2B6C4F0D1E2F30
+             415263748596A7B8C9DAEBFC0D1E2F30",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00 ff\n10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("0g"), None);
+    }
+
+    #[test]
+    fn test_parse_keyfile_v1() -> Result<()> {
+        let keyfile = "<?xml version=\"1.0\"?><KeyFile><Meta><Version>1.00</Version></Meta>\
+             <Key><Data>AQID</Data></Key></KeyFile>";
+        assert_eq!(parse(&mut keyfile.as_bytes())?, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_keyfile_v2() -> Result<()> {
+        assert_eq!(key_hash(&key_v2())?, "EA943DB3");
+        assert_eq!(parse(&mut KEYFILE_V2.as_bytes())?, key_v2());
+
+        // the hash is optional
+        let keyfile = KEYFILE_V2.replace(" Hash=\"EA943DB3\"", "");
+        assert_eq!(parse(&mut keyfile.as_bytes())?, key_v2());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_broken_keyfile_v2() {
+        for keyfile in [
+            KEYFILE_V2.replace("EA943DB3", "EA943DB4"),
+            KEYFILE_V2.replace("1F3BB3E4", "1F3BB3E5"),
+            KEYFILE_V2.replace("1F3BB3E4", "1F3BB3EX"),
+            KEYFILE_V2.replace("<Version>2.0", "<Version>3.0"),
+            KEYFILE_V2.replace("Data", "Foo"),
+        ] {
+            assert!(matches!(
+                parse(&mut keyfile.as_bytes()),
+                Err(Error::InvalidKeyFile)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_hex_keyfile() -> Result<()> {
+        let keyfile = "1F3BB3E44D7C8A6A9E2B6C4F0D1E2F30415263748596A7B8C9DAEBFC0D1E2F30";
+        assert_eq!(parse(&mut keyfile.as_bytes())?, key_v2());
+
+        // anything else is hashed
+        let keyfile = "not a key";
+        assert_eq!(
+            parse(&mut keyfile.as_bytes())?,
+            crypt::calculate_sha256(&[keyfile.as_bytes()])?.to_vec()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_keyfile() -> Result<()> {
+        let mut keyfile = Vec::new();
+        generate_keyfile(&mut keyfile)?;
+
+        let mut other = Vec::new();
+        generate_keyfile(&mut other)?;
+        assert_ne!(keyfile, other);
+
+        let root = Element::parse(keyfile.as_slice()).unwrap();
+        let data = root.get_child("Key").unwrap().get_child("Data").unwrap();
+        let key = decode_hex(&data.get_text().unwrap()).unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(parse(&mut keyfile.as_slice())?, key);
+        Ok(())
+    }
+}
@@ -34,18 +34,26 @@
 #![recursion_limit = "1024"]
 
 mod compress;
-mod config;
+pub mod config;
 mod crypt;
 mod db;
+mod hashed_block_stream;
 mod hmac_block_stream;
+mod key;
 mod keyfile;
 pub mod result;
+mod stream;
+mod uuid;
 mod variant_dictionary;
 mod xml_parse;
 
 pub mod otp;
 pub mod parse;
 
+pub use self::config::DatabaseConfig;
 pub use self::db::*;
-pub use self::result::{CryptoError, DatabaseIntegrityError, Error, Result};
+pub use self::key::{ChallengeResponseProvider, DatabaseKey, LocalChallengeResponse};
+pub use self::keyfile::generate_keyfile;
+pub use self::result::{CryptoError, DatabaseIntegrityError, Error, Result, SerializationError};
+pub use self::uuid::Uuid;
 // see https://gist.github.com/msmuenchen/9318327 for file format details
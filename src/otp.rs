@@ -45,7 +45,7 @@ impl std::fmt::Display for OTPCode {
 
 impl TOTP {
     pub fn parse_from_str(s: &str) -> Option<TOTP> {
-        let parsed = Url::parse(s).unwrap();
+        let parsed = Url::parse(s).ok()?;
         let query_pairs = parsed.query_pairs();
 
         let label: String = parsed.path().trim_start_matches("/").to_string();
@@ -56,39 +56,28 @@ impl TOTP {
         let mut algorithm: AlgoType = AlgoType::Sha1;
 
         for pair in query_pairs {
-            let (k, v) = pair;
-            match k {
-                Cow::Borrowed("secret") => secret = Some(v.into_owned()),
-                Cow::Borrowed("issuer") => issuer = Some(v.into_owned()),
-                Cow::Borrowed("period") => period = Some(v.parse::<u64>().unwrap()),
-                Cow::Borrowed("digits") => digits = Some(v.parse::<u32>().unwrap()),
-                Cow::Borrowed("algorithm") => {
-                    algorithm = match v {
-                        Cow::Borrowed("SHA1") => AlgoType::Sha1,
-                        Cow::Borrowed("SHA256") => AlgoType::Sha256,
-                        Cow::Borrowed("SHA512") => AlgoType::Sha512,
-                        _ => panic!("Received an unsupported algorithm for TOTP"),
+            let (k, v): (Cow<str>, Cow<str>) = pair;
+            match k.as_ref() {
+                "secret" => secret = Some(v.into_owned()),
+                "issuer" => issuer = Some(v.into_owned()),
+                "period" => period = Some(v.parse::<u64>().ok().filter(|p| *p > 0)?),
+                "digits" => digits = Some(v.parse::<u32>().ok()?),
+                "algorithm" => {
+                    algorithm = match v.as_ref() {
+                        "SHA1" => AlgoType::Sha1,
+                        "SHA256" => AlgoType::Sha256,
+                        "SHA512" => AlgoType::Sha512,
+                        _ => return None,
                     }
                 }
-                Cow::Borrowed(_) => (),
-                Cow::Owned(_) => panic!("Somehow got an owned value"),
+                _ => (),
             }
         }
 
-        if secret.is_none() {
-            return None;
-        }
-        if issuer.is_none() {
-            return None;
-        }
         Some(TOTP {
             label,
-            secret: base32::decode(
-                base32::Alphabet::RFC4648 { padding: true },
-                &secret.unwrap(),
-            )
-            .unwrap(),
-            issuer: issuer.unwrap(),
+            secret: base32::decode(base32::Alphabet::RFC4648 { padding: true }, &secret?)?,
+            issuer: issuer?,
             period: period.unwrap_or(DEFAULT_PERIOD),
             digits: digits.unwrap_or(DEFAULT_DIGITS),
             algorithm,
@@ -107,10 +96,34 @@ impl TOTP {
         };
 
         let valid_for = Duration::from_secs(self.period - (time % self.period));
-        return OTPCode {
+        OTPCode {
             code,
             valid_for,
             period: Duration::from_secs(self.period),
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::otp::*;
+
+    #[test]
+    fn test_parse_invalid_totp() {
+        for s in [
+            "not a url",
+            "otpauth://totp/test?issuer=me",
+            "otpauth://totp/test?secret=!!!&issuer=me",
+            "otpauth://totp/test?secret=JBSWY3DPEHPK3PXP&issuer=me&period=abc",
+            "otpauth://totp/test?secret=JBSWY3DPEHPK3PXP&issuer=me&period=0",
+            "otpauth://totp/test?secret=JBSWY3DPEHPK3PXP&issuer=me&digits=-1",
+            "otpauth://totp/test?secret=JBSWY3DPEHPK3PXP&issuer=me&algorithm=MD5",
+        ] {
+            assert_eq!(TOTP::parse_from_str(s), None, "{}", s);
+        }
+
+        assert!(
+            TOTP::parse_from_str("otpauth://totp/test?secret=JBSWY3DPEHPK3PXP&issuer=me").is_some()
+        );
     }
 }
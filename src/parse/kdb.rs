@@ -2,13 +2,15 @@ use crate::{
     config::OuterCipherSuite,
     crypt::kdf::Kdf,
     db::{DBVersion, Database, Entry, Group, Header, InnerHeader, Meta, Node, NodeRefMut, Value},
+    key::DatabaseKey,
+    parse,
     result::{DatabaseIntegrityError, Error, Result},
+    uuid::Uuid,
 };
 
 use byteorder::{ByteOrder, LittleEndian};
-use cipher::generic_array::GenericArray;
 
-use std::{collections::HashMap, convert::TryInto, str};
+use std::{collections::HashMap, str};
 
 #[derive(Debug, PartialEq)]
 pub struct KDBHeader {
@@ -116,9 +118,9 @@ fn parse_groups(root: &mut Group, header_num_groups: u32, data: &mut &[u8]) -> R
     let mut num_groups = 0; // the total number of parsed groups
     while num_groups < header_num_groups as usize {
         // Read group TLV
-        let field_type = LittleEndian::read_u16(&data[0..]);
-        let field_size = LittleEndian::read_u32(&data[2..]);
-        let field_value = &data[6..6 + field_size as usize];
+        let field_type = parse::read_u16(data, 0)?;
+        let field_size = parse::read_u32(data, 2)?;
+        let field_value = parse::get_slice(data, 6, field_size as usize)?;
 
         match field_type {
             0x0000 => {} // KeePass ignores this field type
@@ -183,9 +185,9 @@ fn parse_groups(root: &mut Group, header_num_groups: u32, data: &mut &[u8]) -> R
             }
         }
 
-        *data = &data[6 + field_size as usize..];
+        *data = &data[6 + field_value.len()..];
     }
-    if gid != None {
+    if gid.is_some() {
         return Err(DatabaseIntegrityError::IncompleteKDBGroup.into());
     }
     // Collapse last group tree branch into the root
@@ -206,15 +208,16 @@ fn parse_entries(
     let mut num_entries = 0;
     while num_entries < header_num_entries {
         // Read entry TLV
-        let field_type = LittleEndian::read_u16(&data[0..]);
-        let field_size = LittleEndian::read_u32(&data[2..]);
-        let field_value = &data[6..6 + field_size as usize];
+        let field_type = parse::read_u16(data, 0)?;
+        let field_size = parse::read_u32(data, 2)?;
+        let field_value = parse::get_slice(data, 6, field_size as usize)?;
 
         match field_type {
             0x0000 => {} // KeePass ignores this field type
             0x0001 => {
                 // uuid
                 ensure_length(field_type, field_size, 16)?;
+                entry.uuid = Uuid::from_slice(field_value)?;
             }
             0x0002 => {
                 // GroupId
@@ -260,7 +263,7 @@ fn parse_entries(
                     .ok_or_else(|| {
                         Error::from(DatabaseIntegrityError::InvalidKDBGroupId { group_id })
                     })?
-                    .into_iter()
+                    .iter()
                     .map(|v| v.as_str())
                     .collect();
 
@@ -268,7 +271,7 @@ fn parse_entries(
                 let group = if let Some(NodeRefMut::Group(g)) = group {
                     g
                 } else {
-                    panic!("Follow group_path")
+                    return Err(DatabaseIntegrityError::InvalidKDBGroupId { group_id }.into());
                 };
 
                 group.children.push(Node::Entry(entry));
@@ -281,9 +284,9 @@ fn parse_entries(
             }
         }
 
-        *data = &data[6 + field_size as usize..];
+        *data = &data[6 + field_value.len()..];
     }
-    if gid != None {
+    if gid.is_some() {
         return Err(DatabaseIntegrityError::IncompleteKDBEntry.into());
     }
 
@@ -296,7 +299,7 @@ fn parse_db(header: &KDBHeader, data: &[u8]) -> Result<Group> {
         ..Default::default()
     };
 
-    let mut pos = &data[..];
+    let mut pos = data;
 
     let gid_map = parse_groups(&mut root, header.num_groups, &mut pos)?;
 
@@ -305,20 +308,14 @@ fn parse_db(header: &KDBHeader, data: &[u8]) -> Result<Group> {
     Ok(root)
 }
 
-pub(crate) fn parse(data: &[u8], key_elements: &[Vec<u8>]) -> Result<Database> {
+pub(crate) fn parse(data: &[u8], key: &DatabaseKey) -> Result<Database> {
     let header = parse_header(data)?;
 
     // Rest of file after header is payload
     let payload_encrypted = &data[HEADER_SIZE..];
 
     // derive master key from composite key, transform_seed, transform_rounds and master_seed
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
-    let composite_key = if key_elements.len() == 1 {
-        let key_element: [u8; 32] = key_elements[0].try_into().unwrap();
-        GenericArray::from(key_element) // single pass of SHA256, already done before the call to parse()
-    } else {
-        crate::crypt::calculate_sha256(&key_elements)? // second pass of SHA256
-    };
+    let composite_key = key.kdb_composite_key()?;
 
     // KDF the same as for KDBX
     let transformed_key = crate::crypt::kdf::AesKdf {
@@ -342,25 +339,34 @@ pub(crate) fn parse(data: &[u8], key_elements: &[Vec<u8>]) -> Result<Database> {
     let payload_padded = cipher
         .get_cipher(&master_key, header.encryption_iv.as_ref())?
         .decrypt(payload_encrypted)?;
-    let padlen = payload_padded[payload_padded.len() - 1] as usize;
-    let payload = &payload_padded[..payload_padded.len() - padlen];
+    let padlen = payload_padded.last().copied().unwrap_or(0) as usize;
+    let payload = payload_padded
+        .len()
+        .checked_sub(padlen)
+        .map(|len| &payload_padded[..len])
+        .ok_or(Error::IncorrectKey)?;
 
     // Check if we decrypted correctly
-    let hash = crate::crypt::calculate_sha256(&[&payload])?;
+    let hash = crate::crypt::calculate_sha256(&[payload])?;
     if header.contents_hash != hash.as_slice() {
         return Err(Error::IncorrectKey);
     }
 
-    let root_group = parse_db(&header, &payload)?;
+    let root_group = parse_db(&header, payload)?;
 
     Ok(Database {
         header: Header::KDB(header),
         inner_header: InnerHeader::None,
         root: root_group,
         meta: Meta {
-            recyclebin_uuid: "".to_owned(),
+            recyclebin_uuid: Uuid::nil(),
             ..Default::default()
         },
+        deleted_objects: Vec::new(),
+        unhandled_root_fields: Vec::new(),
+        unhandled_fields: Vec::new(),
         version: DBVersion::KDB2,
+        key: Some(key.clone()),
+        uuid_index: Default::default(),
     })
 }
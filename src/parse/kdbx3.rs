@@ -1,16 +1,23 @@
 use crate::{
     config::{Compression, InnerCipherSuite, OuterCipherSuite},
     crypt::{self, kdf::Kdf},
-    db::{DBVersion, Database, Group, Header, InnerHeader, Meta, Node},
-    result::{DatabaseIntegrityError, Error, Result},
+    db::{DBVersion, Database, Header, InnerHeader},
+    hashed_block_stream::{write_hashed_block_stream, HashedBlockStreamReader},
+    key::DatabaseKey,
+    parse,
+    result::{CryptoError, DatabaseIntegrityError, Error, Result, SerializationError},
     xml_parse,
 };
 
-use byteorder::{ByteOrder, LittleEndian};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use cipher::generic_array::{typenum::U32, GenericArray};
 
-use std::convert::TryFrom;
+use std::{
+    convert::{TryFrom, TryInto},
+    io::Read,
+};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KDBX3Header {
     // https://gist.github.com/msmuenchen/9318327
     pub version: u32,
@@ -31,7 +38,19 @@ pub struct KDBX3Header {
 impl KDBX3Header {
     pub(crate) fn decryptor(&self) -> Result<Box<dyn crypt::ciphers::Cipher>> {
         let stream_key = crypt::calculate_sha256(&[self.protected_stream_key.as_ref()])?;
-        Ok(self.inner_cipher.get_cipher(&stream_key)?)
+        self.inner_cipher.get_cipher(&stream_key)
+    }
+
+    /// A copy of the header with new random seeds, IVs and keys, as written on every save
+    pub(crate) fn with_new_seeds(&self) -> Result<KDBX3Header> {
+        Ok(KDBX3Header {
+            master_seed: crypt::random_bytes(32)?,
+            transform_seed: crypt::random_bytes(32)?,
+            outer_iv: crypt::random_bytes(self.outer_cipher.iv_size())?,
+            protected_stream_key: crypt::random_bytes(32)?,
+            stream_start: crypt::random_bytes(32)?,
+            ..self.clone()
+        })
     }
 }
 fn parse_header(data: &[u8]) -> Result<KDBX3Header> {
@@ -70,9 +89,9 @@ fn parse_header(data: &[u8]) -> Result<KDBX3Header> {
         //   entry_buffer: [u8; entry_length]       // the entry buffer
         // )
 
-        let entry_type = data[pos];
-        let entry_length: usize = LittleEndian::read_u16(&data[pos + 1..(pos + 3)]) as usize;
-        let entry_buffer = &data[(pos + 3)..(pos + 3 + entry_length)];
+        let entry_type = parse::get_slice(data, pos, 1)?[0];
+        let entry_length: usize = parse::read_u16(data, pos + 1)? as usize;
+        let entry_buffer = parse::get_slice(data, pos + 3, entry_length)?;
 
         pos += 3 + entry_length;
 
@@ -93,9 +112,7 @@ fn parse_header(data: &[u8]) -> Result<KDBX3Header> {
 
             // COMPRESSIONFLAGS - first byte determines compression of payload
             3 => {
-                compression = Some(Compression::try_from(LittleEndian::read_u32(
-                    &entry_buffer,
-                ))?);
+                compression = Some(Compression::try_from(parse::read_u32(entry_buffer, 0)?)?);
             }
 
             // MASTERSEED - Master seed for deriving the master key
@@ -105,7 +122,7 @@ fn parse_header(data: &[u8]) -> Result<KDBX3Header> {
             5 => transform_seed = Some(entry_buffer.to_vec()),
 
             // TRANSFORMROUNDS - Number of rounds used in derivation of transformed key
-            6 => transform_rounds = Some(LittleEndian::read_u64(entry_buffer)),
+            6 => transform_rounds = Some(parse::read_u64(entry_buffer, 0)?),
 
             // ENCRYPTIONIV - Initialization Vector for decrypting the payload
             7 => outer_iv = Some(entry_buffer.to_vec()),
@@ -119,9 +136,10 @@ fn parse_header(data: &[u8]) -> Result<KDBX3Header> {
             // INNERRANDOMSTREAMID - specifies which cipher suite
             //                       to use for decrypting the inner protected values
             10 => {
-                inner_cipher = Some(InnerCipherSuite::try_from(LittleEndian::read_u32(
+                inner_cipher = Some(InnerCipherSuite::try_from(parse::read_u32(
                     entry_buffer,
-                ))?);
+                    0,
+                )?)?);
             }
 
             _ => {
@@ -169,50 +187,97 @@ fn parse_header(data: &[u8]) -> Result<KDBX3Header> {
     })
 }
 
+/// This function will _grow_ buf by the necessary amount and write to it
+fn write_header_field(buf: &mut Vec<u8>, field_type: u8, data: &[u8]) -> Result<()> {
+    let size: u16 = data
+        .len()
+        .try_into()
+        .map_err(|_| SerializationError::HeaderFieldTooLarge {
+            field_type,
+            size: data.len(),
+        })?;
+
+    buf.push(field_type);
+    buf.write_u16::<LittleEndian>(size)?;
+    buf.extend(data);
+    Ok(())
+}
+
+fn serialize_header(header: &KDBX3Header) -> Result<Vec<u8>> {
+    let mut buf = vec![0; 12];
+    buf[0..4].copy_from_slice(&parse::KDBX_IDENTIFIER);
+    // version
+    LittleEndian::write_u32(&mut buf[4..], 0xb54b_fb67);
+    // minor version
+    LittleEndian::write_u16(&mut buf[8..], header.file_minor_version);
+    // major version
+    LittleEndian::write_u16(&mut buf[10..], 3);
+
+    let cs: Vec<u8> = (&header.outer_cipher).into();
+    write_header_field(&mut buf, 2, &cs)?;
+
+    let mut cr: [u8; 4] = [0, 0, 0, 0];
+    LittleEndian::write_u32(&mut cr, (&header.compression).into());
+    write_header_field(&mut buf, 3, &cr)?;
+
+    write_header_field(&mut buf, 4, &header.master_seed)?;
+    write_header_field(&mut buf, 5, &header.transform_seed)?;
+
+    let mut rounds: [u8; 8] = [0; 8];
+    LittleEndian::write_u64(&mut rounds, header.transform_rounds);
+    write_header_field(&mut buf, 6, &rounds)?;
+
+    write_header_field(&mut buf, 7, &header.outer_iv)?;
+    write_header_field(&mut buf, 8, &header.protected_stream_key)?;
+    write_header_field(&mut buf, 9, &header.stream_start)?;
+
+    let mut ic: [u8; 4] = [0, 0, 0, 0];
+    LittleEndian::write_u32(&mut ic, (&header.inner_cipher).into());
+    write_header_field(&mut buf, 10, &ic)?;
+
+    // "\r\n\r\n" is what KeePass writes
+    write_header_field(&mut buf, 0, &[13, 10, 13, 10])?;
+
+    Ok(buf)
+}
+
 /// Open, decrypt and parse a KeePass database from a source and a password
-pub(crate) fn parse(data: &[u8], key_elements: &[Vec<u8>]) -> Result<Database> {
-    let (header, xml_blocks) = decrypt_xml(data, key_elements)?;
+pub(crate) fn parse(source: &mut dyn Read, key: &DatabaseKey) -> Result<Database> {
+    let (header, header_data, mut xml) = decrypt_payload(source, key)?;
 
     // Derive stream key for decrypting inner protected values and set up decryption context
     let stream_key = crypt::calculate_sha256(&[header.protected_stream_key.as_ref()])?;
     let mut inner_decryptor = header.inner_cipher.get_cipher(&stream_key)?;
-    let mut meta = Meta {
-        recyclebin_uuid: Default::default(),
-        ..Default::default()
-    };
-
-    let mut root = Group {
-        name: "Root".to_owned(),
-        ..Default::default()
-    };
-
-    // Parse XML data blocks
-    for block_buffer in xml_blocks {
-        let (block_group, _meta) =
-            xml_parse::parse_xml_block(&block_buffer, &mut *inner_decryptor)?;
-        // FIXME: This should only be done when _meta has a meaningful value
-        meta = _meta;
-        root.children.push(Node::Group(block_group));
-    }
 
-    // Re-root db.root if it contains only one child (if there was only one block)
-    if root.children.len() == 1 {
-        let new_root = if let Node::Group(g) = root.children.drain(..).next().unwrap() {
-            Some(g)
-        } else {
-            None
-        };
-        if let Some(g) = new_root {
-            root = g;
+    let mut document = xml_parse::parse_xml(&mut xml, &mut *inner_decryptor)?;
+
+    // KDBX 3.1 stores a hash of the outer header in the metadata. It is derived from the header
+    // on every save, so verify it here and do not keep it around as a regular field.
+    let header_hash = document
+        .meta
+        .unhandled_fields
+        .iter()
+        .position(|el| el.name == "HeaderHash")
+        .map(|i| document.meta.unhandled_fields.remove(i));
+    if let Some(header_hash) = header_hash {
+        let header_hash = base64::decode(header_hash.get_text().unwrap_or_default().as_bytes())
+            .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+        if header_hash != crypt::calculate_sha256(&[&header_data])?.as_slice() {
+            return Err(DatabaseIntegrityError::HeaderHashMismatch.into());
         }
     }
 
     let db = Database {
         header: Header::KDBX3(header),
         inner_header: InnerHeader::None,
-        root,
-        meta,
+        root: document.root,
+        meta: document.meta,
+        deleted_objects: document.deleted_objects,
+        unhandled_root_fields: document.unhandled_root_fields,
+        unhandled_fields: document.unhandled_fields,
         version: DBVersion::KDB3,
+        key: Some(key.clone()),
+        uuid_index: Default::default(),
     };
 
     Ok(db)
@@ -220,85 +285,226 @@ pub(crate) fn parse(data: &[u8], key_elements: &[Vec<u8>]) -> Result<Database> {
 
 /// Open and decrypt a KeePass KDBX3 database from a source and a password
 pub(crate) fn decrypt_xml(
-    data: &[u8],
-    key_elements: &[Vec<u8>],
+    source: &mut dyn Read,
+    key: &DatabaseKey,
 ) -> Result<(KDBX3Header, Vec<Vec<u8>>)> {
-    // parse header
-    let header = parse_header(data)?;
-
-    let mut pos = header.body_start;
+    let (header, _, mut payload) = decrypt_payload(source, key)?;
 
-    // Turn enums into appropriate trait objects
-    let compression = header.compression.get_compression();
+    let mut xml = Vec::new();
+    payload.read_to_end(&mut xml)?;
 
-    // Rest of file after header is payload
-    let payload_encrypted = &data[pos..];
-
-    // derive master key from composite key, transform_seed, transform_rounds and master_seed
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
-    let composite_key = crypt::calculate_sha256(&key_elements)?;
+    Ok((header, vec![xml]))
+}
 
+/// Derive the master key from the composite key, transform_seed, transform_rounds and master_seed
+///
+/// The response of a challenge-response key component to the master seed is hashed into the
+/// master key as well, the same way KeePassXC does it.
+fn derive_master_key(header: &KDBX3Header, key: &DatabaseKey) -> Result<GenericArray<u8, U32>> {
     // KDF is hard coded for KDBX 3
     let transformed_key = crypt::kdf::AesKdf {
         seed: header.transform_seed.clone(),
         rounds: header.transform_rounds,
     }
-    .transform_key(&composite_key)?;
+    .transform_key(&key.composite_key())?;
 
-    let master_key = crypt::calculate_sha256(&[header.master_seed.as_ref(), &transformed_key])?;
-
-    // Decrypt payload
-    let payload = header
-        .outer_cipher
-        .get_cipher(&master_key, header.outer_iv.as_ref())?
-        .decrypt(payload_encrypted)?;
+    match key.challenge_response(&header.master_seed)? {
+        Some(response) => {
+            crypt::calculate_sha256(&[header.master_seed.as_ref(), &response, &transformed_key])
+        }
+        None => crypt::calculate_sha256(&[header.master_seed.as_ref(), &transformed_key]),
+    }
+}
 
-    // Check if we decrypted correctly
-    if &payload[0..header.stream_start.len()] != header.stream_start.as_slice() {
+/// Parse the header of a KeePass KDBX3 database and set up the decryption of its payload
+///
+/// Returns the header, its raw bytes and a reader over the XML document that decrypts, verifies
+/// and decompresses the payload as it is read.
+fn decrypt_payload<'a>(
+    source: &'a mut dyn Read,
+    key: &DatabaseKey,
+) -> Result<(KDBX3Header, Vec<u8>, Box<dyn Read + 'a>)> {
+    // parse header
+    let header_data = parse::read_kdbx_header(source, 2)?;
+    let header = parse_header(&header_data)?;
+
+    let master_key = derive_master_key(&header, key)?;
+
+    // Rest of file after header is the encrypted payload
+    let mut payload = header.outer_cipher.get_decrypt_reader(
+        &master_key,
+        header.outer_iv.as_ref(),
+        Box::new(source),
+    )?;
+
+    // Check if we decrypted correctly. Small payloads are decrypted in one go, so a wrong key can
+    // also show up as broken padding here.
+    let stream_start = match parse::read_bytes(&mut payload, header.stream_start.len()) {
+        Err(Error::DatabaseIntegrity {
+            e:
+                DatabaseIntegrityError::Crypto {
+                    e: CryptoError::BlockMode { .. },
+                },
+        }) => return Err(Error::IncorrectKey),
+        res => res?,
+    };
+    if stream_start != header.stream_start {
         return Err(Error::IncorrectKey);
     }
 
-    let mut buf = Vec::new();
+    // The rest of the payload is a hashed block stream of the (possibly compressed) XML
+    let blocks = HashedBlockStreamReader::new(payload);
+    let xml = header.compression.get_decompress_reader(Box::new(blocks));
 
-    pos = 32;
-    let mut block_index = 0;
-    loop {
-        // Parse blocks in payload.
-        //
-        // Each block is a tuple of size (40 + block_size) with structure:
-        //
-        // (
-        //   block_id: u32,                                 // a numeric block ID (starts at 0)
-        //   block_hash: [u8, 32],                          // SHA256 of block_buffer_compressed
-        //   block_size: u32,                               // block_size size in bytes
-        //   block_buffer_compressed: [u8, block_size]      // Block data, possibly compressed
-        // )
+    Ok((header, header_data, xml))
+}
 
-        // let block_id = LittleEndian::read_u32(&payload[pos..(pos + 4)]);
-        let block_hash = &payload[(pos + 4)..(pos + 36)];
-        let block_size = LittleEndian::read_u32(&payload[(pos + 36)..(pos + 40)]) as usize;
+/// Encrypt a KeePass KDBX3 database from representation and key elements
+pub(crate) fn encrypt_xml(d: &Database, key: &DatabaseKey) -> Result<Vec<u8>> {
+    let h = match &d.header {
+        Header::KDBX3(h) => h.with_new_seeds()?,
+        _ => return Err(SerializationError::UnsupportedDatabaseVersion.into()),
+    };
 
-        // A block with size 0 means we have hit EOF
-        if block_size == 0 {
-            break;
-        }
+    let mut payload = serialize_header(&h)?;
 
-        let block_buffer_compressed = &payload[(pos + 40)..(pos + 40 + block_size)];
+    let master_key = derive_master_key(&h, key)?;
 
-        // Test block hash
-        let block_hash_check = crypt::calculate_sha256(&[&block_buffer_compressed])?;
-        if block_hash != block_hash_check.as_slice() {
-            return Err(DatabaseIntegrityError::BlockHashMismatch { block_index }.into());
-        }
+    let mut inner_encryptor = h.decryptor()?;
+    let mut xml = Vec::new();
+    xml_parse::write_xml(&mut xml, d, &mut *inner_encryptor)?;
+
+    let payload_compressed = h.compression.get_compression().compress(&xml)?;
+
+    // the stream start bytes are prepended so that readers can check for the correct key
+    let mut payload_plain = h.stream_start.clone();
+    payload_plain.extend(write_hashed_block_stream(&payload_compressed)?);
+
+    let payload_encrypted = h
+        .outer_cipher
+        .get_cipher(&master_key, h.outer_iv.as_ref())?
+        .encrypt(&payload_plain)?;
 
-        // Decompress block_buffer_compressed
-        buf.append(&mut block_buffer_compressed.to_vec());
+    payload.extend(payload_encrypted);
 
-        pos += 40 + block_size;
-        block_index += 1;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        config::{Compression, InnerCipherSuite, OuterCipherSuite},
+        db::{Database, NodeRef},
+        parse::kdbx3::*,
+        result::Result,
+    };
+    use std::{fs::File, path::Path};
+
+    #[test]
+    fn test_encrypt_decrypt_xml() -> Result<()> {
+        let key = DatabaseKey::new().with_password("demopass");
+
+        let path = Path::new("tests/resources/test_db_with_password.kdbx");
+        let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+
+        let encrypted = encrypt_xml(&db, &key)?;
+        let parsed_db = parse(&mut &encrypted[..], &key)?;
+
+        // every save uses new seeds, IVs and keys, everything else is kept
+        match (&parsed_db.header, &db.header) {
+            (Header::KDBX3(parsed), Header::KDBX3(orig)) => {
+                assert_ne!(parsed.master_seed, orig.master_seed);
+                assert_ne!(parsed.transform_seed, orig.transform_seed);
+                assert_ne!(parsed.outer_iv, orig.outer_iv);
+                assert_ne!(parsed.protected_stream_key, orig.protected_stream_key);
+                assert_ne!(parsed.stream_start, orig.stream_start);
+                assert_eq!(
+                    KDBX3Header {
+                        master_seed: orig.master_seed.clone(),
+                        transform_seed: orig.transform_seed.clone(),
+                        outer_iv: orig.outer_iv.clone(),
+                        protected_stream_key: orig.protected_stream_key.clone(),
+                        stream_start: orig.stream_start.clone(),
+                        ..parsed.clone()
+                    },
+                    *orig
+                );
+            }
+            _ => panic!("Expected KDBX3 headers"),
+        }
+        assert_eq!(parsed_db.meta, db.meta);
+        assert_eq!(parsed_db.version, db.version);
+
+        for (p_node, orig_node) in parsed_db.root.iter().zip(db.root.iter()) {
+            match (p_node, orig_node) {
+                (NodeRef::Entry(e), NodeRef::Entry(og)) => {
+                    assert_eq!(og.uuid, e.uuid);
+                    assert_eq!(og.fields, e.fields);
+                    assert_eq!(og.times, e.times);
+                    assert_eq!(og.history.len(), e.history.len());
+                }
+                (NodeRef::Group(g), NodeRef::Group(og)) => {
+                    assert_eq!(og.name, g.name);
+                    assert_eq!(og.uuid, g.uuid);
+                    assert_eq!(og.unhandled_fields, g.unhandled_fields);
+                }
+                _ => panic!("Node types differ after round-trip"),
+            }
+        }
+        Ok(())
     }
-    let mut xml_blocks = Vec::new();
-    xml_blocks.push(compression.decompress(&buf)?.to_vec());
 
-    Ok((header, xml_blocks))
+    #[test]
+    fn test_write_and_parse_header() -> Result<()> {
+        let header = KDBX3Header {
+            version: 0xb54b_fb67,
+            file_major_version: 3,
+            file_minor_version: 1,
+            outer_cipher: OuterCipherSuite::AES256,
+            compression: Compression::GZip,
+            master_seed: vec![1; 32],
+            transform_seed: vec![2; 32],
+            transform_rounds: 6000,
+            outer_iv: vec![3; 16],
+            protected_stream_key: vec![4; 32],
+            stream_start: vec![5; 32],
+            inner_cipher: InnerCipherSuite::Salsa20,
+            body_start: 222,
+        };
+        let bytes = serialize_header(&header)?;
+        let parsed = parse_header(&bytes)?;
+        assert_eq!(header, parsed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_header_field_too_large() -> Result<()> {
+        let header = KDBX3Header {
+            version: 0xb54b_fb67,
+            file_major_version: 3,
+            file_minor_version: 1,
+            outer_cipher: OuterCipherSuite::AES256,
+            compression: Compression::GZip,
+            master_seed: vec![1; 70000],
+            transform_seed: vec![2; 32],
+            transform_rounds: 6000,
+            outer_iv: vec![3; 16],
+            protected_stream_key: vec![4; 32],
+            stream_start: vec![5; 32],
+            inner_cipher: InnerCipherSuite::Salsa20,
+            body_start: 0,
+        };
+        match serialize_header(&header) {
+            Err(Error::Serialization {
+                e: SerializationError::HeaderFieldTooLarge { field_type, size },
+            }) => {
+                assert_eq!(field_type, 4);
+                assert_eq!(size, 70000);
+            }
+            _ => panic!("Expected a header field size error"),
+        }
+        Ok(())
+    }
 }
@@ -2,22 +2,26 @@ use byteorder::WriteBytesExt;
 use num_derive::FromPrimitive;
 use num_derive::ToPrimitive;
 use num_traits::FromPrimitive;
-use num_traits::ToPrimitive;
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{Read, Write},
+};
 
 use crate::{
     config::{Compression, InnerCipherSuite, KdfSettings, OuterCipherSuite},
     crypt,
-    db::{DBVersion, Database, Header, InnerHeader},
-    hmac_block_stream, parse,
-    result::{DatabaseIntegrityError, Error, Result},
+    db::{BinaryAttachment, DBVersion, Database, Header, InnerHeader},
+    hmac_block_stream,
+    key::DatabaseKey,
+    parse,
+    result::{DatabaseIntegrityError, Error, Result, SerializationError},
     variant_dictionary::VariantDictionary,
     xml_parse,
 };
 
 use byteorder::{ByteOrder, LittleEndian};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KDBX4Header {
     // https://gist.github.com/msmuenchen/9318327
     pub version: u32,
@@ -31,15 +35,22 @@ pub struct KDBX4Header {
     pub body_start: usize,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct BinaryAttachment {
-    flags: u8,
-    pub content: Vec<u8>,
+impl KDBX4Header {
+    /// A copy of the header with a new master seed, encryption IV and KDF salt, as written on
+    /// every save
+    pub(crate) fn with_new_seeds(&self) -> Result<KDBX4Header> {
+        Ok(KDBX4Header {
+            master_seed: crypt::random_bytes(32)?,
+            outer_iv: crypt::random_bytes(self.outer_cipher.iv_size())?,
+            kdf: self.kdf.with_new_seed()?,
+            ..self.clone()
+        })
+    }
 }
 
 #[derive(Debug, FromPrimitive, ToPrimitive)]
 #[repr(u8)]
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum InnerHeaderFieldType {
     END = 0,
     RANDOM_STREAM_ID = 1,
@@ -49,6 +60,7 @@ pub enum InnerHeaderFieldType {
 
 #[derive(Debug, FromPrimitive, ToPrimitive)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum OuterHeaderFieldType {
     END = 0,
     COMMENT = 1,
@@ -63,7 +75,7 @@ impl TryFrom<&[u8]> for BinaryAttachment {
     type Error = Error;
 
     fn try_from(data: &[u8]) -> Result<Self> {
-        let flags = data[0];
+        let flags = parse::get_slice(data, 0, 1)?[0];
         let content = data[1..].to_vec();
 
         Ok(BinaryAttachment { flags, content })
@@ -78,19 +90,25 @@ impl From<&BinaryAttachment> for Vec<u8> {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KDBX4InnerHeader {
-    inner_random_stream: InnerCipherSuite,
-    inner_random_stream_key: Vec<u8>,
-    pub binaries: Vec<BinaryAttachment>,
-    body_start: usize,
+    pub(crate) inner_random_stream: InnerCipherSuite,
+    pub(crate) inner_random_stream_key: Vec<u8>,
+    pub(crate) body_start: usize,
 }
 
 impl KDBX4InnerHeader {
     pub(crate) fn decryptor(&self) -> Result<Box<dyn crypt::ciphers::Cipher>> {
-        Ok(self
-            .inner_random_stream
-            .get_cipher(&self.inner_random_stream_key)?)
+        self.inner_random_stream
+            .get_cipher(&self.inner_random_stream_key)
+    }
+
+    /// A copy of the header with a new inner stream key, as written on every save
+    pub(crate) fn with_new_seeds(&self) -> Result<KDBX4InnerHeader> {
+        Ok(KDBX4InnerHeader {
+            inner_random_stream_key: crypt::random_bytes(self.inner_random_stream.key_size())?,
+            ..self.clone()
+        })
     }
 }
 
@@ -126,9 +144,9 @@ fn parse_outer_header(data: &[u8]) -> Result<KDBX4Header> {
         //   entry_buffer: [u8; entry_length]       // the entry buffer
         // )
 
-        let entry_type = data[pos];
-        let entry_length: usize = LittleEndian::read_u32(&data[pos + 1..(pos + 5)]) as usize;
-        let entry_buffer = &data[(pos + 5)..(pos + 5 + entry_length)];
+        let entry_type = parse::get_slice(data, pos, 1)?[0];
+        let entry_length: usize = parse::read_u32(data, pos + 1)? as usize;
+        let entry_buffer = parse::get_slice(data, pos + 5, entry_length)?;
 
         pos += 5 + entry_length;
 
@@ -149,9 +167,7 @@ fn parse_outer_header(data: &[u8]) -> Result<KDBX4Header> {
 
             // COMPRESSIONFLAGS - first byte determines compression of payload
             Some(OuterHeaderFieldType::COMPRESSIONFLAGS) => {
-                compression = Some(Compression::try_from(LittleEndian::read_u32(
-                    &entry_buffer,
-                ))?);
+                compression = Some(Compression::try_from(parse::read_u32(entry_buffer, 0)?)?);
             }
 
             // MASTERSEED - Master seed for deriving the master key
@@ -203,7 +219,11 @@ fn parse_outer_header(data: &[u8]) -> Result<KDBX4Header> {
     })
 }
 
-fn parse_inner_header(data: &[u8]) -> Result<KDBX4InnerHeader> {
+/// Read the inner header from the start of the decrypted and decompressed payload
+///
+/// The source is left positioned at the start of the XML document. The binary attachments are
+/// returned separately, as they are kept in the metadata of the database.
+fn read_inner_header(source: &mut dyn Read) -> Result<(KDBX4InnerHeader, Vec<BinaryAttachment>)> {
     let mut pos = 0;
 
     let mut inner_random_stream = None;
@@ -211,24 +231,26 @@ fn parse_inner_header(data: &[u8]) -> Result<KDBX4InnerHeader> {
     let mut binaries = Vec::new();
 
     loop {
-        let entry_type = data[pos];
-        let entry_length: usize = LittleEndian::read_u32(&data[pos + 1..(pos + 5)]) as usize;
-        let entry_buffer = &data[(pos + 5)..(pos + 5 + entry_length)];
+        let field = parse::read_bytes(source, 5)?;
+        let entry_type = field[0];
+        let entry_length: usize = parse::read_u32(&field, 1)? as usize;
+        let entry_buffer = parse::read_bytes(source, entry_length)?;
 
         pos += 5 + entry_length;
 
         match FromPrimitive::from_u8(entry_type) {
             Some(InnerHeaderFieldType::END) => break,
             Some(InnerHeaderFieldType::RANDOM_STREAM_ID) => {
-                inner_random_stream = Some(InnerCipherSuite::try_from(LittleEndian::read_u32(
+                inner_random_stream = Some(InnerCipherSuite::try_from(parse::read_u32(
                     &entry_buffer,
-                ))?);
+                    0,
+                )?)?);
             }
             Some(InnerHeaderFieldType::RANDOM_STREAM_KEY) => {
-                inner_random_stream_key = Some(entry_buffer.to_vec())
+                inner_random_stream_key = Some(entry_buffer)
             }
             Some(InnerHeaderFieldType::BINARY_ATTACHMENT) => {
-                let binary = BinaryAttachment::try_from(entry_buffer)?;
+                let binary = BinaryAttachment::try_from(&entry_buffer[..])?;
                 binaries.push(binary);
             }
             None => {
@@ -249,111 +271,142 @@ fn parse_inner_header(data: &[u8]) -> Result<KDBX4InnerHeader> {
     let inner_random_stream = get_or_err(inner_random_stream, "Inner random stream UUID")?;
     let inner_random_stream_key = get_or_err(inner_random_stream_key, "Inner random stream key")?;
 
-    Ok(KDBX4InnerHeader {
-        inner_random_stream,
-        inner_random_stream_key,
+    Ok((
+        KDBX4InnerHeader {
+            inner_random_stream,
+            inner_random_stream_key,
+            body_start: pos,
+        },
         binaries,
-        body_start: pos,
-    })
+    ))
 }
-fn serialize_inner_header(header: &KDBX4InnerHeader) -> Result<Vec<u8>> {
-    let mut buf: Vec<u8> = vec![];
+/// Write the inner header one field at a time, so that attachments are not copied all at once
+fn write_inner_header(
+    header: &KDBX4InnerHeader,
+    binaries: &[BinaryAttachment],
+    writer: &mut dyn Write,
+) -> Result<()> {
     let mut random_stream: Vec<u8> = vec![];
 
     random_stream.write_u32::<LittleEndian>((&header.inner_random_stream).into())?;
     write_value_inner_header(
-        &mut buf,
+        writer,
         InnerHeaderFieldType::RANDOM_STREAM_ID,
         &random_stream,
-    );
+    )?;
 
     write_value_inner_header(
-        &mut buf,
+        writer,
         InnerHeaderFieldType::RANDOM_STREAM_KEY,
         &header.inner_random_stream_key,
-    );
-    for binary in &header.binaries {
+    )?;
+    for binary in binaries {
         let b_buf: Vec<u8> = binary.into();
-        write_value_inner_header(&mut buf, InnerHeaderFieldType::BINARY_ATTACHMENT, &b_buf);
+        write_value_inner_header(writer, InnerHeaderFieldType::BINARY_ATTACHMENT, &b_buf)?;
     }
-    write_value_inner_header(&mut buf, InnerHeaderFieldType::END, &[]);
-    Ok(buf)
+    write_value_inner_header(writer, InnerHeaderFieldType::END, &[])?;
+    Ok(())
 }
 
-/// This function will _grow_ buf by the necessary amount and write to it
-fn write_value_header(buf: &mut Vec<u8>, field_type: u8, data: &[u8]) {
-    buf.push(ToPrimitive::to_u8(&field_type).unwrap());
-    buf.write_u32::<LittleEndian>(data.len().try_into().unwrap())
-        .unwrap();
-    buf.extend(data);
+/// Write a header field as a (type, length, data) triplet
+fn write_value_header<W: Write + ?Sized>(
+    writer: &mut W,
+    field_type: u8,
+    data: &[u8],
+) -> Result<()> {
+    let size: u32 = data
+        .len()
+        .try_into()
+        .map_err(|_| SerializationError::HeaderFieldTooLarge {
+            field_type,
+            size: data.len(),
+        })?;
+
+    writer.write_u8(field_type)?;
+    writer.write_u32::<LittleEndian>(size)?;
+    writer.write_all(data)?;
+    Ok(())
 }
 
-/// This function will _grow_ buf by the necessary amount and write to it
-fn write_value_inner_header(buf: &mut Vec<u8>, field_type: InnerHeaderFieldType, data: &[u8]) {
-    write_value_header(buf, ToPrimitive::to_u8(&field_type).unwrap(), data)
+fn write_value_inner_header(
+    writer: &mut dyn Write,
+    field_type: InnerHeaderFieldType,
+    data: &[u8],
+) -> Result<()> {
+    write_value_header(writer, field_type as u8, data)
 }
 
 /// This function will _grow_ buf by the necessary amount and write to it
-fn write_value_outer_header(buf: &mut Vec<u8>, field_type: OuterHeaderFieldType, data: &[u8]) {
-    write_value_header(buf, ToPrimitive::to_u8(&field_type).unwrap(), data)
+fn write_value_outer_header(
+    buf: &mut Vec<u8>,
+    field_type: OuterHeaderFieldType,
+    data: &[u8],
+) -> Result<()> {
+    write_value_header(buf, field_type as u8, data)
 }
-fn serialize_outer_header(header: &KDBX4Header) -> Vec<u8> {
+fn serialize_outer_header(header: &KDBX4Header) -> Result<Vec<u8>> {
     let mut vec = vec![0; 12];
     vec[0..4].copy_from_slice(&parse::KDBX_IDENTIFIER);
     // version
     LittleEndian::write_u32(&mut vec[4..], 0xb54b_fb67);
     // minor version
-    LittleEndian::write_u16(&mut vec[8..], 0);
+    LittleEndian::write_u16(&mut vec[8..], header.file_minor_version);
     // major version
     LittleEndian::write_u16(&mut vec[10..], 4);
 
     let cs: Vec<u8> = (&header.outer_cipher).into();
-    write_value_outer_header(&mut vec, OuterHeaderFieldType::CIPHERID, &cs);
+    write_value_outer_header(&mut vec, OuterHeaderFieldType::CIPHERID, &cs)?;
 
     let mut cr: [u8; 4] = [0, 0, 0, 0];
     LittleEndian::write_u32(&mut cr, (&header.compression).into());
-    write_value_outer_header(&mut vec, OuterHeaderFieldType::COMPRESSIONFLAGS, &cr);
+    write_value_outer_header(&mut vec, OuterHeaderFieldType::COMPRESSIONFLAGS, &cr)?;
 
     write_value_outer_header(
         &mut vec,
         OuterHeaderFieldType::MASTERSEED,
         &header.master_seed,
-    );
+    )?;
 
     write_value_outer_header(
         &mut vec,
         OuterHeaderFieldType::ENCRYPTIONIV,
         &header.outer_iv,
-    );
+    )?;
 
     let kdf_dict: VariantDictionary = (&header.kdf).into();
-    let kdf_settings: Vec<u8> = kdf_dict.serialize().unwrap();
-    write_value_outer_header(&mut vec, OuterHeaderFieldType::KDFPARAMS, &kdf_settings);
+    let kdf_settings: Vec<u8> = kdf_dict.serialize()?;
+    write_value_outer_header(&mut vec, OuterHeaderFieldType::KDFPARAMS, &kdf_settings)?;
     write_value_outer_header(
         &mut vec,
         OuterHeaderFieldType::END,
-        &vec![13, 10, 13, 10], // "\r\n\r\n" is what keepassxc writes
-    );
-    vec
+        &[13, 10, 13, 10], // "\r\n\r\n" is what keepassxc writes
+    )?;
+    Ok(vec)
 }
 
 /// Open, decrypt and parse a KeePass database from a source and key elements
-pub(crate) fn parse(data: &[u8], key_elements: &[Vec<u8>]) -> Result<Database> {
-    let (header, inner_header, xml) = decrypt_xml(data, key_elements)?;
+pub(crate) fn parse(source: &mut dyn Read, key: &DatabaseKey) -> Result<Database> {
+    let (header, inner_header, binaries, mut xml) = decrypt_payload(source, key)?;
 
     // Initialize inner decryptor from inner header params
     let mut inner_decryptor = inner_header
         .inner_random_stream
         .get_cipher(&inner_header.inner_random_stream_key)?;
 
-    let (root, meta) = xml_parse::parse_xml_block(&xml, &mut *inner_decryptor)?;
+    let mut document = xml_parse::parse_xml(&mut xml, &mut *inner_decryptor)?;
+    document.meta.binaries = binaries;
 
     let db = Database {
         header: Header::KDBX4(header),
         inner_header: InnerHeader::KDBX4(inner_header),
-        root,
-        meta,
+        root: document.root,
+        meta: document.meta,
+        deleted_objects: document.deleted_objects,
+        unhandled_root_fields: document.unhandled_root_fields,
+        unhandled_fields: document.unhandled_fields,
         version: DBVersion::KDB4,
+        key: Some(key.clone()),
+        uuid_index: Default::default(),
     };
 
     Ok(db)
@@ -361,116 +414,112 @@ pub(crate) fn parse(data: &[u8], key_elements: &[Vec<u8>]) -> Result<Database> {
 
 /// Open and decrypt a KeePass KDBX4 database from a source and key elements
 pub(crate) fn decrypt_xml(
-    data: &[u8],
-    key_elements: &[Vec<u8>],
+    source: &mut dyn Read,
+    key: &DatabaseKey,
 ) -> Result<(KDBX4Header, KDBX4InnerHeader, Vec<u8>)> {
-    // parse header
-    let header = parse_outer_header(data)?;
-    let pos = header.body_start;
+    let (header, inner_header, _, mut payload) = decrypt_payload(source, key)?;
 
-    // split file into segments:
+    let mut xml = Vec::new();
+    payload.read_to_end(&mut xml)?;
+
+    Ok((header, inner_header, xml))
+}
+
+/// Verify the headers of a KeePass KDBX4 database and set up the decryption of its payload
+///
+/// Returns the outer and inner headers, the binary attachments, and a reader over the XML
+/// document that decrypts, verifies and decompresses the payload as it is read.
+#[allow(clippy::type_complexity)]
+fn decrypt_payload<'a>(
+    source: &'a mut dyn Read,
+    key: &DatabaseKey,
+) -> Result<(
+    KDBX4Header,
+    KDBX4InnerHeader,
+    Vec<BinaryAttachment>,
+    Box<dyn Read + 'a>,
+)> {
+    // the file is made of the following segments:
     //      header_data         - The outer header data
     //      header_sha256       - A Sha256 hash of header_data (for verification of header integrity)
-    //      header_hmac         - A HMAC of the header_data (for verification of the key_elements)
+    //      header_hmac         - A HMAC of the header_data (for verification of the key)
     //      hmac_block_stream   - A HMAC-verified block stream of encrypted and compressed blocks
-    let header_data = &data[0..pos];
-    let header_sha256 = &data[pos..(pos + 32)];
-    let header_hmac = &data[(pos + 32)..(pos + 64)];
-    let hmac_block_stream = &data[(pos + 64)..];
+    let header_data = parse::read_kdbx_header(source, 4)?;
+    let header = parse_outer_header(&header_data)?;
+    let header_sha256 = parse::read_bytes(source, 32)?;
+    let header_hmac = parse::read_bytes(source, 32)?;
 
     // derive master key from composite key, transform_seed, transform_rounds and master_seed
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
-    let composite_key = crypt::calculate_sha256(&key_elements)?;
+    let composite_key = key.kdbx4_composite_key(header.kdf.seed())?;
     let transformed_key = header.kdf.get_kdf().transform_key(&composite_key)?;
     let master_key = crypt::calculate_sha256(&[header.master_seed.as_ref(), &transformed_key])?;
 
     // verify header
-    if header_sha256 != crypt::calculate_sha256(&[&data[0..pos]])?.as_slice() {
+    if header_sha256 != crypt::calculate_sha256(&[&header_data])?.as_slice() {
         return Err(DatabaseIntegrityError::HeaderHashMismatch.into());
     }
 
     // verify credentials
     let hmac_key = crypt::calculate_sha512(&[&header.master_seed, &transformed_key, b"\x01"])?;
-    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::max_value(), &hmac_key)?;
-    if header_hmac != crypt::calculate_hmac(&[header_data], &header_hmac_key)?.as_slice() {
+    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::MAX, &hmac_key)?;
+    if header_hmac != crypt::calculate_hmac(&[&header_data], &header_hmac_key)?.as_slice() {
         return Err(Error::IncorrectKey);
     }
 
-    let payload_encrypted =
-        hmac_block_stream::read_hmac_block_stream(&hmac_block_stream, &hmac_key)?;
-
-    // Decrypt and decompress encrypted payload
-    let payload_compressed = header
-        .outer_cipher
-        .get_cipher(&master_key, header.outer_iv.as_ref())?
-        .decrypt(&payload_encrypted)?;
-    let payload = header
-        .compression
-        .get_compression()
-        .decompress(&payload_compressed)?;
+    // Verify, decrypt and decompress the payload as it is read
+    let payload_encrypted = hmac_block_stream::HmacBlockStreamReader::new(source, &hmac_key);
+    let payload_compressed = header.outer_cipher.get_decrypt_reader(
+        &master_key,
+        header.outer_iv.as_ref(),
+        Box::new(payload_encrypted),
+    )?;
+    let mut payload = header.compression.get_decompress_reader(payload_compressed);
 
-    // KDBX4 has inner header, too - parse it
-    let inner_header = parse_inner_header(&payload)?;
+    // KDBX4 has inner header, too - parse it. After it is one XML document
+    let (inner_header, binaries) = read_inner_header(&mut payload)?;
 
-    // after inner header is one XML document
-    let xml = &payload[inner_header.body_start..];
-
-    Ok((header, inner_header, xml.to_vec()))
+    Ok((header, inner_header, binaries, payload))
 }
 
-/// Encrypt a KeePass KDBX4 database from representation and key elements
-pub(crate) fn encrypt_xml(d: &Database, key_elements: Vec<u8>) -> Result<Vec<u8>> {
-    let mut payload = Vec::new();
+/// Encrypt a KeePass KDBX4 database from representation and key elements, writing it out
+///
+/// The payload is streamed through compression, the outer cipher and the HMAC block stream, so
+/// it is never held in memory as a whole.
+pub(crate) fn encrypt_xml(d: &Database, writer: &mut dyn Write, key: &DatabaseKey) -> Result<()> {
+    let (h, ih) = match (&d.header, &d.inner_header) {
+        (Header::KDBX4(h), InnerHeader::KDBX4(ih)) => (h.with_new_seeds()?, ih.with_new_seeds()?),
+        _ => return Err(SerializationError::UnsupportedDatabaseVersion.into()),
+    };
 
-    if let Header::KDBX4(h) = &d.header {
-        payload.extend(serialize_outer_header(&h));
+    let header_data = serialize_outer_header(&h)?;
 
-        let composite_key = crypt::calculate_sha256(&[key_elements.as_slice()]).unwrap();
-        let transformed_key = h.kdf.get_kdf().transform_key(&composite_key).unwrap();
-        let hmac_key =
-            crypt::calculate_sha512(&[&h.master_seed, &transformed_key, b"\x01"]).unwrap();
-        let header_hmac_key =
-            hmac_block_stream::get_hmac_block_key(u64::max_value(), &hmac_key).unwrap();
-        let header_hmac = crypt::calculate_hmac(&[&payload], &header_hmac_key).unwrap();
-        let header_sha256 = crypt::calculate_sha256(&[payload.as_slice()]).unwrap();
-        let master_key =
-            crypt::calculate_sha256(&[h.master_seed.as_ref(), &transformed_key]).unwrap();
+    let composite_key = key.kdbx4_composite_key(h.kdf.seed())?;
+    let transformed_key = h.kdf.get_kdf().transform_key(&composite_key)?;
+    let hmac_key = crypt::calculate_sha512(&[&h.master_seed, &transformed_key, b"\x01"])?;
+    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::MAX, &hmac_key)?;
+    let header_hmac = crypt::calculate_hmac(&[&header_data], &header_hmac_key)?;
+    let header_sha256 = crypt::calculate_sha256(&[&header_data])?;
+    let master_key = crypt::calculate_sha256(&[h.master_seed.as_ref(), &transformed_key])?;
 
-        payload.extend(header_sha256.as_slice());
-        payload.extend(header_hmac.as_slice());
-        // start with payload
+    writer.write_all(&header_data)?;
+    writer.write_all(&header_sha256)?;
+    writer.write_all(&header_hmac)?;
 
-        let mut inner_payload = vec![];
+    let payload_encrypted = Box::new(hmac_block_stream::HmacBlockStreamWriter::new(
+        writer, &hmac_key,
+    ));
+    let payload_compressed =
+        h.outer_cipher
+            .get_encrypt_writer(&master_key, h.outer_iv.as_ref(), payload_encrypted)?;
+    let mut payload = h.compression.get_compress_writer(payload_compressed);
 
-        let mut inner_encryptor = d.get_decryptor().unwrap();
-        let xml = xml_parse::write_xml(&d, &mut *inner_encryptor).unwrap();
+    // inner header, followed by the XML document
+    write_inner_header(&ih, &d.meta.binaries, &mut payload)?;
 
-        // inner header
-        if let InnerHeader::KDBX4(ih) = &d.inner_header {
-            inner_payload.extend(serialize_inner_header(&ih).unwrap());
-        }
-        inner_payload.extend(xml);
-
-        let payload_compressed = h
-            .compression
-            .get_compression()
-            .compress(&inner_payload)
-            .unwrap();
-        let payload_encrypted = h
-            .outer_cipher
-            .get_cipher(&master_key, h.outer_iv.as_ref())
-            .unwrap()
-            .encrypt(&payload_compressed)
-            .unwrap();
-
-        let payload_hmacd =
-            hmac_block_stream::write_hmac_block_stream(&payload_encrypted, &hmac_key).unwrap();
-        payload.extend(payload_hmacd);
-    } else {
-        panic!("expected kdb4");
-    }
+    let mut inner_encryptor = ih.decryptor()?;
+    xml_parse::write_xml(&mut payload, d, &mut *inner_encryptor)?;
 
-    Ok(payload)
+    payload.finish()
 }
 
 #[cfg(test)]
@@ -489,26 +538,42 @@ mod test {
 
     #[test]
     fn test_encrypt_decrypt_xml() -> Result<()> {
-        let mut key_elements: Vec<Vec<u8>> = Vec::new();
-
-        key_elements.push(
-            crypt::calculate_sha256(&["demopass".as_bytes()])?
-                .as_slice()
-                .to_vec(),
-        );
-
-        let key_elements: Vec<u8> = key_elements.into_iter().flatten().collect();
+        let key = DatabaseKey::new().with_password("demopass");
 
         let path = Path::new("tests/resources/test_db_kdbx4_with_password_aes.kdbx");
         let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
 
-        let encrypted = encrypt_xml(&db, key_elements.clone()).unwrap();
-        let parsed_db = parse(&encrypted, &[key_elements]).unwrap();
-
-        assert_eq!(parsed_db.inner_header, db.inner_header);
+        let mut encrypted = Vec::new();
+        encrypt_xml(&db, &mut encrypted, &key)?;
+        let parsed_db = parse(&mut &encrypted[..], &key)?;
+
+        // every save uses new seeds, IVs and keys, everything else is kept
+        match (&parsed_db.header, &db.header) {
+            (Header::KDBX4(parsed), Header::KDBX4(orig)) => {
+                assert_ne!(parsed.master_seed, orig.master_seed);
+                assert_ne!(parsed.outer_iv, orig.outer_iv);
+                assert_ne!(parsed.kdf.seed(), orig.kdf.seed());
+                assert_eq!(
+                    KDBX4Header {
+                        master_seed: orig.master_seed.clone(),
+                        outer_iv: orig.outer_iv.clone(),
+                        kdf: orig.kdf.clone(),
+                        ..parsed.clone()
+                    },
+                    *orig
+                );
+            }
+            _ => panic!("Expected KDBX4 headers"),
+        }
+        match (&parsed_db.inner_header, &db.inner_header) {
+            (InnerHeader::KDBX4(parsed), InnerHeader::KDBX4(orig)) => {
+                assert_ne!(parsed.inner_random_stream_key, orig.inner_random_stream_key);
+                assert_eq!(parsed.inner_random_stream, orig.inner_random_stream);
+            }
+            _ => panic!("Expected KDBX4 inner headers"),
+        }
         assert_eq!(parsed_db.meta, db.meta);
         assert_eq!(parsed_db.version, db.version);
-        assert_eq!(parsed_db.header, db.header);
 
         assert_eq!(parsed_db.root.name, db.root.name);
         assert_eq!(parsed_db.root.children.len(), db.root.children.len());
@@ -539,8 +604,6 @@ mod test {
             assert_eq!(p_child, orig_child);
         }
         assert_eq!(parsed_db.root, db.root);
-
-        assert_eq!(parsed_db, db);
         Ok(())
     }
 
@@ -563,7 +626,7 @@ mod test {
             },
             outer_iv: Vec::new(),
         };
-        let bytes = serialize_outer_header(&header);
+        let bytes = serialize_outer_header(&header)?;
         let parsed = parse_outer_header(&bytes)?;
         assert_eq!(header, parsed);
         Ok(())
@@ -572,14 +635,18 @@ mod test {
     #[test]
     fn test_write_and_parse_inner_header() -> Result<()> {
         let h = KDBX4InnerHeader {
-            binaries: vec![],
-            body_start: 25,
+            body_start: 40,
             inner_random_stream: InnerCipherSuite::ChaCha20,
             inner_random_stream_key: vec![1, 2, 3, 4, 5, 6],
         };
-        let serialized = serialize_inner_header(&h).unwrap();
-        let parsed = parse_inner_header(&serialized).unwrap();
-        assert_eq!(h, parsed);
+        let binaries = vec![
+            BinaryAttachment::new(vec![1, 2, 3], true),
+            BinaryAttachment::new(vec![], false),
+        ];
+        let mut serialized = Vec::new();
+        write_inner_header(&h, &binaries, &mut serialized)?;
+        let parsed = read_inner_header(&mut &serialized[..]).unwrap();
+        assert_eq!((h, binaries), parsed);
         Ok(())
     }
 }
@@ -3,21 +3,136 @@ pub(crate) mod kdbx3;
 pub(crate) mod kdbx4;
 
 use byteorder::{ByteOrder, LittleEndian};
+use std::io::Read;
 
 use crate::result::{DatabaseIntegrityError, Result};
 
 const KDBX_IDENTIFIER: [u8; 4] = [0x03, 0xd9, 0xa2, 0x9a];
 
+/// Get `len` bytes of `data` starting at `pos`, failing if the data is too short
+pub(crate) fn get_slice(data: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+    pos.checked_add(len)
+        .and_then(|end| data.get(pos..end))
+        .ok_or_else(|| DatabaseIntegrityError::UnexpectedEndOfData.into())
+}
+
+/// Read a little-endian u16 from `data` at `pos`, failing if the data is too short
+pub(crate) fn read_u16(data: &[u8], pos: usize) -> Result<u16> {
+    Ok(LittleEndian::read_u16(get_slice(data, pos, 2)?))
+}
+
+/// Read a little-endian u32 from `data` at `pos`, failing if the data is too short
+pub(crate) fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    Ok(LittleEndian::read_u32(get_slice(data, pos, 4)?))
+}
+
+/// Read a little-endian u64 from `data` at `pos`, failing if the data is too short
+pub(crate) fn read_u64(data: &[u8], pos: usize) -> Result<u64> {
+    Ok(LittleEndian::read_u64(get_slice(data, pos, 8)?))
+}
+
+/// Read exactly `len` bytes from `source`, failing if it ends early
+///
+/// Unlike `read_exact`, the buffer only grows with the data that is actually read, so a bogus
+/// length in a corrupted file does not lead to a huge allocation.
+pub(crate) fn read_bytes<R: Read + ?Sized>(source: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    (&mut *source).take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(DatabaseIntegrityError::UnexpectedEndOfData.into());
+    }
+    Ok(buf)
+}
+
+/// Read the raw bytes of a KDBX outer header from `source`, up to and including the END field
+///
+/// Every header field is a triplet of (entry_type: u8, entry_length, entry_buffer), where the
+/// entry length is stored in `length_size` bytes.
+pub(crate) fn read_kdbx_header<R: Read + ?Sized>(
+    source: &mut R,
+    length_size: usize,
+) -> Result<Vec<u8>> {
+    let mut header = read_bytes(source, 12)?;
+
+    loop {
+        let field = read_bytes(source, 1 + length_size)?;
+        let entry_type = field[0];
+        let entry_length = LittleEndian::read_uint(&field[1..], length_size) as usize;
+
+        header.extend(field);
+        header.extend(read_bytes(source, entry_length)?);
+
+        if entry_type == 0 {
+            return Ok(header);
+        }
+    }
+}
+
 /// Read the KDBX header to get the file version
 pub fn get_kdbx_version(data: &[u8]) -> Result<(u32, u16, u16)> {
     // check identifier
-    if data[0..4] != KDBX_IDENTIFIER {
+    if get_slice(data, 0, 4)? != KDBX_IDENTIFIER {
         return Err(DatabaseIntegrityError::InvalidKDBXIdentifier.into());
     }
 
-    let version = LittleEndian::read_u32(&data[4..8]);
-    let file_minor_version = LittleEndian::read_u16(&data[8..10]);
-    let file_major_version = LittleEndian::read_u16(&data[10..12]);
+    let version = read_u32(data, 4)?;
+    let file_minor_version = read_u16(data, 8)?;
+    let file_major_version = read_u16(data, 10)?;
 
     Ok((version, file_major_version, file_minor_version))
 }
+
+/// Read the start of a KDBX file from `source` to get the file version
+///
+/// The consumed bytes are returned along with the version, so that they can be chained back in
+/// front of the rest of the source.
+pub(crate) fn read_kdbx_version<R: Read + ?Sized>(
+    source: &mut R,
+) -> Result<(Vec<u8>, (u32, u16, u16))> {
+    let mut prefix = Vec::new();
+    (&mut *source).take(12).read_to_end(&mut prefix)?;
+    let version = get_kdbx_version(&prefix)?;
+    Ok((prefix, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parse::*,
+        result::{DatabaseIntegrityError, Error},
+    };
+
+    fn is_end_of_data<T>(res: Result<T>) -> bool {
+        matches!(
+            res,
+            Err(Error::DatabaseIntegrity {
+                e: DatabaseIntegrityError::UnexpectedEndOfData
+            })
+        )
+    }
+
+    #[test]
+    fn test_bounds_checked_reads() -> Result<()> {
+        let data = [1, 0, 0, 0, 0, 0, 0, 0];
+
+        assert_eq!(get_slice(&data, 2, 6)?, &data[2..]);
+        assert_eq!(read_u16(&data, 0)?, 1);
+        assert_eq!(read_u32(&data, 4)?, 0);
+        assert_eq!(read_u64(&data, 0)?, 1);
+
+        assert!(is_end_of_data(get_slice(&data, 2, 7)));
+        assert!(is_end_of_data(get_slice(&data, usize::MAX, 2)));
+        assert!(is_end_of_data(read_u16(&data, 7)));
+        assert!(is_end_of_data(read_u32(&data, 5)));
+        assert!(is_end_of_data(read_u64(&data, 1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_kdbx_version_truncated() {
+        assert!(is_end_of_data(get_kdbx_version(&[])));
+        assert!(is_end_of_data(get_kdbx_version(&[
+            0x03, 0xd9, 0xa2, 0x9a, 0x67, 0xfb, 0x4b, 0xb5, 0x01
+        ])));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::uuid::Uuid;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -38,6 +40,7 @@ pub enum DatabaseIntegrityError {
     InvalidFixedHeader {
         size: usize,
     },
+    UnexpectedEndOfData,
     InvalidOuterHeaderEntry {
         entry_type: u8,
     },
@@ -106,6 +109,18 @@ pub enum DatabaseIntegrityError {
     XMLParsing {
         e: xml::reader::Error,
     },
+    UnexpectedXMLElement {
+        element: String,
+        parent: String,
+    },
+    MissingXMLElement {
+        element: String,
+        parent: String,
+    },
+    InvalidXMLValue {
+        element: String,
+        value: String,
+    },
     Base64 {
         e: base64::DecodeError,
     },
@@ -114,12 +129,34 @@ pub enum DatabaseIntegrityError {
     },
 }
 
+#[derive(Debug)]
+pub enum SerializationError {
+    XMLWriting {
+        e: xml::writer::Error,
+    },
+    InvalidProtectedValue {
+        field_name: String,
+        e: std::str::Utf8Error,
+    },
+    HeaderFieldTooLarge {
+        field_type: u8,
+        size: usize,
+    },
+    UnsupportedDatabaseVersion,
+    MissingKey,
+}
+
 #[derive(Debug)]
 pub enum Error {
     IO { e: std::io::Error },
     DatabaseIntegrity { e: DatabaseIntegrityError },
+    Serialization { e: SerializationError },
     IncorrectKey,
     InvalidKeyFile,
+    InvalidUuid { uuid: String },
+    InvalidColor { color: String },
+    NodeNotFound { uuid: Uuid },
+    InvalidMove { uuid: Uuid, target: Uuid },
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -150,6 +187,8 @@ impl std::fmt::Display for DatabaseIntegrityError {
                 ),
                 DatabaseIntegrityError::InvalidFixedHeader { size } =>
                     format!("Invalid KBD Header (size: {})", size),
+                DatabaseIntegrityError::UnexpectedEndOfData =>
+                    "Encountered an unexpected end of data".to_owned(),
                 DatabaseIntegrityError::InvalidOuterHeaderEntry { entry_type } => format!(
                     "Encountered an invalid outer header entry with type {}",
                     entry_type
@@ -227,6 +266,18 @@ impl std::fmt::Display for DatabaseIntegrityError {
                     "Encountered an error when parsing the inner XML payload: {}",
                     e
                 ),
+                DatabaseIntegrityError::UnexpectedXMLElement { element, parent } => format!(
+                    "Encountered an unexpected element {} when parsing {}",
+                    element, parent
+                ),
+                DatabaseIntegrityError::MissingXMLElement { element, parent } => format!(
+                    "Missing element {} when parsing {}",
+                    element, parent
+                ),
+                DatabaseIntegrityError::InvalidXMLValue { element, value } => format!(
+                    "Encountered an invalid value for element {}: {}",
+                    element, value
+                ),
                 DatabaseIntegrityError::UTF8 { e } => format!(
                     "Encountering an error when parsing an UTF-8 formatted string: {}",
                     e
@@ -240,6 +291,38 @@ impl std::fmt::Display for DatabaseIntegrityError {
     }
 }
 
+#[cfg_attr(tarpaulin, skip)]
+impl std::fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Serialization error: {}",
+            match self {
+                SerializationError::XMLWriting { e } => {
+                    format!(
+                        "Encountered an error when writing the inner XML payload: {}",
+                        e
+                    )
+                }
+                SerializationError::InvalidProtectedValue { field_name, e } => format!(
+                    "Protected value of field {} is not a valid UTF-8 string: {}",
+                    field_name, e
+                ),
+                SerializationError::HeaderFieldTooLarge { field_type, size } => format!(
+                    "Header field with type {} is too large to be written: {} bytes",
+                    field_type, size
+                ),
+                SerializationError::UnsupportedDatabaseVersion => {
+                    "Writing this database version is not supported".to_owned()
+                }
+                SerializationError::MissingKey => {
+                    "No key to encrypt the database with has been set".to_owned()
+                }
+            }
+        )
+    }
+}
+
 impl std::fmt::Display for Error {
     #[cfg_attr(tarpaulin, skip)]
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -250,7 +333,14 @@ impl std::fmt::Display for Error {
                 Error::IO { e } => format!("IO error: {}", e),
                 Error::IncorrectKey => "Incorrect key specified".to_owned(),
                 Error::InvalidKeyFile => "Keyfile format invalid".to_owned(),
+                Error::InvalidUuid { uuid } => format!("Invalid UUID: {}", uuid),
+                Error::InvalidColor { color } => format!("Invalid color: {}", color),
+                Error::NodeNotFound { uuid } => format!("No entry or group with UUID {}", uuid),
+                Error::InvalidMove { uuid, target } => {
+                    format!("Cannot move or copy {} into {}", uuid, target)
+                }
                 Error::DatabaseIntegrity { e } => format!("{}", e),
+                Error::Serialization { e } => format!("{}", e),
             }
         )
     }
@@ -301,12 +391,24 @@ impl std::error::Error for DatabaseIntegrityError {
     }
 }
 
+impl std::error::Error for SerializationError {
+    #[cfg_attr(tarpaulin, skip)]
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SerializationError::XMLWriting { e } => Some(e),
+            SerializationError::InvalidProtectedValue { e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl std::error::Error for Error {
     #[cfg_attr(tarpaulin, skip)]
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::IO { e } => Some(e),
             Error::DatabaseIntegrity { e } => e.source(),
+            Error::Serialization { e } => e.source(),
             _ => None,
         }
     }
@@ -319,6 +421,22 @@ impl From<DatabaseIntegrityError> for Error {
     }
 }
 
+impl From<SerializationError> for Error {
+    #[cfg_attr(tarpaulin, skip)]
+    fn from(e: SerializationError) -> Self {
+        Error::Serialization { e }
+    }
+}
+
+impl From<xml::writer::Error> for Error {
+    #[cfg_attr(tarpaulin, skip)]
+    fn from(e: xml::writer::Error) -> Self {
+        Error::Serialization {
+            e: SerializationError::XMLWriting { e },
+        }
+    }
+}
+
 impl From<CryptoError> for DatabaseIntegrityError {
     #[cfg_attr(tarpaulin, skip)]
     fn from(e: CryptoError) -> Self {
@@ -329,10 +447,28 @@ impl From<CryptoError> for DatabaseIntegrityError {
 impl From<std::io::Error> for Error {
     #[cfg_attr(tarpaulin, skip)]
     fn from(e: std::io::Error) -> Self {
+        // errors raised inside of the streaming readers are passed through std::io::Read
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let kind = e.kind();
+            return match e.into_inner().map(|inner| inner.downcast::<Error>()) {
+                Some(Ok(inner)) => *inner,
+                _ => Error::IO { e: kind.into() },
+            };
+        }
         Error::IO { e }
     }
 }
 
+impl From<Error> for std::io::Error {
+    #[cfg_attr(tarpaulin, skip)]
+    fn from(e: Error) -> Self {
+        match e {
+            Error::IO { e } => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 impl From<argon2::Error> for CryptoError {
     #[cfg_attr(tarpaulin, skip)]
     fn from(e: argon2::Error) -> Self {
@@ -0,0 +1,24 @@
+use crate::result::Result;
+use std::io::Write;
+
+/// A writer that has to be told when the stream ends, so that it can write out trailing data
+///
+/// The layers of the database writing pipeline (compression, encryption, block streams) wrap each
+/// other. Finishing a layer writes out its own trailing data and then finishes the layer below.
+pub(crate) trait FinishWrite: Write {
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl<'a> FinishWrite for Box<dyn FinishWrite + 'a> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        (*self).finish()
+    }
+}
+
+/// Collect the output of a pipeline in memory, for testing
+#[cfg(test)]
+impl FinishWrite for &mut Vec<u8> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use crate::result::{Error, Result};
+use std::convert::TryInto;
+
+/// A UUID identifying a group, an entry or another object of a KeePass database
+///
+/// KeePass stores UUIDs as base64 in the XML document, while KeePassXC displays them as hex, so
+/// both representations can be converted from and to, along with the canonical hyphenated form.
+/// ```
+/// use keepass::Uuid;
+///
+/// let uuid = Uuid::from_base64("d4z/qQkwSwuBNaUoNP0dug==")?;
+/// assert_eq!(uuid.to_hex(), "778cffa909304b0b8135a52834fd1dba");
+/// assert_eq!(uuid.to_string(), "778cffa9-0930-4b0b-8135-a52834fd1dba");
+/// assert_eq!("778cffa9-0930-4b0b-8135-a52834fd1dba".parse::<Uuid>()?, uuid);
+/// # Ok::<(), keepass::Error>(())
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    /// The all-zero UUID, which KeePass uses where no object is referenced
+    pub const fn nil() -> Uuid {
+        Uuid([0; 16])
+    }
+
+    /// Generate a random (version 4) UUID
+    ///
+    /// Fails if the random number generator of the operating system fails.
+    pub fn new_v4() -> Result<Uuid> {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(std::io::Error::from)?;
+
+        // set the version (4) and the variant (RFC 4122)
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(Uuid(bytes))
+    }
+
+    pub const fn from_bytes(bytes: [u8; 16]) -> Uuid {
+        Uuid(bytes)
+    }
+
+    /// Create a UUID from a slice, which has to be exactly 16 bytes long
+    pub fn from_slice(bytes: &[u8]) -> Result<Uuid> {
+        let bytes: [u8; 16] = bytes.try_into().map_err(|_| Error::InvalidUuid {
+            uuid: format!("{:?}", bytes),
+        })?;
+        Ok(Uuid(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+
+    /// Parse a UUID from the base64 form that KeePass stores in its XML document
+    pub fn from_base64(s: &str) -> Result<Uuid> {
+        let invalid = || Error::InvalidUuid { uuid: s.to_owned() };
+        let bytes = base64::decode(s).map_err(|_| invalid())?;
+        bytes.as_slice().try_into().map(Uuid).map_err(|_| invalid())
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+
+    /// Parse a UUID from 32 hex digits, in upper or lower case
+    pub fn from_hex(s: &str) -> Result<Uuid> {
+        let invalid = || Error::InvalidUuid { uuid: s.to_owned() };
+        if s.len() != 32 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Uuid(bytes))
+    }
+
+    /// Format the UUID as 32 lower case hex digits, as KeePassXC displays it
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Format the UUID in the canonical hyphenated form
+impl std::fmt::Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let hex = self.to_hex();
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
+
+impl std::fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Uuid({})", self)
+    }
+}
+
+/// Parse a UUID from the canonical hyphenated form, or from 32 hex digits
+impl std::str::FromStr for Uuid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Uuid> {
+        let is_canonical = s.len() == 36
+            && s.char_indices()
+                .all(|(i, c)| (c == '-') == [8, 13, 18, 23].contains(&i));
+
+        if is_canonical {
+            Uuid::from_hex(&s.replace('-', ""))
+                .map_err(|_| Error::InvalidUuid { uuid: s.to_owned() })
+        } else {
+            Uuid::from_hex(s)
+        }
+    }
+}
+
+impl From<[u8; 16]> for Uuid {
+    fn from(bytes: [u8; 16]) -> Uuid {
+        Uuid(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uuid::*;
+
+    #[test]
+    fn test_conversions() -> Result<()> {
+        let uuid = Uuid::from_bytes([
+            0x77, 0x8c, 0xff, 0xa9, 0x09, 0x30, 0x4b, 0x0b, 0x81, 0x35, 0xa5, 0x28, 0x34, 0xfd,
+            0x1d, 0xba,
+        ]);
+
+        assert_eq!(uuid.to_base64(), "d4z/qQkwSwuBNaUoNP0dug==");
+        assert_eq!(uuid.to_hex(), "778cffa909304b0b8135a52834fd1dba");
+        assert_eq!(uuid.to_string(), "778cffa9-0930-4b0b-8135-a52834fd1dba");
+
+        assert_eq!(Uuid::from_base64("d4z/qQkwSwuBNaUoNP0dug==")?, uuid);
+        assert_eq!(Uuid::from_hex("778CFFA909304B0B8135A52834FD1DBA")?, uuid);
+        assert_eq!(Uuid::from_slice(uuid.as_bytes())?, uuid);
+        assert_eq!("778cffa909304b0b8135a52834fd1dba".parse::<Uuid>()?, uuid);
+        assert_eq!(
+            "778CFFA9-0930-4B0B-8135-A52834FD1DBA".parse::<Uuid>()?,
+            uuid
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_uuids() {
+        for base64 in [
+            "",
+            "d4z/qQkwSwuBNaUoNP0d",
+            "d4z/qQkwSwuBNaUoNP0dug==AA==",
+            "not base64",
+        ] {
+            assert!(matches!(
+                Uuid::from_base64(base64),
+                Err(Error::InvalidUuid { .. })
+            ));
+        }
+
+        for s in [
+            "",
+            "778cffa909304b0b8135a52834fd1db",
+            "778cffa909304b0b8135a52834fd1dbx",
+            "778cffa909304b0b8135a52834fd1dba00",
+            "778cffa9-0930-4b0b-8135a52834fd1dba",
+            "778cffa9-0930-4b0b-8135-a52834fd1dbä",
+            "+78cffa909304b0b8135a52834fd1dba",
+        ] {
+            assert!(matches!(s.parse::<Uuid>(), Err(Error::InvalidUuid { .. })));
+        }
+
+        assert!(matches!(
+            Uuid::from_slice(&[0; 15]),
+            Err(Error::InvalidUuid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_new_v4() -> Result<()> {
+        let uuid = Uuid::new_v4()?;
+        assert_ne!(uuid, Uuid::new_v4()?);
+        assert!(!uuid.is_nil());
+        assert!(Uuid::nil().is_nil());
+        assert_eq!(Uuid::default(), Uuid::nil());
+
+        // version 4, RFC 4122 variant
+        assert_eq!(uuid.as_bytes()[6] >> 4, 4);
+        assert_eq!(uuid.as_bytes()[8] >> 6, 2);
+        assert_eq!(&uuid.to_string()[14..15], "4");
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use crate::result::{DatabaseIntegrityError, Error, Result};
+use crate::{
+    parse,
+    result::{DatabaseIntegrityError, Error, Result},
+};
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 
 use std::collections::HashMap;
@@ -10,7 +13,7 @@ pub(crate) struct VariantDictionary {
 
 impl VariantDictionary {
     pub(crate) fn parse(buffer: &[u8]) -> Result<VariantDictionary> {
-        let version = LittleEndian::read_u16(&buffer[0..2]);
+        let version = parse::read_u16(buffer, 0)?;
 
         if version != 0x100 {
             return Err(DatabaseIntegrityError::InvalidVariantDictionaryVersion { version }.into());
@@ -19,30 +22,30 @@ impl VariantDictionary {
         let mut pos = 2;
         let mut data = HashMap::new();
 
-        while pos < buffer.len() - 9 {
+        while pos + 9 < buffer.len() {
             let value_type = buffer[pos];
             pos += 1;
 
-            let key_length = LittleEndian::read_u32(&buffer[pos..(pos + 4)]) as usize;
+            let key_length = parse::read_u32(buffer, pos)? as usize;
             pos += 4;
 
-            let key = std::str::from_utf8(&buffer[pos..(pos + key_length)])
+            let key = std::str::from_utf8(parse::get_slice(buffer, pos, key_length)?)
                 .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?
                 .to_owned();
             pos += key_length;
 
-            let value_length = LittleEndian::read_u32(&buffer[pos..(pos + 4)]) as usize;
+            let value_length = parse::read_u32(buffer, pos)? as usize;
             pos += 4;
 
-            let value_buffer = &buffer[pos..(pos + value_length)];
+            let value_buffer = parse::get_slice(buffer, pos, value_length)?;
             pos += value_length;
 
             let value = match value_type {
-                0x04 => VariantDictionaryValue::UInt32(LittleEndian::read_u32(value_buffer)),
-                0x05 => VariantDictionaryValue::UInt64(LittleEndian::read_u64(value_buffer)),
+                0x04 => VariantDictionaryValue::UInt32(parse::read_u32(value_buffer, 0)?),
+                0x05 => VariantDictionaryValue::UInt64(parse::read_u64(value_buffer, 0)?),
                 0x08 => VariantDictionaryValue::Bool(value_buffer != [0]),
-                0x0c => VariantDictionaryValue::Int32(LittleEndian::read_i32(value_buffer)),
-                0x0d => VariantDictionaryValue::Int64(LittleEndian::read_i64(value_buffer)),
+                0x0c => VariantDictionaryValue::Int32(parse::read_u32(value_buffer, 0)? as i32),
+                0x0d => VariantDictionaryValue::Int64(parse::read_u64(value_buffer, 0)? as i64),
                 0x18 => VariantDictionaryValue::String(
                     std::str::from_utf8(value_buffer)
                         .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?
@@ -221,3 +224,64 @@ pub(crate) enum VariantDictionaryValue {
     String(String),
     ByteArray(Vec<u8>),
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        result::{DatabaseIntegrityError, Error, Result},
+        variant_dictionary::*,
+    };
+
+    #[test]
+    fn test_serialize_parse() -> Result<()> {
+        let mut data = HashMap::new();
+        data.insert("R".to_string(), VariantDictionaryValue::UInt64(6000));
+        data.insert(
+            "S".to_string(),
+            VariantDictionaryValue::ByteArray(vec![1; 32]),
+        );
+        let buf = VariantDictionary { data }.serialize()?;
+
+        let vd = VariantDictionary::parse(&buf)?;
+        assert_eq!(vd.get::<u64>("R")?, 6000);
+        assert_eq!(vd.get::<Vec<u8>>("S")?, vec![1; 32]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_truncated() -> Result<()> {
+        let mut data = HashMap::new();
+        data.insert(
+            "S".to_string(),
+            VariantDictionaryValue::ByteArray(vec![1; 32]),
+        );
+        let buf = VariantDictionary { data }.serialize()?;
+
+        for len in 0..buf.len() - 1 {
+            match VariantDictionary::parse(&buf[..len]) {
+                Err(Error::DatabaseIntegrity {
+                    e: DatabaseIntegrityError::UnexpectedEndOfData,
+                }) => {}
+                // a dictionary cut right after an entry is still valid
+                Ok(_) if len <= 11 => {}
+                res => panic!("Unexpected result for length {}: {:?}", len, res),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_value_too_short() {
+        // a UInt64 value with only 4 bytes of data
+        let buf = [
+            0x00, 0x01, 0x05, 0x01, 0x00, 0x00, 0x00, b'R', 0x04, 0x00, 0x00, 0x00, 0x01, 0x02,
+            0x03, 0x04, 0x00,
+        ];
+        assert!(matches!(
+            VariantDictionary::parse(&buf),
+            Err(Error::DatabaseIntegrity {
+                e: DatabaseIntegrityError::UnexpectedEndOfData
+            })
+        ));
+    }
+}
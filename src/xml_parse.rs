@@ -1,45 +1,218 @@
 use crate::crypt::ciphers::Cipher;
-use crate::result::{DatabaseIntegrityError, Error, Result};
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::result::{DatabaseIntegrityError, Error, Result, SerializationError};
 use flate2::read::GzDecoder;
-use std::collections::HashMap;
+use indexmap::IndexMap;
+use std::convert::TryInto;
 use std::io::Read;
 use xmltree::{Element, XMLNode};
 
 use secstr::SecStr;
 
-use xml::writer::{EmitterConfig, EventWriter, Result as WResult, XmlEvent as WXmlEvent};
+use xml::attribute::OwnedAttribute;
+use xml::name::OwnedName;
+use xml::reader::{EventReader, XmlEvent};
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent as WXmlEvent};
 
 use std::io::Write;
 
-use super::db::{AutoType, AutoTypeAssociation, Database, Entry, Group, Meta, Node, Value};
+use super::db::{
+    AutoType, AutoTypeAssociation, BinaryAttachment, Color, CustomDataItem, CustomIcon, Database,
+    DeletedObject, Entry, Group, Header, Meta, Node, Times, Value,
+};
+use super::uuid::Uuid;
+
+/// The version of the file format that an XML document is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum XmlVersion {
+    KDBX3,
+    KDBX4,
+    KDBX41,
+}
+
+impl XmlVersion {
+    fn of(db: &Database) -> XmlVersion {
+        match &db.header {
+            Header::KDBX4(h) if h.file_minor_version >= 1 => XmlVersion::KDBX41,
+            Header::KDBX4(_) => XmlVersion::KDBX4,
+            Header::KDB(_) | Header::KDBX3(_) => XmlVersion::KDBX3,
+        }
+    }
+}
 
 pub(crate) trait Serializable {
     fn serialize<W: Write>(
         &self,
         w: &mut EventWriter<W>,
+        version: XmlVersion,
         encryptor: &mut dyn Cipher,
-    ) -> WResult<()>;
+    ) -> Result<()>;
 }
 
-fn write_simple_element<W: Write>(w: &mut EventWriter<W>, tag: &str, value: &str) -> WResult<()> {
+fn write_simple_element<W: Write>(w: &mut EventWriter<W>, tag: &str, value: &str) -> Result<()> {
     w.write(WXmlEvent::start_element(tag))?;
     w.write(WXmlEvent::characters(value))?;
     w.write(WXmlEvent::end_element())?;
     Ok(())
 }
 
+/// Write an element only if there is a value for it
+fn write_optional_element<W: Write, S: AsRef<str>>(
+    w: &mut EventWriter<W>,
+    tag: &str,
+    value: Option<S>,
+) -> Result<()> {
+    match value {
+        Some(v) => write_simple_element(w, tag, v.as_ref()),
+        None => Ok(()),
+    }
+}
+
+/// Write an element that was read by `read_element` back as it was
+fn write_element<W: Write>(w: &mut EventWriter<W>, el: &Element) -> Result<()> {
+    let mut start = WXmlEvent::start_element(el.name.as_str());
+    for (k, v) in &el.attributes {
+        start = start.attr(k.as_str(), v);
+    }
+    w.write(start)?;
+
+    for child in &el.children {
+        match child {
+            XMLNode::Element(c) => write_element(w, c)?,
+            XMLNode::Text(t) => w.write(WXmlEvent::characters(t))?,
+            XMLNode::CData(t) => w.write(WXmlEvent::cdata(t))?,
+            XMLNode::Comment(t) => w.write(WXmlEvent::comment(t))?,
+            XMLNode::ProcessingInstruction(name, data) => {
+                w.write(WXmlEvent::processing_instruction(name, data.as_deref()))?
+            }
+        }
+    }
+
+    w.write(WXmlEvent::end_element())?;
+    Ok(())
+}
+
+fn format_bool(b: bool) -> &'static str {
+    if b {
+        "True"
+    } else {
+        "False"
+    }
+}
+
+/// Format a flag that can be inherited from the parent group, which is written as "null"
+fn format_tristate(b: Option<bool>) -> &'static str {
+    b.map(format_bool).unwrap_or("null")
+}
+
+/// Format a timestamp for the given file version, as an ISO 8601 string for KDBX3 or as Base64
+/// encoded seconds since 0001-01-01 00:00:00 for KDBX4
+fn format_timestamp(t: &chrono::DateTime<chrono::Utc>, version: XmlVersion) -> String {
+    if version >= XmlVersion::KDBX4 {
+        base64::encode((t.timestamp() - keepass_epoch().timestamp()).to_le_bytes())
+    } else {
+        t.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    }
+}
+
+/// Write the custom data of the metadata, a group or an entry
+fn write_custom_data<W: Write>(
+    w: &mut EventWriter<W>,
+    custom_data: &IndexMap<String, CustomDataItem>,
+    version: XmlVersion,
+) -> Result<()> {
+    w.write(WXmlEvent::start_element("CustomData"))?;
+    for (k, item) in custom_data {
+        w.write(WXmlEvent::start_element("Item"))?;
+        write_simple_element(w, "Key", k)?;
+        write_simple_element(w, "Value", &item.value)?;
+        if version >= XmlVersion::KDBX41 {
+            write_optional_element(
+                w,
+                "LastModificationTime",
+                item.last_modification_time
+                    .as_ref()
+                    .map(|t| format_timestamp(t, version)),
+            )?;
+        }
+        w.write(WXmlEvent::end_element())?;
+    }
+    w.write(WXmlEvent::end_element())?;
+    Ok(())
+}
+
+/// Write the times of a group or an entry, in the order that KeePass uses
+fn write_times<W: Write>(w: &mut EventWriter<W>, times: &Times, version: XmlVersion) -> Result<()> {
+    let timestamp = |t: &Option<chrono::DateTime<chrono::Utc>>| {
+        t.as_ref().map(|t| format_timestamp(t, version))
+    };
+
+    w.write(WXmlEvent::start_element("Times"))?;
+    write_optional_element(
+        w,
+        "LastModificationTime",
+        timestamp(&times.last_modification),
+    )?;
+    write_optional_element(w, "CreationTime", timestamp(&times.creation))?;
+    write_optional_element(w, "LastAccessTime", timestamp(&times.last_access))?;
+    write_optional_element(w, "ExpiryTime", timestamp(&times.expiry))?;
+    write_simple_element(w, "Expires", format_bool(times.expires))?;
+    write_simple_element(w, "UsageCount", &times.usage_count.to_string())?;
+    write_optional_element(w, "LocationChanged", timestamp(&times.location_changed))?;
+    w.write(WXmlEvent::end_element())?;
+    Ok(())
+}
+
 impl Serializable for Meta {
     fn serialize<W: Write>(
         &self,
         w: &mut EventWriter<W>,
-        _encryptor: &mut dyn Cipher,
-    ) -> WResult<()> {
+        version: XmlVersion,
+        encryptor: &mut dyn Cipher,
+    ) -> Result<()> {
         w.write(WXmlEvent::start_element("Meta"))?;
 
-        w.write(WXmlEvent::start_element("RecycleBinUUID"))?;
-        w.write(WXmlEvent::characters(&self.recyclebin_uuid))?;
-        w.write(WXmlEvent::end_element())?;
+        let timestamp = |t: &Option<chrono::DateTime<chrono::Utc>>| {
+            t.as_ref().map(|t| format_timestamp(t, version))
+        };
+        let uuid = |u: &Option<Uuid>| u.as_ref().map(Uuid::to_base64);
+
+        write_optional_element(w, "Generator", self.generator.as_ref())?;
+        write_optional_element(w, "SettingsChanged", timestamp(&self.settings_changed))?;
+        write_optional_element(w, "DatabaseName", self.database_name.as_ref())?;
+        write_optional_element(
+            w,
+            "DatabaseNameChanged",
+            timestamp(&self.database_name_changed),
+        )?;
+        write_optional_element(w, "DatabaseDescription", self.database_description.as_ref())?;
+        write_optional_element(
+            w,
+            "DatabaseDescriptionChanged",
+            timestamp(&self.database_description_changed),
+        )?;
+        write_optional_element(w, "DefaultUserName", self.default_username.as_ref())?;
+        write_optional_element(
+            w,
+            "DefaultUserNameChanged",
+            timestamp(&self.default_username_changed),
+        )?;
+        write_optional_element(
+            w,
+            "MaintenanceHistoryDays",
+            self.maintenance_history_days.map(|d| d.to_string()),
+        )?;
+        write_optional_element(w, "Color", self.color.as_ref())?;
+        write_optional_element(w, "MasterKeyChanged", timestamp(&self.master_key_changed))?;
+        write_optional_element(
+            w,
+            "MasterKeyChangeRec",
+            self.master_key_change_rec.map(|d| d.to_string()),
+        )?;
+        write_optional_element(
+            w,
+            "MasterKeyChangeForce",
+            self.master_key_change_force.map(|d| d.to_string()),
+        )?;
 
         w.write(WXmlEvent::start_element("MemoryProtection"))?;
         for (k, v) in &self.memory_protection {
@@ -47,21 +220,79 @@ impl Serializable for Meta {
         }
         w.write(WXmlEvent::end_element())?;
 
-        w.write(WXmlEvent::start_element("CustomData"))?;
-        for (k, v) in &self.custom_data {
-            w.write(WXmlEvent::start_element("Item"))?;
-            write_simple_element(w, "Key", k)?;
-            write_simple_element(w, "Value", v)?;
+        w.write(WXmlEvent::start_element("CustomIcons"))?;
+        for icon in &self.custom_icons {
+            w.write(WXmlEvent::start_element("Icon"))?;
+            write_simple_element(w, "UUID", &icon.uuid.to_base64())?;
+            write_simple_element(w, "Data", &base64::encode(&icon.data))?;
+            if version >= XmlVersion::KDBX41 {
+                write_optional_element(w, "Name", icon.name.as_ref())?;
+                write_optional_element(
+                    w,
+                    "LastModificationTime",
+                    timestamp(&icon.last_modification_time),
+                )?;
+            }
             w.write(WXmlEvent::end_element())?;
         }
         w.write(WXmlEvent::end_element())?;
 
-        for (k, v) in &self.unhandled_fields {
-            w.write(WXmlEvent::start_element(k.as_str()))?;
-            w.write(WXmlEvent::characters(v.as_str()))?;
+        write_optional_element(
+            w,
+            "RecycleBinEnabled",
+            self.recyclebin_enabled.map(format_bool),
+        )?;
+        write_simple_element(w, "RecycleBinUUID", &self.recyclebin_uuid.to_base64())?;
+        write_optional_element(w, "RecycleBinChanged", timestamp(&self.recyclebin_changed))?;
+        write_optional_element(w, "EntryTemplatesGroup", uuid(&self.entry_templates_group))?;
+        write_optional_element(
+            w,
+            "EntryTemplatesGroupChanged",
+            timestamp(&self.entry_templates_group_changed),
+        )?;
+        write_optional_element(w, "LastSelectedGroup", uuid(&self.last_selected_group))?;
+        write_optional_element(w, "LastTopVisibleGroup", uuid(&self.last_top_visible_group))?;
+        write_optional_element(
+            w,
+            "HistoryMaxItems",
+            self.history_max_items.map(|i| i.to_string()),
+        )?;
+        write_optional_element(
+            w,
+            "HistoryMaxSize",
+            self.history_max_size.map(|s| s.to_string()),
+        )?;
+
+        // KDBX4 keeps the binaries in the inner header instead
+        if version == XmlVersion::KDBX3 {
+            w.write(WXmlEvent::start_element("Binaries"))?;
+            for (i, binary) in self.binaries.iter().enumerate() {
+                let id = i.to_string();
+                if binary.is_protected() {
+                    w.write(
+                        WXmlEvent::start_element("Binary")
+                            .attr("ID", &id)
+                            .attr("Protected", "True"),
+                    )?;
+                    if !binary.content.is_empty() {
+                        let encrypted = encryptor.encrypt(&binary.content)?;
+                        w.write(WXmlEvent::characters(&base64::encode(&encrypted)))?;
+                    }
+                } else {
+                    w.write(WXmlEvent::start_element("Binary").attr("ID", &id))?;
+                    w.write(WXmlEvent::characters(&base64::encode(&binary.content)))?;
+                }
+                w.write(WXmlEvent::end_element())?;
+            }
             w.write(WXmlEvent::end_element())?;
         }
 
+        write_custom_data(w, &self.custom_data, version)?;
+
+        for el in &self.unhandled_fields {
+            write_element(w, el)?;
+        }
+
         w.write(WXmlEvent::end_element())?;
         Ok(())
     }
@@ -71,51 +302,78 @@ impl Serializable for Entry {
     fn serialize<W: Write>(
         &self,
         w: &mut EventWriter<W>,
+        version: XmlVersion,
         encryptor: &mut dyn Cipher,
-    ) -> WResult<()> {
+    ) -> Result<()> {
         w.write(WXmlEvent::start_element("Entry"))?;
 
         w.write(WXmlEvent::start_element("UUID"))?;
-        w.write(WXmlEvent::characters(&self.uuid))?;
+        w.write(WXmlEvent::characters(&self.uuid.to_base64()))?;
         w.write(WXmlEvent::end_element())?;
 
-        for (k, v) in &self.unhandled_fields {
-            w.write(WXmlEvent::start_element(k.as_str()))?;
-            w.write(WXmlEvent::characters(v.as_str()))?;
-            w.write(WXmlEvent::end_element())?;
+        write_optional_element(w, "IconID", self.icon_id.map(|i| i.to_string()))?;
+        write_optional_element(
+            w,
+            "CustomIconUUID",
+            self.custom_icon_uuid.as_ref().map(Uuid::to_base64),
+        )?;
+
+        // KeePass writes empty elements for entries without colors or tags
+        let color = |c: &Option<Color>| c.map(|c| c.to_string()).unwrap_or_default();
+        write_simple_element(w, "ForegroundColor", &color(&self.foreground_color))?;
+        write_simple_element(w, "BackgroundColor", &color(&self.background_color))?;
+        write_optional_element(w, "OverrideURL", self.override_url.as_ref())?;
+        if version >= XmlVersion::KDBX41 {
+            write_optional_element(w, "QualityCheck", self.quality_check.map(format_bool))?;
+        }
+        write_simple_element(w, "Tags", &self.tags.join(";"))?;
+        if version >= XmlVersion::KDBX41 {
+            write_optional_element(
+                w,
+                "PreviousParentGroup",
+                self.previous_parent_group.as_ref().map(Uuid::to_base64),
+            )?;
+        }
+        write_times(w, &self.times, version)?;
+
+        for el in &self.unhandled_fields {
+            write_element(w, el)?;
         }
 
         for field_name in self.fields.keys() {
             w.write(WXmlEvent::start_element("String"))?;
             w.write(WXmlEvent::start_element("Key"))?;
-            w.write(WXmlEvent::characters(&field_name))?;
+            w.write(WXmlEvent::characters(field_name))?;
             w.write(WXmlEvent::end_element())?;
             match self.fields.get(field_name) {
-                Some(&Value::Bytes(_)) => {
-                    w.write(WXmlEvent::start_element("Value"))?;
-                    // FIXME: no bytes value
+                Some(Value::Bytes(b)) => {
+                    // XML strings cannot hold arbitrary bytes, so they are written in base64 and
+                    // marked with an attribute that only this crate reads back, see `Value::Bytes`
+                    w.write(WXmlEvent::start_element("Value").attr("Bytes", "True"))?;
+                    w.write(WXmlEvent::characters(&base64::encode(b)))?;
                     w.write(WXmlEvent::end_element())?;
                 }
-                Some(&Value::Protected(ref pv)) => {
+                Some(Value::Protected(pv)) => {
                     w.write(WXmlEvent::start_element("Value").attr("Protected", "True"))?;
 
                     let plain = std::str::from_utf8(pv.unsecure())
-                        .ok()
-                        .unwrap()
-                        .as_bytes()
-                        .to_vec();
+                        .map_err(|e| SerializationError::InvalidProtectedValue {
+                            field_name: field_name.clone(),
+                            e,
+                        })?
+                        .as_bytes();
 
-                    if plain.len() > 0 {
-                        let buf_encrypted = encryptor.encrypt(&plain).unwrap();
+                    if !plain.is_empty() {
+                        let buf_encrypted = encryptor.encrypt(plain)?;
                         let buf_encoded = base64::encode(&buf_encrypted);
 
                         w.write(WXmlEvent::characters(&buf_encoded))?;
                     }
                     w.write(WXmlEvent::end_element())?;
                 }
-                Some(&Value::Unprotected(ref uv)) => {
+                Some(Value::Unprotected(uv)) => {
                     w.write(WXmlEvent::start_element("Value"))?;
-                    w.write(WXmlEvent::characters(&uv))?;
+                    w.write(WXmlEvent::characters(uv))?;
                     w.write(WXmlEvent::end_element())?;
                 }
                 None => {
@@ -127,48 +385,36 @@ impl Serializable for Entry {
             w.write(WXmlEvent::end_element())?;
         }
 
-        if let Some(at) = &self.autotype {
-            w.write(WXmlEvent::start_element("AutoType"))?;
-            write_simple_element(w, "Enabled", if at.enabled { "True" } else { "False" })?;
-            write_simple_element(w, "DataTransferObfuscation", &at.obfuscation)?;
-            if let Some(seq) = &at.sequence {
-                write_simple_element(w, "DefaultSequence", seq)?;
-            }
+        for (name, position) in &self.binary_refs {
+            w.write(WXmlEvent::start_element("Binary"))?;
+            write_simple_element(w, "Key", name)?;
+            w.write(WXmlEvent::start_element("Value").attr("Ref", &position.to_string()))?;
+            w.write(WXmlEvent::end_element())?;
             w.write(WXmlEvent::end_element())?;
         }
 
-        if self.history.len() > 0 {
-            w.write(WXmlEvent::start_element("History"))?;
-            for history_item in &self.history {
-                history_item.serialize(w, encryptor)?;
+        if let Some(at) = &self.autotype {
+            w.write(WXmlEvent::start_element("AutoType"))?;
+            write_simple_element(w, "Enabled", format_bool(at.enabled))?;
+            write_simple_element(w, "DataTransferObfuscation", &at.obfuscation)?;
+            write_optional_element(w, "DefaultSequence", at.sequence.as_ref())?;
+            for association in &at.associations {
+                w.write(WXmlEvent::start_element("Association"))?;
+                write_optional_element(w, "Window", association.window.as_ref())?;
+                write_optional_element(w, "KeystrokeSequence", association.sequence.as_ref())?;
+                w.write(WXmlEvent::end_element())?;
             }
             w.write(WXmlEvent::end_element())?;
         }
 
-        let start =
-            chrono::NaiveDateTime::parse_from_str("0001-01-01T00:00:00", "%Y-%m-%dT%H:%M:%S")
-                .unwrap()
-                .timestamp();
-        w.write(WXmlEvent::start_element("Times"))?;
-        write_simple_element(w, "Expires", if self.expires { "True" } else { "False" })?;
-        write_simple_element(w, "UsageCount", &self.usage_count)?;
-
-        for (key, value) in &self.times {
-            let mut ts_bytes = vec![];
-            ts_bytes.write_i64::<LittleEndian>(value.timestamp() - start)?;
-            w.write(WXmlEvent::start_element(key.as_str()))?;
-            w.write(WXmlEvent::characters(base64::encode(ts_bytes).as_str()))?;
-            w.write(WXmlEvent::end_element())?;
+        if !self.custom_data.is_empty() {
+            write_custom_data(w, &self.custom_data, version)?;
         }
-        w.write(WXmlEvent::end_element())?;
 
-        if !self.custom_data.is_empty() {
-            w.write(WXmlEvent::start_element("CustomData"))?;
-            for (k, v) in &self.custom_data {
-                w.write(WXmlEvent::start_element("Item"))?;
-                write_simple_element(w, "Key", k)?;
-                write_simple_element(w, "Value", v)?;
-                w.write(WXmlEvent::end_element())?;
+        if !self.history.is_empty() {
+            w.write(WXmlEvent::start_element("History"))?;
+            for history_item in &self.history {
+                history_item.serialize(w, version, encryptor)?;
             }
             w.write(WXmlEvent::end_element())?;
         }
@@ -181,44 +427,66 @@ impl Serializable for Group {
     fn serialize<W: Write>(
         &self,
         w: &mut EventWriter<W>,
+        version: XmlVersion,
         encryptor: &mut dyn Cipher,
-    ) -> WResult<()> {
+    ) -> Result<()> {
         w.write(WXmlEvent::start_element("Group"))?;
 
         w.write(WXmlEvent::start_element("UUID"))?;
-        w.write(WXmlEvent::characters(&self.uuid))?;
+        w.write(WXmlEvent::characters(&self.uuid.to_base64()))?;
         w.write(WXmlEvent::end_element())?;
 
         w.write(WXmlEvent::start_element("Name"))?;
         w.write(WXmlEvent::characters(&self.name))?;
         w.write(WXmlEvent::end_element())?;
 
-        for (k, v) in &self.unhandled_fields {
-            w.write(WXmlEvent::start_element(k.as_str()))?;
-            w.write(WXmlEvent::characters(v.as_str()))?;
-            w.write(WXmlEvent::end_element())?;
+        write_optional_element(w, "Notes", self.notes.as_ref())?;
+        write_optional_element(w, "IconID", self.icon_id.map(|i| i.to_string()))?;
+        write_optional_element(
+            w,
+            "CustomIconUUID",
+            self.custom_icon_uuid.as_ref().map(Uuid::to_base64),
+        )?;
+
+        write_times(w, &self.times, version)?;
+
+        write_simple_element(w, "IsExpanded", format_bool(self.is_expanded))?;
+        write_optional_element(
+            w,
+            "DefaultAutoTypeSequence",
+            self.default_autotype_sequence.as_ref(),
+        )?;
+        write_simple_element(w, "EnableAutoType", format_tristate(self.enable_autotype))?;
+        write_simple_element(w, "EnableSearching", format_tristate(self.enable_searching))?;
+        write_optional_element(
+            w,
+            "LastTopVisibleEntry",
+            self.last_top_visible_entry.as_ref().map(Uuid::to_base64),
+        )?;
+
+        if version >= XmlVersion::KDBX41 {
+            if !self.tags.is_empty() {
+                write_simple_element(w, "Tags", &self.tags.join(";"))?;
+            }
+            write_optional_element(
+                w,
+                "PreviousParentGroup",
+                self.previous_parent_group.as_ref().map(Uuid::to_base64),
+            )?;
         }
 
-        let start =
-            chrono::NaiveDateTime::parse_from_str("0001-01-01T00:00:00", "%Y-%m-%dT%H:%M:%S")
-                .unwrap()
-                .timestamp();
-        w.write(WXmlEvent::start_element("Times"))?;
-        write_simple_element(w, "Expires", if self.expires { "True" } else { "False" })?;
-        write_simple_element(w, "UsageCount", &self.usage_count)?;
-        for (key, value) in &self.times {
-            let mut ts_bytes = vec![];
-            ts_bytes.write_i64::<LittleEndian>(value.timestamp() - start)?;
-            w.write(WXmlEvent::start_element(key.as_str()))?;
-            w.write(WXmlEvent::characters(base64::encode(ts_bytes).as_str()))?;
-            w.write(WXmlEvent::end_element())?;
+        if !self.custom_data.is_empty() {
+            write_custom_data(w, &self.custom_data, version)?;
+        }
+
+        for el in &self.unhandled_fields {
+            write_element(w, el)?;
         }
-        w.write(WXmlEvent::end_element())?;
 
         for node in &self.children {
             match node {
-                Node::Group(g) => g.serialize(w, encryptor)?,
-                Node::Entry(e) => e.serialize(w, encryptor)?,
+                Node::Group(g) => g.serialize(w, version, encryptor)?,
+                Node::Entry(e) => e.serialize(w, version, encryptor)?,
             };
         }
 
@@ -231,105 +499,209 @@ impl Serializable for Database {
     fn serialize<W: Write>(
         &self,
         w: &mut EventWriter<W>,
+        version: XmlVersion,
         encryptor: &mut dyn Cipher,
-    ) -> WResult<()> {
+    ) -> Result<()> {
         w.write(WXmlEvent::start_element("KeePassFile"))?;
-        self.meta.serialize(w, encryptor)?;
+        self.meta.serialize(w, version, encryptor)?;
         w.write(WXmlEvent::start_element("Root"))?;
-        self.root.serialize(w, encryptor)?;
+        self.root.serialize(w, version, encryptor)?;
+
+        w.write(WXmlEvent::start_element("DeletedObjects"))?;
+        for deleted in &self.deleted_objects {
+            w.write(WXmlEvent::start_element("DeletedObject"))?;
+            write_simple_element(w, "UUID", &deleted.uuid.to_base64())?;
+            write_simple_element(
+                w,
+                "DeletionTime",
+                &format_timestamp(&deleted.deletion_time, version),
+            )?;
+            w.write(WXmlEvent::end_element())?;
+        }
         w.write(WXmlEvent::end_element())?;
+
+        for el in &self.unhandled_root_fields {
+            write_element(w, el)?;
+        }
+        w.write(WXmlEvent::end_element())?;
+
+        for el in &self.unhandled_fields {
+            write_element(w, el)?;
+        }
         w.write(WXmlEvent::end_element())?;
         Ok(())
     }
 }
 
-fn parse_xml_timestamp(t: &str) -> Result<chrono::NaiveDateTime> {
+/// KDBX4 timestamps are stored as seconds since 0001-01-01 00:00:00
+fn keepass_epoch() -> chrono::NaiveDateTime {
+    chrono::NaiveDate::from_ymd(1, 1, 1).and_hms(0, 0, 0)
+}
+
+fn parse_xml_timestamp(t: &str) -> Option<chrono::NaiveDateTime> {
     match chrono::NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%SZ") {
         // Prior to KDBX4 file format, timestamps were stored as ISO 8601 strings
-        Ok(ndt) => Ok(ndt),
+        Ok(ndt) => Some(ndt),
         // In KDBX4, timestamps are stored as seconds, Base64 encoded, since 0001-01-01 00:00:00
         // So, if we don't have a valid ISO 8601 string, assume we have found a Base64 encoded int.
         _ => {
-            let v = base64::decode(t).map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+            let v = base64::decode(t).ok()?;
             // Cast the Vec created by base64::decode into the array expected by i64::from_le_bytes
-            let mut a: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-            a.copy_from_slice(&v[0..8]);
+            let a: [u8; 8] = v.get(0..8)?.try_into().ok()?;
             let sec = i64::from_le_bytes(a);
-            let ndt =
-                chrono::NaiveDateTime::parse_from_str("0001-01-01T00:00:00", "%Y-%m-%dT%H:%M:%S")
-                    .unwrap()
-                    + chrono::Duration::seconds(sec);
-            Ok(ndt)
+
+            // chrono::Duration::seconds panics when the duration exceeds i64::MAX milliseconds
+            if !(-(i64::MAX / 1000)..=i64::MAX / 1000).contains(&sec) {
+                return None;
+            }
+            keepass_epoch().checked_add_signed(chrono::Duration::seconds(sec))
         }
     }
 }
 
-pub(crate) fn write_xml(d: &Database, encryptor: &mut dyn Cipher) -> WResult<Vec<u8>> {
-    let mut data = Vec::new();
+pub(crate) fn write_xml(
+    writer: &mut dyn Write,
+    d: &Database,
+    encryptor: &mut dyn Cipher,
+) -> Result<()> {
     let mut writer = EmitterConfig::new()
         .perform_indent(true)
-        .create_writer(&mut data);
+        .create_writer(writer);
 
-    d.serialize(&mut writer, encryptor).unwrap();
-    Ok(data)
+    d.serialize(&mut writer, XmlVersion::of(d), encryptor)
 }
 
 fn decompress(in_buffer: &[u8]) -> Result<Vec<u8>> {
     let mut res = Vec::new();
     let mut decoder = GzDecoder::new(in_buffer);
-    decoder.read_to_end(&mut res)?;
+    decoder
+        .read_to_end(&mut res)
+        .map_err(|_| Error::from(DatabaseIntegrityError::Compression))?;
     Ok(res)
 }
 
-fn parse_meta(e: &Element) -> Meta {
+fn parse_meta<R: Read>(reader: &mut EventReader<R>, inner_cipher: &mut dyn Cipher) -> Result<Meta> {
     let mut meta = Meta {
         ..Default::default()
     };
-    for node in &e.children {
-        if let XMLNode::Element(el) = node {
-            match el.name.as_str() {
-                "RecycleBinUUID" => meta.recyclebin_uuid = get_text(el),
-                "CustomData" => meta.custom_data = get_items(el),
-                "MemoryProtection" => meta.memory_protection = get_hashmap(el),
-                "Binaries" => {
-                    for bin_node in &el.children {
-                        if let XMLNode::Element(el) = bin_node {
-                            let compressed = el.attributes.get("Compressed").unwrap() == "True";
-                            let raw_data = base64::decode(get_text(el)).unwrap();
-                            let data;
-                            if compressed {
-                                data = decompress(&raw_data).unwrap();
-                            } else {
-                                data = raw_data;
-                            }
-
-                            meta.binaries.push(data);
-                        }
-                    }
-                }
-                _ => {
-                    println!("Unhandled field {}", el.name);
-                    meta.unhandled_fields.insert(el.name.clone(), get_text(el));
+    for_each_child(reader, |reader, name, attributes| {
+        if name.local_name == "Binaries" {
+            // binaries are read one by one, so that only one of them is kept as XML at a time
+            return for_each_child(reader, |reader, name, attributes| {
+                let el = read_element(reader, name, attributes)?;
+                meta.binaries.push(parse_meta_binary(&el, inner_cipher)?);
+                Ok(())
+            });
+        }
+
+        let el = read_element(reader, name, attributes)?;
+        match el.name.as_str() {
+            "Generator" => meta.generator = Some(get_text(&el)),
+            "SettingsChanged" => meta.settings_changed = Some(get_timestamp(&el)?),
+            "DatabaseName" => meta.database_name = Some(get_text(&el)),
+            "DatabaseNameChanged" => meta.database_name_changed = Some(get_timestamp(&el)?),
+            "DatabaseDescription" => meta.database_description = Some(get_text(&el)),
+            "DatabaseDescriptionChanged" => {
+                meta.database_description_changed = Some(get_timestamp(&el)?)
+            }
+            "DefaultUserName" => meta.default_username = Some(get_text(&el)),
+            "DefaultUserNameChanged" => meta.default_username_changed = Some(get_timestamp(&el)?),
+            "MaintenanceHistoryDays" => meta.maintenance_history_days = Some(get_number(&el)?),
+            "Color" => meta.color = Some(get_text(&el)),
+            "MasterKeyChanged" => meta.master_key_changed = Some(get_timestamp(&el)?),
+            "MasterKeyChangeRec" => meta.master_key_change_rec = Some(get_number(&el)?),
+            "MasterKeyChangeForce" => meta.master_key_change_force = Some(get_number(&el)?),
+            "RecycleBinEnabled" => meta.recyclebin_enabled = Some(get_bool(&el)?),
+            "RecycleBinUUID" => meta.recyclebin_uuid = get_uuid(&el)?,
+            "RecycleBinChanged" => meta.recyclebin_changed = Some(get_timestamp(&el)?),
+            "EntryTemplatesGroup" => meta.entry_templates_group = Some(get_uuid(&el)?),
+            "EntryTemplatesGroupChanged" => {
+                meta.entry_templates_group_changed = Some(get_timestamp(&el)?)
+            }
+            "LastSelectedGroup" => meta.last_selected_group = Some(get_uuid(&el)?),
+            "LastTopVisibleGroup" => meta.last_top_visible_group = Some(get_uuid(&el)?),
+            "HistoryMaxItems" => meta.history_max_items = Some(get_number(&el)?),
+            "HistoryMaxSize" => meta.history_max_size = Some(get_number(&el)?),
+            "CustomData" => meta.custom_data = get_items(&el)?,
+            "MemoryProtection" => meta.memory_protection = get_hashmap(&el),
+            "CustomIcons" => {
+                for icon in el.children.iter().filter_map(XMLNode::as_element) {
+                    meta.custom_icons.push(parse_custom_icon(icon)?);
                 }
             }
+            _ => meta.unhandled_fields.push(el),
+        }
+        Ok(())
+    })?;
+    Ok(meta)
+}
+
+fn parse_custom_icon(e: &Element) -> Result<CustomIcon> {
+    let mut uuid = None;
+    let mut data = None;
+    let mut name = None;
+    let mut last_modification_time = None;
+    for el in e.children.iter().filter_map(XMLNode::as_element) {
+        match el.name.as_str() {
+            "UUID" => uuid = Some(get_uuid(el)?),
+            "Data" => data = Some(get_value(el, |t| base64::decode(t).ok())?),
+            "Name" => name = Some(get_text(el)),
+            "LastModificationTime" => last_modification_time = Some(get_timestamp(el)?),
+            _ => {}
         }
     }
-    meta
+
+    Ok(CustomIcon {
+        uuid: uuid.ok_or_else(|| missing_element("UUID", "Icon"))?,
+        data: data.ok_or_else(|| missing_element("Data", "Icon"))?,
+        name,
+        last_modification_time,
+    })
 }
 
-fn parse_history(e: &Element, inner_cipher: &mut dyn Cipher) -> Vec<Entry> {
+fn parse_meta_binary(e: &Element, inner_cipher: &mut dyn Cipher) -> Result<BinaryAttachment> {
+    let flag = |name| e.attributes.get(name).map(|c| c == "True").unwrap_or(false);
+    let protected = flag("Protected");
+    let mut raw_data =
+        base64::decode(get_text(e)).map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+    if protected {
+        raw_data = inner_cipher.decrypt(&raw_data)?;
+    }
+    if flag("Compressed") {
+        raw_data = decompress(&raw_data)?;
+    }
+    Ok(BinaryAttachment::new(raw_data, protected))
+}
+
+fn unexpected_element(el: &Element, parent: &Element) -> Error {
+    DatabaseIntegrityError::UnexpectedXMLElement {
+        element: el.name.clone(),
+        parent: parent.name.clone(),
+    }
+    .into()
+}
+
+fn missing_element(element: &str, parent: &str) -> Error {
+    DatabaseIntegrityError::MissingXMLElement {
+        element: element.to_owned(),
+        parent: parent.to_owned(),
+    }
+    .into()
+}
+
+fn parse_history(e: &Element, inner_cipher: &mut dyn Cipher) -> Result<Vec<Entry>> {
     let mut res = Vec::new();
     for node in &e.children {
         if let XMLNode::Element(el) = node {
             match el.name.as_str() {
-                "Entry" => res.push(parse_entry(el, inner_cipher)),
-                _ => panic!("Found {} when parsing history", el.name),
+                "Entry" => res.push(parse_entry(el, inner_cipher)?),
+                _ => return Err(unexpected_element(el, e)),
             }
         }
     }
-    res
+    Ok(res)
 }
-fn parse_autotype(e: &Element) -> Option<AutoType> {
+fn parse_autotype(e: &Element) -> Result<Option<AutoType>> {
     let mut at = AutoType {
         ..Default::default()
     };
@@ -346,18 +718,15 @@ fn parse_autotype(e: &Element) -> Option<AutoType> {
                         sequence: a_hm.get("KeystrokeSequence").map(String::to_owned),
                     });
                 }
-                _ => panic!(
-                    "Found unhandled element {} when parsing autotype for {:?}",
-                    el.name, e
-                ),
+                _ => return Err(unexpected_element(el, e)),
             }
         }
     }
-    Some(at)
+    Ok(Some(at))
 }
 
-fn get_hashmap(e: &Element) -> HashMap<String, String> {
-    let mut ret = HashMap::new();
+fn get_hashmap(e: &Element) -> IndexMap<String, String> {
+    let mut ret = IndexMap::new();
     for node in &e.children {
         if let XMLNode::Element(el) = node {
             ret.insert(el.name.clone(), get_text(el));
@@ -365,30 +734,40 @@ fn get_hashmap(e: &Element) -> HashMap<String, String> {
     }
     ret
 }
-fn get_items(e: &Element) -> HashMap<String, String> {
-    let mut ret = HashMap::new();
+fn get_items(e: &Element) -> Result<IndexMap<String, CustomDataItem>> {
+    let mut ret = IndexMap::new();
 
     for node in &e.children {
         // Item
         if let XMLNode::Element(item_el) = node {
             let mut k: Option<String> = None;
             let mut v: Option<String> = None;
+            let mut last_modification_time = None;
             for node in &item_el.children {
                 if let XMLNode::Element(el) = node {
                     match el.name.as_str() {
                         "Key" => k = Some(get_text(el)),
                         "Value" => v = Some(get_text(el)),
-                        _ => panic!("Found el {} when parsing KV pair", el.name),
+                        // KDBX 4.1 stores a modification time for every item
+                        "LastModificationTime" => last_modification_time = Some(get_timestamp(el)?),
+                        _ => return Err(unexpected_element(el, item_el)),
                     }
                 }
             }
-            // blowing up if no key/value are found
-            ret.insert(k.unwrap(), v.unwrap());
+            let k = k.ok_or_else(|| missing_element("Key", &item_el.name))?;
+            let v = v.ok_or_else(|| missing_element("Value", &item_el.name))?;
+            ret.insert(
+                k,
+                CustomDataItem {
+                    value: v,
+                    last_modification_time,
+                },
+            );
         }
     }
-    ret
+    Ok(ret)
 }
-fn get_entry_binary_ref(e: &Element) -> (String, String) {
+fn get_entry_binary_ref(e: &Element) -> Result<(String, usize)> {
     let mut key: Option<String> = None;
     let mut val: Option<&String> = None;
 
@@ -397,16 +776,23 @@ fn get_entry_binary_ref(e: &Element) -> (String, String) {
             match el.name.as_str() {
                 "Key" => key = Some(get_text(el)),
                 "Value" => val = el.attributes.get("Ref"),
-                _ => panic!("Found el {} when parsing KV pair", el.name),
+                _ => return Err(unexpected_element(el, e)),
             }
         }
     }
 
-    // blowing up if no key/value are found
-    (key.unwrap(), val.unwrap().to_owned())
+    let key = key.ok_or_else(|| missing_element("Key", &e.name))?;
+    let val = val.ok_or_else(|| missing_element("Value", &e.name))?;
+    let val = val.parse::<usize>().map_err(|_| {
+        Error::from(DatabaseIntegrityError::InvalidXMLValue {
+            element: "Value".to_owned(),
+            value: val.to_owned(),
+        })
+    })?;
+    Ok((key, val))
 }
 
-fn get_kv_pair(e: &Element, inner_cipher: &mut dyn Cipher) -> (String, Value) {
+fn get_kv_pair(e: &Element, inner_cipher: &mut dyn Cipher) -> Result<(String, Value)> {
     let mut key: Option<String> = None;
     let mut val: Option<Value> = None;
 
@@ -417,145 +803,1126 @@ fn get_kv_pair(e: &Element, inner_cipher: &mut dyn Cipher) -> (String, Value) {
                 "Value" => {
                     if let Some(p) = el.attributes.get("Protected") {
                         if p == "True" {
-                            let enc_bytes = base64::decode(get_text(el)).unwrap();
-                            let dec_bytes = inner_cipher.decrypt(&enc_bytes).unwrap();
+                            let enc_bytes = base64::decode(get_text(el))
+                                .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+                            let dec_bytes = inner_cipher.decrypt(&enc_bytes)?;
                             val = Some(Value::Protected(SecStr::new(dec_bytes)));
                             break;
                         }
                     }
+                    if el.attributes.get("Bytes").is_some_and(|b| b == "True") {
+                        let bytes = base64::decode(get_text(el))
+                            .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))?;
+                        val = Some(Value::Bytes(bytes));
+                        continue;
+                    }
                     val = Some(Value::Unprotected(get_text(el)));
                 }
-                _ => panic!("Found el {} when parsing KV pair", el.name),
+                _ => return Err(unexpected_element(el, e)),
             }
         }
     }
 
-    // blowing up if no key/value are found
-    (key.unwrap(), val.unwrap())
+    let key = key.ok_or_else(|| missing_element("Key", &e.name))?;
+    let val = val.ok_or_else(|| missing_element("Value", &e.name))?;
+    Ok((key, val))
 }
 
-fn parse_entry(e: &Element, inner_cipher: &mut dyn Cipher) -> Entry {
+fn parse_entry(e: &Element, inner_cipher: &mut dyn Cipher) -> Result<Entry> {
     let mut entry = Entry {
         ..Default::default()
     };
     for node in &e.children {
         if let XMLNode::Element(el) = node {
             match el.name.as_str() {
-                "UUID" => entry.uuid = get_text(el),
+                "UUID" => entry.uuid = get_uuid(el)?,
+                "IconID" => entry.icon_id = Some(get_number(el)?),
+                "CustomIconUUID" => entry.custom_icon_uuid = Some(get_uuid(el)?),
+                "ForegroundColor" => entry.foreground_color = get_color(el)?,
+                "BackgroundColor" => entry.background_color = get_color(el)?,
+                "OverrideURL" => entry.override_url = Some(get_text(el)),
+                "Tags" => entry.tags = get_tags(el),
+                "QualityCheck" => entry.quality_check = Some(get_bool(el)?),
+                "PreviousParentGroup" => entry.previous_parent_group = Some(get_uuid(el)?),
                 "Times" => {
-                    let (t, e, u) = parse_times(el);
-                    entry.times = t;
-                    entry.expires = e;
-                    entry.usage_count = u;
+                    entry.times = parse_times(el)?;
                 }
                 "Binary" => {
-                    let (k, r) = get_entry_binary_ref(el);
-                    entry.binary_refs.insert(k, r.parse::<usize>().unwrap());
+                    let (k, r) = get_entry_binary_ref(el)?;
+                    entry.binary_refs.insert(k, r);
                 }
                 "String" => {
-                    let (k, v) = get_kv_pair(el, inner_cipher);
+                    let (k, v) = get_kv_pair(el, inner_cipher)?;
                     entry.fields.insert(k, v);
                 }
-                "AutoType" => entry.autotype = parse_autotype(el),
-                "History" => entry.history = parse_history(el, inner_cipher),
-                "CustomData" => entry.custom_data = get_items(el),
+                "AutoType" => entry.autotype = parse_autotype(el)?,
+                "History" => entry.history = parse_history(el, inner_cipher)?,
+                "CustomData" => entry.custom_data = get_items(el)?,
                 _ => {
-                    entry.unhandled_fields.insert(el.name.clone(), get_text(el));
+                    entry.unhandled_fields.push(el.clone());
                 }
             }
         }
     }
-    entry
+    Ok(entry)
 }
+
+/// Get the text content of an element, ignoring any non-text children
 fn get_text(e: &Element) -> String {
-    let mut _s: String;
-    for node in &e.children {
-        if let XMLNode::Text(s) = node {
-            return s.clone();
+    e.children
+        .iter()
+        .filter_map(|node| match node {
+            XMLNode::Text(s) | XMLNode::CData(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse the text content of an element, failing with an invalid value error if `parse` does
+fn get_value<T>(e: &Element, parse: impl FnOnce(&str) -> Option<T>) -> Result<T> {
+    let text = get_text(e);
+    parse(&text).ok_or_else(|| {
+        DatabaseIntegrityError::InvalidXMLValue {
+            element: e.name.clone(),
+            value: text,
         }
+        .into()
+    })
+}
 
-        panic!(
-            "Found a non-text child item when parsing {:?} - {:?}",
-            node, e
-        );
+/// Get the UUID that an element contains in base64
+fn get_uuid(e: &Element) -> Result<Uuid> {
+    get_value(e, |t| Uuid::from_base64(t).ok())
+}
+
+fn get_bool(e: &Element) -> Result<bool> {
+    get_value(e, |t| match t {
+        "True" | "true" => Some(true),
+        "False" | "false" => Some(false),
+        _ => None,
+    })
+}
+
+/// Get a flag that can be inherited from the parent group, with "null" meaning inherited
+fn get_tristate(e: &Element) -> Result<Option<bool>> {
+    if get_text(e) == "null" {
+        Ok(None)
+    } else {
+        get_bool(e).map(Some)
     }
-    "".to_string()
 }
 
-fn parse_times(e: &Element) -> (HashMap<String, chrono::NaiveDateTime>, bool, String) {
-    let mut times = HashMap::new();
-    let mut expires = false;
-    let mut usage: String = String::from("");
+/// Get an HTML color like `#FF0000`, which is empty if no color is set
+fn get_color(e: &Element) -> Result<Option<Color>> {
+    get_value(e, |t| match t {
+        "" => Some(None),
+        _ => t.parse().ok().map(Some),
+    })
+}
+
+/// Get a list of tags, which KeePass separates with `;` and other clients with `,`
+fn get_tags(e: &Element) -> Vec<String> {
+    get_text(e)
+        .split([';', ','])
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn get_number<T: std::str::FromStr>(e: &Element) -> Result<T> {
+    get_value(e, |t| t.trim().parse().ok())
+}
+
+/// Parse a timestamp, which KeePass always stores in UTC
+fn get_timestamp(e: &Element) -> Result<chrono::DateTime<chrono::Utc>> {
+    get_value(e, |t| {
+        parse_xml_timestamp(t).map(|t| chrono::DateTime::from_utc(t, chrono::Utc))
+    })
+}
+
+fn parse_times(e: &Element) -> Result<Times> {
+    let mut times = Times::default();
+
+    let timestamp = |el| get_timestamp(el).map(Some);
 
-    let time_fields = [
-        "LastModificationTime",
-        "CreationTime",
-        "LastAccessTime",
-        "ExpiryTime",
-        "LocationChanged",
-    ];
     for node in &e.children {
         if let XMLNode::Element(el) = node {
-            if time_fields.contains(&el.name.as_str()) {
-                let ts = parse_xml_timestamp(&get_text(el)).unwrap();
-                times.insert(el.name.clone(), ts);
-            } else if el.name == "Expires" {
-                expires = get_text(el) == "True";
-            } else if el.name == "UsageCount" {
-                usage = get_text(el);
+            match el.name.as_str() {
+                "LastModificationTime" => times.last_modification = timestamp(el)?,
+                "CreationTime" => times.creation = timestamp(el)?,
+                "LastAccessTime" => times.last_access = timestamp(el)?,
+                "ExpiryTime" => times.expiry = timestamp(el)?,
+                "LocationChanged" => times.location_changed = timestamp(el)?,
+                "Expires" => times.expires = get_bool(el)?,
+                "UsageCount" => times.usage_count = get_number(el)?,
+                _ => {}
             }
         }
     }
-    (times, expires, usage)
+    Ok(times)
 }
 
-fn parse_group(e: &Element, inner_cipher: &mut dyn Cipher) -> Group {
+fn parse_group<R: Read>(
+    reader: &mut EventReader<R>,
+    inner_cipher: &mut dyn Cipher,
+) -> Result<Group> {
     let mut group = Group {
         ..Default::default()
     };
 
-    for node in &e.children {
-        if let XMLNode::Element(el) = node {
-            match el.name.as_str() {
-                "UUID" => group.uuid = get_text(el),
-                "Name" => group.name = get_text(el),
-                "Group" => group
-                    .children
-                    .push(Node::Group(parse_group(el, inner_cipher))),
-                "Entry" => group
+    for_each_child(reader, |reader, name, attributes| {
+        match name.local_name.as_str() {
+            "Group" => group
+                .children
+                .push(Node::Group(parse_group(reader, inner_cipher)?)),
+            "Entry" => {
+                let el = read_element(reader, name, attributes)?;
+                group
                     .children
-                    .push(Node::Entry(parse_entry(el, inner_cipher))),
-                "Times" => {
-                    let (t, e, u) = parse_times(el);
-                    group.times = t;
-                    group.expires = e;
-                    group.usage_count = u;
-                }
-                _ => {
-                    group.unhandled_fields.insert(el.name.clone(), get_text(el));
+                    .push(Node::Entry(parse_entry(&el, inner_cipher)?));
+            }
+            _ => {
+                let el = read_element(reader, name, attributes)?;
+                match el.name.as_str() {
+                    "UUID" => group.uuid = get_uuid(&el)?,
+                    "Name" => group.name = get_text(&el),
+                    "Notes" => group.notes = Some(get_text(&el)),
+                    "IconID" => group.icon_id = Some(get_number(&el)?),
+                    "CustomIconUUID" => group.custom_icon_uuid = Some(get_uuid(&el)?),
+                    "IsExpanded" => group.is_expanded = get_bool(&el)?,
+                    "DefaultAutoTypeSequence" => {
+                        group.default_autotype_sequence = Some(get_text(&el))
+                    }
+                    "EnableAutoType" => group.enable_autotype = get_tristate(&el)?,
+                    "EnableSearching" => group.enable_searching = get_tristate(&el)?,
+                    "LastTopVisibleEntry" => group.last_top_visible_entry = Some(get_uuid(&el)?),
+                    "Tags" => group.tags = get_tags(&el),
+                    "PreviousParentGroup" => group.previous_parent_group = Some(get_uuid(&el)?),
+                    "CustomData" => group.custom_data = get_items(&el)?,
+                    "Times" => {
+                        group.times = parse_times(&el)?;
+                    }
+                    _ => {
+                        group.unhandled_fields.push(el);
+                    }
                 }
             }
         }
+        Ok(())
+    })?;
+    Ok(group)
+}
+fn parse_deleted_object(e: &Element) -> Result<DeletedObject> {
+    let mut uuid = None;
+    let mut deletion_time = None;
+    for el in e.children.iter().filter_map(XMLNode::as_element) {
+        match el.name.as_str() {
+            "UUID" => uuid = Some(get_uuid(el)?),
+            "DeletionTime" => deletion_time = Some(get_timestamp(el)?),
+            _ => {}
+        }
     }
-    group
+
+    Ok(DeletedObject {
+        uuid: uuid.ok_or_else(|| missing_element("UUID", "DeletedObject"))?,
+        deletion_time: deletion_time
+            .ok_or_else(|| missing_element("DeletionTime", "DeletedObject"))?,
+    })
+}
+
+/// The contents of a KeePass XML document
+#[derive(Debug)]
+pub(crate) struct XmlDocument {
+    pub root: Group,
+    pub meta: Meta,
+    pub deleted_objects: Vec<DeletedObject>,
+    pub unhandled_root_fields: Vec<Element>,
+    pub unhandled_fields: Vec<Element>,
 }
-fn parse_root(e: &Element, inner_cipher: &mut dyn Cipher) -> Group {
+
+fn parse_root<R: Read>(
+    reader: &mut EventReader<R>,
+    inner_cipher: &mut dyn Cipher,
+) -> Result<(Group, Vec<DeletedObject>, Vec<Element>)> {
     let mut root = Group {
         ..Default::default()
     };
-    for node in &e.children {
-        if let XMLNode::Element(el) = node {
-            match el.name.as_str() {
-                "Group" => root = parse_group(el, inner_cipher),
-                _ => println!("<root> Found unknown element! {}", el.name),
+    let mut deleted_objects = Vec::new();
+    let mut unhandled_fields = Vec::new();
+    for_each_child(reader, |reader, name, attributes| {
+        match name.local_name.as_str() {
+            "Group" => root = parse_group(reader, inner_cipher)?,
+            "DeletedObjects" => {
+                let el = read_element(reader, name, attributes)?;
+                for deleted in el.children.iter().filter_map(XMLNode::as_element) {
+                    deleted_objects.push(parse_deleted_object(deleted)?);
+                }
+            }
+            _ => unhandled_fields.push(read_element(reader, name, attributes)?),
+        }
+        Ok(())
+    })?;
+    Ok((root, deleted_objects, unhandled_fields))
+}
+
+fn next_event<R: Read>(reader: &mut EventReader<R>) -> Result<XmlEvent> {
+    reader
+        .next()
+        .map_err(|e| Error::from(DatabaseIntegrityError::from(e)))
+}
+
+/// Build an element from the XML events up to the end of the element that was just started
+fn read_element<R: Read>(
+    reader: &mut EventReader<R>,
+    name: OwnedName,
+    attributes: Vec<OwnedAttribute>,
+) -> Result<Element> {
+    let mut el = Element::new(&name.local_name);
+    el.prefix = name.prefix;
+    el.namespace = name.namespace;
+    for attr in attributes {
+        el.attributes.insert(attr.name.local_name, attr.value);
+    }
+
+    loop {
+        match next_event(reader)? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                let child = read_element(reader, name, attributes)?;
+                el.children.push(XMLNode::Element(child));
+            }
+            XmlEvent::EndElement { .. } => return Ok(el),
+            XmlEvent::Characters(s) | XmlEvent::CData(s) => el.children.push(XMLNode::Text(s)),
+            XmlEvent::Comment(s) => el.children.push(XMLNode::Comment(s)),
+            XmlEvent::ProcessingInstruction { name, data } => {
+                el.children.push(XMLNode::ProcessingInstruction(name, data))
             }
+            XmlEvent::EndDocument => return Err(DatabaseIntegrityError::UnexpectedEndOfData.into()),
+            XmlEvent::StartDocument { .. } | XmlEvent::Whitespace(_) => {}
+        }
+    }
+}
+
+/// Call `f` for every child element of the element that was just started, up to its end
+///
+/// `f` needs to consume all events of the child element that it is called for.
+fn for_each_child<R, F>(reader: &mut EventReader<R>, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&mut EventReader<R>, OwnedName, Vec<OwnedAttribute>) -> Result<()>,
+{
+    loop {
+        match next_event(reader)? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => f(reader, name, attributes)?,
+            XmlEvent::EndElement { .. } => return Ok(()),
+            XmlEvent::EndDocument => return Err(DatabaseIntegrityError::UnexpectedEndOfData.into()),
+            _ => {}
         }
     }
-    root
 }
-pub(crate) fn parse_xml_block(xml: &[u8], inner_cipher: &mut dyn Cipher) -> Result<(Group, Meta)> {
-    let root_el = Element::parse(xml).unwrap();
-    let meta = parse_meta(root_el.get_child("Meta").unwrap());
-    let root_group = parse_root(root_el.get_child("Root").unwrap(), inner_cipher);
-    Ok((root_group, meta))
+
+/// Reader that keeps the first error of the underlying reader, since the XML parser only reports
+/// a copy of its description
+struct ErrorCapturingReader<R: Read> {
+    inner: R,
+    error: Option<std::io::Error>,
+}
+
+impl<R: Read> Read for ErrorCapturingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            let copy = std::io::Error::new(e.kind(), e.to_string());
+            self.error.get_or_insert(e);
+            copy
+        })
+    }
+}
+
+/// Parse a KeePass XML document from a stream of XML events
+pub(crate) fn parse_xml(xml: &mut dyn Read, inner_cipher: &mut dyn Cipher) -> Result<XmlDocument> {
+    let mut source = ErrorCapturingReader {
+        inner: xml,
+        error: None,
+    };
+    let res = parse_document(&mut EventReader::new(&mut source), inner_cipher);
+
+    // report errors of the underlying reader instead of the XML parser's copy of them
+    match (res, source.error) {
+        (Err(_), Some(e)) => Err(e.into()),
+        (res, _) => res,
+    }
+}
+
+fn parse_document<R: Read>(
+    reader: &mut EventReader<R>,
+    inner_cipher: &mut dyn Cipher,
+) -> Result<XmlDocument> {
+    let document_name = loop {
+        match next_event(reader)? {
+            XmlEvent::StartElement { name, .. } => break name.local_name,
+            XmlEvent::EndDocument => return Err(missing_element("KeePassFile", "document")),
+            _ => {}
+        }
+    };
+
+    let mut meta = None;
+    let mut root = None;
+    let mut unhandled_fields = Vec::new();
+    for_each_child(reader, |reader, name, attributes| {
+        match name.local_name.as_str() {
+            "Meta" if meta.is_none() => meta = Some(parse_meta(reader, inner_cipher)?),
+            "Root" if root.is_none() => root = Some(parse_root(reader, inner_cipher)?),
+            _ => unhandled_fields.push(read_element(reader, name, attributes)?),
+        }
+        Ok(())
+    })?;
+
+    let meta = meta.ok_or_else(|| missing_element("Meta", &document_name))?;
+    let (root, deleted_objects, unhandled_root_fields) =
+        root.ok_or_else(|| missing_element("Root", &document_name))?;
+    Ok(XmlDocument {
+        root,
+        meta,
+        deleted_objects,
+        unhandled_root_fields,
+        unhandled_fields,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{DatabaseConfig, KdfConfig},
+        crypt::ciphers::{Cipher, PlainCipher},
+        db::{Database, Entry, Node, Value},
+        result::{CryptoError, DatabaseIntegrityError, Error, Result, SerializationError},
+        xml_parse::*,
+    };
+
+    /// A cipher that fails on every operation
+    struct FailingCipher;
+
+    impl Cipher for FailingCipher {
+        fn decrypt(&mut self, _ciphertext: &[u8]) -> Result<Vec<u8>> {
+            Err(DatabaseIntegrityError::from(CryptoError::from(block_modes::BlockModeError)).into())
+        }
+        fn encrypt(&mut self, _data: &[u8]) -> Result<Vec<u8>> {
+            Err(DatabaseIntegrityError::from(CryptoError::from(block_modes::BlockModeError)).into())
+        }
+    }
+
+    /// A writer that fails on every write
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_database(password: Value) -> Result<Database> {
+        let mut db = Database::new(DatabaseConfig {
+            kdf: KdfConfig::Aes { rounds: 1 },
+            ..Default::default()
+        })?;
+
+        let mut entry = Entry::default();
+        entry.fields.insert("Password".to_owned(), password);
+        db.root.children.push(Node::Entry(entry));
+        Ok(db)
+    }
+
+    #[test]
+    fn test_write_xml_cipher_failure() -> Result<()> {
+        let db = create_database(Value::Protected("secret".as_bytes().into()))?;
+
+        let res = write_xml(&mut Vec::new(), &db, &mut FailingCipher);
+        assert!(matches!(
+            res,
+            Err(Error::DatabaseIntegrity {
+                e: DatabaseIntegrityError::Crypto { .. }
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_xml_invalid_protected_value() -> Result<()> {
+        let db = create_database(Value::Protected(vec![0xff, 0xfe].into()))?;
+
+        let res = write_xml(&mut Vec::new(), &db, &mut PlainCipher::new(&[])?);
+        match res {
+            Err(Error::Serialization {
+                e: SerializationError::InvalidProtectedValue { field_name, .. },
+            }) => assert_eq!(field_name, "Password"),
+            _ => panic!("Expected an invalid protected value error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_xml_writer_failure() -> Result<()> {
+        let db = create_database(Value::Unprotected("secret".to_owned()))?;
+
+        let mut writer = EmitterConfig::new().create_writer(FailingWriter);
+        let res = db.serialize(&mut writer, XmlVersion::KDBX4, &mut PlainCipher::new(&[])?);
+        assert!(matches!(
+            res,
+            Err(Error::Serialization {
+                e: SerializationError::XMLWriting { .. }
+            })
+        ));
+        Ok(())
+    }
+
+    fn parse_error(xml: &str) -> DatabaseIntegrityError {
+        match parse_xml(&mut xml.as_bytes(), &mut crate::crypt::ciphers::PlainCipher) {
+            Err(Error::DatabaseIntegrity { e }) => e,
+            res => panic!("Expected an integrity error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_parse_malformed_xml() {
+        assert!(matches!(
+            parse_error("<KeePassFile><Meta>"),
+            DatabaseIntegrityError::XMLParsing { .. }
+        ));
+
+        assert!(matches!(
+            parse_error("<KeePassFile><Root/></KeePassFile>"),
+            DatabaseIntegrityError::MissingXMLElement { element, .. } if element == "Meta"
+        ));
+
+        assert!(matches!(
+            parse_error("<KeePassFile><Meta/></KeePassFile>"),
+            DatabaseIntegrityError::MissingXMLElement { element, .. } if element == "Root"
+        ));
+    }
+
+    #[test]
+    fn test_parse_malformed_entries() {
+        let wrap = |entry: &str| {
+            format!(
+                "<KeePassFile><Meta/><Root><Group>{}</Group></Root></KeePassFile>",
+                entry
+            )
+        };
+
+        assert!(matches!(
+            parse_error(&wrap("<Entry><String><Value>x</Value></String></Entry>")),
+            DatabaseIntegrityError::MissingXMLElement { element, parent } if element == "Key" && parent == "String"
+        ));
+
+        assert!(matches!(
+            parse_error(&wrap("<Entry><String><Key>a</Key><Foo/></String></Entry>")),
+            DatabaseIntegrityError::UnexpectedXMLElement { element, .. } if element == "Foo"
+        ));
+
+        assert!(matches!(
+            parse_error(&wrap("<Entry><History><Group/></History></Entry>")),
+            DatabaseIntegrityError::UnexpectedXMLElement { element, parent } if element == "Group" && parent == "History"
+        ));
+
+        assert!(matches!(
+            parse_error(&wrap("<Entry><AutoType><Foo/></AutoType></Entry>")),
+            DatabaseIntegrityError::UnexpectedXMLElement { element, .. } if element == "Foo"
+        ));
+
+        assert!(matches!(
+            parse_error(&wrap(
+                "<Entry><Binary><Key>a</Key><Value Ref=\"x\"/></Binary></Entry>"
+            )),
+            DatabaseIntegrityError::InvalidXMLValue { value, .. } if value == "x"
+        ));
+
+        assert!(matches!(
+            parse_error(&wrap(
+                "<Entry><String><Key>a</Key><Value Protected=\"True\">!!</Value></String></Entry>"
+            )),
+            DatabaseIntegrityError::Base64 { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_malformed_times() {
+        for (element, value) in [("Expires", "maybe"), ("UsageCount", "-1")] {
+            let xml = format!(
+                "<KeePassFile><Meta/><Root><Group><Times><{0}>{1}</{0}></Times></Group></Root></KeePassFile>",
+                element, value
+            );
+            assert!(matches!(
+                parse_error(&xml),
+                DatabaseIntegrityError::InvalidXMLValue { element: e, .. } if e == element
+            ));
+        }
+
+        for timestamp in ["AAAA", "not a time", "////////f38=", "AAAAAAAAAIA="] {
+            let xml = format!(
+                "<KeePassFile><Meta/><Root><Group><Times><CreationTime>{}</CreationTime></Times></Group></Root></KeePassFile>",
+                timestamp
+            );
+            assert!(matches!(
+                parse_error(&xml),
+                DatabaseIntegrityError::InvalidXMLValue { element, .. } if element == "CreationTime"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_times() -> Result<()> {
+        let xml = "<KeePassFile><Meta/><Root><Group><Times><CreationTime>2021-04-10T12:30:00Z</CreationTime><ExpiryTime>SJED2A4AAAA=</ExpiryTime><Expires>True</Expires><UsageCount>3</UsageCount></Times></Group></Root></KeePassFile>";
+        let XmlDocument { root, .. } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+        let creation = chrono::DateTime::from_utc(
+            chrono::NaiveDate::from_ymd(2021, 4, 10).and_hms(12, 30, 0),
+            chrono::Utc,
+        );
+        assert_eq!(
+            root.times,
+            Times {
+                creation: Some(creation),
+                expiry: Some(creation),
+                expires: true,
+                usage_count: 3,
+                ..Default::default()
+            }
+        );
+
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.root.times = root.times;
+        for (version, encoded) in [
+            (XmlVersion::KDBX3, "2021-04-10T12:30:00Z"),
+            (XmlVersion::KDBX4, "SJED2A4AAAA="),
+        ] {
+            let mut xml = Vec::new();
+            db.serialize(
+                &mut EmitterConfig::new().create_writer(&mut xml),
+                version,
+                &mut PlainCipher,
+            )?;
+            let text = String::from_utf8(xml.clone()).unwrap();
+            assert!(text.contains(&format!("<CreationTime>{}</CreationTime>", encoded)));
+            let XmlDocument { root, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+            assert_eq!(root.times, db.root.times);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_uuids() -> Result<()> {
+        let xml = "<KeePassFile><Meta><RecycleBinUUID>AAAAAAAAAAAAAAAAAAAAAA==</RecycleBinUUID></Meta><Root><Group><UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID><Entry><UUID>VjFx/mWYQtyAA/mN3jLocg==</UUID></Entry></Group></Root></KeePassFile>";
+        let XmlDocument { root, meta, .. } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+        assert!(meta.recyclebin_uuid.is_nil());
+        assert_eq!(root.uuid.to_hex(), "778cffa909304b0b8135a52834fd1dba");
+        match &root.children[..] {
+            [Node::Entry(e)] => assert_eq!(e.uuid.to_hex(), "563171fe659842dc8003f98dde32e872"),
+            _ => panic!("Expected an entry"),
+        }
+
+        for uuid in ["", "AAAA", "not base64", "AAAAAAAAAAAAAAAAAAAAAAAA"] {
+            let xml = format!(
+                "<KeePassFile><Meta/><Root><Group><Entry><UUID>{}</UUID></Entry></Group></Root></KeePassFile>",
+                uuid
+            );
+            assert!(matches!(
+                parse_error(&xml),
+                DatabaseIntegrityError::InvalidXMLValue { element, .. } if element == "UUID"
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_malformed_meta() {
+        for (element, value) in [
+            ("HistoryMaxItems", "ten"),
+            ("HistoryMaxSize", ""),
+            ("MaintenanceHistoryDays", "-1"),
+            ("RecycleBinEnabled", "yes"),
+            ("MasterKeyChanged", "yesterday"),
+            ("LastSelectedGroup", "AAAA"),
+        ] {
+            let xml = format!(
+                "<KeePassFile><Meta><{0}>{1}</{0}></Meta><Root/></KeePassFile>",
+                element, value
+            );
+            assert!(matches!(
+                parse_error(&xml),
+                DatabaseIntegrityError::InvalidXMLValue { element: e, .. } if e == element
+            ));
+        }
+    }
+
+    #[test]
+    fn test_group_fields() -> Result<()> {
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.root.notes = Some("Notes".to_owned());
+        db.root.icon_id = Some(3);
+        db.root.custom_icon_uuid = Some(Uuid::new_v4()?);
+        db.root.default_autotype_sequence = Some("{PASSWORD}{ENTER}".to_owned());
+        db.root.enable_autotype = Some(false);
+        db.root.enable_searching = Some(true);
+        db.root.last_top_visible_entry = Some(Uuid::new_v4()?);
+        db.root.custom_data.insert(
+            "key".to_owned(),
+            CustomDataItem {
+                value: "value".to_owned(),
+                last_modification_time: None,
+            },
+        );
+
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument { root, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(root, db.root);
+
+        for (element, value) in [
+            ("IconID", "-1"),
+            ("IsExpanded", "null"),
+            ("EnableSearching", "maybe"),
+            ("CustomIconUUID", ""),
+        ] {
+            let xml = format!(
+                "<KeePassFile><Meta/><Root><Group><{0}>{1}</{0}></Group></Root></KeePassFile>",
+                element, value
+            );
+            assert!(matches!(
+                parse_error(&xml),
+                DatabaseIntegrityError::InvalidXMLValue { element: e, .. } if e == element
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_fields() -> Result<()> {
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        let mut entry = Entry {
+            uuid: Uuid::new_v4()?,
+            icon_id: Some(12),
+            custom_icon_uuid: Some(Uuid::new_v4()?),
+            foreground_color: Some("#FF8000".parse()?),
+            background_color: None,
+            override_url: Some("cmd://firefox {URL}".to_owned()),
+            tags: vec!["work".to_owned(), "mail".to_owned()],
+            ..Default::default()
+        };
+        entry
+            .fields
+            .insert("Title".to_owned(), Value::Unprotected("Entry".to_owned()));
+        db.root.children.push(Node::Entry(entry));
+
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument { root, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(root, db.root);
+
+        let xml = "<KeePassFile><Meta/><Root><Group><Entry><Tags>a; b,,c ;</Tags><ForegroundColor></ForegroundColor><BackgroundColor>#00ff7f</BackgroundColor></Entry></Group></Root></KeePassFile>";
+        let XmlDocument { root, .. } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+        match &root.children[0] {
+            Node::Entry(e) => {
+                assert_eq!(e.tags, vec!["a", "b", "c"]);
+                assert_eq!(e.foreground_color, None);
+                assert_eq!(
+                    e.background_color,
+                    Some(Color {
+                        r: 0,
+                        g: 0xff,
+                        b: 0x7f
+                    })
+                );
+                assert_eq!(e.background_color.unwrap().to_string(), "#00FF7F");
+                assert!(e.unhandled_fields.is_empty());
+            }
+            _ => panic!("Expected an entry"),
+        }
+
+        for (element, value) in [
+            ("IconID", "x"),
+            ("ForegroundColor", "red"),
+            ("BackgroundColor", "#12345"),
+            ("CustomIconUUID", "AAAA"),
+        ] {
+            let xml = format!(
+                "<KeePassFile><Meta/><Root><Group><Entry><{0}>{1}</{0}></Entry></Group></Root></KeePassFile>",
+                element, value
+            );
+            assert!(matches!(
+                parse_error(&xml),
+                DatabaseIntegrityError::InvalidXMLValue { element: e, .. } if e == element
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_values_and_autotype() -> Result<()> {
+        let mut db = create_database(Value::Protected("secret".as_bytes().into()))?;
+        let mut entry = Entry {
+            uuid: Uuid::new_v4()?,
+            autotype: Some(AutoType {
+                enabled: true,
+                sequence: Some("{USERNAME}{TAB}{PASSWORD}{ENTER}".to_owned()),
+                associations: vec![
+                    AutoTypeAssociation {
+                        window: Some("Firefox - *".to_owned()),
+                        sequence: Some("{PASSWORD}{ENTER}".to_owned()),
+                    },
+                    AutoTypeAssociation {
+                        window: Some("Terminal".to_owned()),
+                        sequence: None,
+                    },
+                ],
+                obfuscation: "0".to_owned(),
+            }),
+            ..Default::default()
+        };
+        entry
+            .fields
+            .insert("Title".to_owned(), Value::Unprotected("Entry".to_owned()));
+        entry.fields.insert(
+            "Password".to_owned(),
+            Value::Protected("pw".as_bytes().into()),
+        );
+        entry
+            .fields
+            .insert("Key".to_owned(), Value::Bytes(vec![0x00, 0xff, 0x80]));
+        entry.history.push(Entry {
+            uuid: entry.uuid,
+            autotype: Some(AutoType {
+                associations: vec![AutoTypeAssociation::default()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        db.root.children.push(Node::Entry(entry));
+
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument { root, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(root, db.root);
+
+        // without the attribute, base64 text is an ordinary string like for other clients
+        let xml = "<KeePassFile><Meta/><Root><Group><Entry><String><Key>Key</Key><Value>AP+A</Value></String></Entry></Group></Root></KeePassFile>";
+        let XmlDocument { root, .. } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+        match &root.children[0] {
+            Node::Entry(e) => assert_eq!(e.fields["Key"], Value::Unprotected("AP+A".to_owned())),
+            _ => panic!("Expected an entry"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_deleted_objects() -> Result<()> {
+        let xml = "<KeePassFile><Meta/><Root><Group/><DeletedObjects><DeletedObject><UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID><DeletionTime>2021-04-10T12:30:00Z</DeletionTime></DeletedObject></DeletedObjects></Root></KeePassFile>";
+        let XmlDocument {
+            deleted_objects, ..
+        } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+        assert_eq!(
+            deleted_objects,
+            vec![DeletedObject {
+                uuid: "778cffa9-0930-4b0b-8135-a52834fd1dba".parse()?,
+                deletion_time: chrono::DateTime::from_utc(
+                    chrono::NaiveDate::from_ymd(2021, 4, 10).and_hms(12, 30, 0),
+                    chrono::Utc
+                ),
+            }]
+        );
+
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.deleted_objects = deleted_objects;
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument {
+            deleted_objects, ..
+        } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(deleted_objects, db.deleted_objects);
+
+        let xml = "<KeePassFile><Meta/><Root><DeletedObjects><DeletedObject><UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID></DeletedObject></DeletedObjects></Root></KeePassFile>";
+        assert!(matches!(
+            parse_error(xml),
+            DatabaseIntegrityError::MissingXMLElement { element, .. } if element == "DeletionTime"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_icons() -> Result<()> {
+        let xml = format!(
+            "<KeePassFile><Meta><CustomIcons><Icon><UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID><Data>{}</Data><Name>Icon</Name><LastModificationTime>2021-04-10T12:30:00Z</LastModificationTime></Icon></CustomIcons></Meta><Root/></KeePassFile>",
+            base64::encode("png"),
+        );
+        let XmlDocument { meta, .. } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+        assert_eq!(
+            meta.custom_icons,
+            vec![CustomIcon {
+                uuid: "778cffa9-0930-4b0b-8135-a52834fd1dba".parse()?,
+                data: b"png".to_vec(),
+                name: Some("Icon".to_owned()),
+                last_modification_time: Some(chrono::DateTime::from_utc(
+                    chrono::NaiveDate::from_ymd(2021, 4, 10).and_hms(12, 30, 0),
+                    chrono::Utc
+                )),
+            }]
+        );
+        assert!(meta.unhandled_fields.is_empty());
+
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.meta.custom_icons = meta.custom_icons;
+        db.meta.add_custom_icon(b"another png".to_vec())?;
+        db.set_kdbx4_minor_version(1)?;
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument { meta, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(meta.custom_icons, db.meta.custom_icons);
+
+        let xml = "<KeePassFile><Meta><CustomIcons><Icon><UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID></Icon></CustomIcons></Meta><Root/></KeePassFile>";
+        assert!(matches!(
+            parse_error(xml),
+            DatabaseIntegrityError::MissingXMLElement { element, .. } if element == "Data"
+        ));
+
+        let xml = "<KeePassFile><Meta><CustomIcons><Icon><UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID><Data>not base64</Data></Icon></CustomIcons></Meta><Root/></KeePassFile>";
+        assert!(matches!(
+            parse_error(xml),
+            DatabaseIntegrityError::InvalidXMLValue { element, .. } if element == "Data"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lossless_round_trip() -> Result<()> {
+        let xml = r#"<KeePassFile>
+            <Meta>
+                <Future b="2" a="1"><Nested x="y">text</Nested><!--comment--><Empty/></Future>
+                <CustomData>
+                    <Item><Key>z</Key><Value>1</Value></Item>
+                    <Item><Key>a</Key><Value>2</Value></Item>
+                </CustomData>
+            </Meta>
+            <Root>
+                <Group>
+                    <Future>group</Future>
+                    <Entry>
+                        <String><Key>Title</Key><Value>Entry</Value></String>
+                        <String><Key>Zulu</Key><Value>z</Value></String>
+                        <String><Key>Alpha</Key><Value>a</Value></String>
+                        <String><Key>Mike</Key><Value>m</Value></String>
+                        <Future Protected="True"><Item>entry</Item></Future>
+                    </Entry>
+                </Group>
+                <Future/>
+            </Root>
+            <Extension version="2">document</Extension>
+        </KeePassFile>"#;
+        let XmlDocument {
+            root,
+            meta,
+            unhandled_root_fields,
+            unhandled_fields,
+            ..
+        } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+
+        assert_eq!(unhandled_root_fields.len(), 1);
+        assert_eq!(unhandled_root_fields[0].name, "Future");
+        assert_eq!(unhandled_fields.len(), 1);
+        assert_eq!(unhandled_fields[0].attributes["version"], "2");
+        let future = &meta.unhandled_fields[0];
+        assert_eq!(future.name, "Future");
+        assert_eq!(future.attributes.keys().collect::<Vec<_>>(), vec!["b", "a"]);
+        let nested = future.get_child("Nested").unwrap();
+        assert_eq!(nested.attributes.get("x").map(String::as_str), Some("y"));
+        assert_eq!(nested.get_text().as_deref(), Some("text"));
+        assert!(future.get_child("Empty").is_some());
+        assert_eq!(meta.custom_data.keys().collect::<Vec<_>>(), vec!["z", "a"]);
+
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.meta = meta;
+        db.root = root;
+        db.unhandled_root_fields = unhandled_root_fields;
+        db.unhandled_fields = unhandled_fields;
+
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let document = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(document.unhandled_root_fields, db.unhandled_root_fields);
+        assert_eq!(document.unhandled_fields, db.unhandled_fields);
+        let XmlDocument { root, meta, .. } = document;
+        assert_eq!(meta, db.meta);
+        assert_eq!(root, db.root);
+        assert_eq!(
+            meta.unhandled_fields[0]
+                .attributes
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["b", "a"]
+        );
+        assert_eq!(meta.custom_data.keys().collect::<Vec<_>>(), vec!["z", "a"]);
+        match &root.children[0] {
+            Node::Entry(e) => {
+                assert_eq!(
+                    e.fields.keys().collect::<Vec<_>>(),
+                    vec!["Title", "Zulu", "Alpha", "Mike"]
+                );
+                assert_eq!(e.unhandled_fields[0].attributes["Protected"], "True");
+            }
+            _ => panic!("Expected an entry"),
+        }
+
+        // writing the same database again produces the same document
+        db.meta = meta;
+        db.root = root;
+        let mut rewritten = Vec::new();
+        write_xml(&mut rewritten, &db, &mut PlainCipher)?;
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            String::from_utf8(xml).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_kdbx41_fields() -> Result<()> {
+        let xml = r#"<KeePassFile>
+            <Meta>
+                <CustomIcons>
+                    <Icon>
+                        <UUID>d4z/qQkwSwuBNaUoNP0dug==</UUID>
+                        <Data>cG5n</Data>
+                        <Name>Icon</Name>
+                        <LastModificationTime>2021-04-10T12:30:00Z</LastModificationTime>
+                    </Icon>
+                </CustomIcons>
+                <CustomData>
+                    <Item>
+                        <Key>key</Key>
+                        <Value>value</Value>
+                        <LastModificationTime>2021-04-10T12:30:00Z</LastModificationTime>
+                    </Item>
+                </CustomData>
+            </Meta>
+            <Root>
+                <Group>
+                    <Tags>group;tags</Tags>
+                    <PreviousParentGroup>d4z/qQkwSwuBNaUoNP0dug==</PreviousParentGroup>
+                    <Entry>
+                        <QualityCheck>False</QualityCheck>
+                        <Tags>entry</Tags>
+                        <PreviousParentGroup>d4z/qQkwSwuBNaUoNP0dug==</PreviousParentGroup>
+                    </Entry>
+                </Group>
+            </Root>
+        </KeePassFile>"#;
+        let XmlDocument { root, meta, .. } = parse_xml(&mut xml.as_bytes(), &mut PlainCipher)?;
+
+        let uuid: Uuid = "778cffa9-0930-4b0b-8135-a52834fd1dba".parse()?;
+        let time = chrono::DateTime::from_utc(
+            chrono::NaiveDate::from_ymd(2021, 4, 10).and_hms(12, 30, 0),
+            chrono::Utc,
+        );
+        assert_eq!(meta.custom_icons[0].name.as_deref(), Some("Icon"));
+        assert_eq!(meta.custom_icons[0].last_modification_time, Some(time));
+        assert_eq!(meta.custom_data["key"].last_modification_time, Some(time));
+        assert_eq!(root.tags, vec!["group", "tags"]);
+        assert_eq!(root.previous_parent_group, Some(uuid));
+        match &root.children[0] {
+            Node::Entry(e) => {
+                assert_eq!(e.quality_check, Some(false));
+                assert_eq!(e.previous_parent_group, Some(uuid));
+            }
+            _ => panic!("Expected an entry"),
+        }
+
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.meta = meta;
+        db.root = root;
+
+        db.set_kdbx4_minor_version(1)?;
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument { root, meta, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(meta, db.meta);
+        assert_eq!(root, db.root);
+
+        // KDBX 4.0 does not store any of the additions, except for the tags of entries
+        db.set_kdbx4_minor_version(0)?;
+        let mut xml = Vec::new();
+        write_xml(&mut xml, &db, &mut PlainCipher)?;
+        let XmlDocument { root, meta, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(meta.custom_icons[0].name, None);
+        assert_eq!(meta.custom_icons[0].last_modification_time, None);
+        assert_eq!(meta.custom_data["key"].last_modification_time, None);
+        assert!(root.tags.is_empty());
+        assert_eq!(root.previous_parent_group, None);
+        match &root.children[0] {
+            Node::Entry(e) => {
+                assert_eq!(e.quality_check, None);
+                assert_eq!(e.previous_parent_group, None);
+                assert_eq!(e.tags, vec!["entry"]);
+            }
+            _ => panic!("Expected an entry"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_meta_binaries() -> Result<()> {
+        let xml = format!(
+            "<KeePassFile><Meta><Binaries><Binary ID=\"0\">{}</Binary><Binary ID=\"1\" Compressed=\"True\">{}</Binary></Binaries></Meta><Root/></KeePassFile>",
+            base64::encode("plain"),
+            base64::encode("not gzip"),
+        );
+        assert!(matches!(
+            parse_error(&xml),
+            DatabaseIntegrityError::Compression
+        ));
+
+        let xml = format!(
+            "<KeePassFile><Meta><Binaries><Binary ID=\"0\">{}</Binary></Binaries></Meta><Root/></KeePassFile>",
+            base64::encode("plain"),
+        );
+        let XmlDocument { meta, .. } =
+            parse_xml(&mut xml.as_bytes(), &mut crate::crypt::ciphers::PlainCipher)?;
+        assert_eq!(
+            meta.binaries,
+            vec![BinaryAttachment::new(b"plain".to_vec(), false)]
+        );
+
+        // KDBX3 writes the binaries into the metadata, encrypting protected ones
+        let mut db = create_database(Value::Unprotected("secret".to_owned()))?;
+        db.meta.binaries = vec![
+            BinaryAttachment::new(b"plain".to_vec(), false),
+            BinaryAttachment::new(b"secret".to_vec(), true),
+        ];
+        let mut xml = Vec::new();
+        db.serialize(
+            &mut EmitterConfig::new().create_writer(&mut xml),
+            XmlVersion::KDBX3,
+            &mut PlainCipher,
+        )?;
+        let text = String::from_utf8(xml.clone()).unwrap();
+        assert!(text.contains(&format!(
+            "<Binary ID=\"1\" Protected=\"True\">{}</Binary>",
+            base64::encode("secret")
+        )));
+        let XmlDocument { meta, .. } = parse_xml(&mut xml.as_slice(), &mut PlainCipher)?;
+        assert_eq!(meta.binaries, db.meta.binaries);
+
+        // KDBX4 keeps them in the inner header
+        let mut xml = Vec::new();
+        db.serialize(
+            &mut EmitterConfig::new().create_writer(&mut xml),
+            XmlVersion::KDBX4,
+            &mut PlainCipher,
+        )?;
+        assert!(!String::from_utf8(xml).unwrap().contains("<Binaries>"));
+        Ok(())
+    }
 }
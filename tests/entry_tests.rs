@@ -14,12 +14,13 @@ mod tests {
             assert_eq!(e.get_username(), Some("User Name"));
             assert_eq!(e.get_password(), Some("Password"));
             assert_eq!(e.get("custom attribute"), Some("data for custom attribute"));
-            assert_eq!(e.expires, false);
+            assert!(!e.times.expires);
             let et =
                 chrono::NaiveDateTime::parse_from_str("2016-01-06 09:43:01", "%Y-%m-%d %H:%M:%S")
                     .unwrap();
+            let et = chrono::DateTime::<chrono::Utc>::from_utc(et, chrono::Utc);
             assert_eq!(e.get_expiry_time(), Some(&et));
-            assert_eq!(e.get_time("ExpiryTime"), Some(&et));
+            assert_eq!(e.times.expiry, Some(et));
 
             if let Some(ref at) = e.autotype {
                 if let Some(ref s) = at.sequence {
@@ -38,9 +39,9 @@ mod tests {
             assert_eq!(e.get_title(), Some("test entry"));
             assert_eq!(e.get_username(), Some("jdoe"));
             assert_eq!(e.get_password(), Some("nWuu5AtqsxqNhnYgLwoB"));
-            assert_eq!(e.expires, false);
-            if let Some(t) = e.get_time("ExpiryTime") {
-                assert_eq!(format!("{}", t), "2016-01-28 12:25:36");
+            assert!(!e.times.expires);
+            if let Some(t) = e.get_expiry_time() {
+                assert_eq!(format!("{}", t), "2016-01-28 12:25:36 UTC");
             } else {
                 panic!("Expected an ExpiryTime");
             }
@@ -62,9 +63,9 @@ mod tests {
             assert_eq!(e.get_title(), Some("ASDF"));
             assert_eq!(e.get_username(), Some("ghj"));
             assert_eq!(e.get_password(), Some("klmno"));
-            assert_eq!(e.expires, true);
-            if let Some(t) = e.get_time("ExpiryTime") {
-                assert_eq!(format!("{}", t), "2021-04-10 16:53:18");
+            assert!(e.times.expires);
+            if let Some(t) = e.get_expiry_time() {
+                assert_eq!(format!("{}", t), "2021-04-10 16:53:18 UTC");
             } else {
                 panic!("Expected an ExpiryTime");
             }
@@ -114,4 +115,61 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn edit_entry() -> Result<()> {
+        let mut db = Database::new(DatabaseConfig::default())?;
+        let mut entry = Entry::new()?;
+        assert!(entry.times.last_modification.is_some());
+
+        entry.edit(&db.meta, |e| {
+            e.set_title(&db.meta, "Mail");
+            e.set_username(&db.meta, "jdoe");
+            e.set_password(&db.meta, "hunter2");
+            e.set_field(&db.meta, "PIN", "1234");
+        });
+        assert!(matches!(field(&entry, "Title"), Value::Unprotected(_)));
+        assert!(matches!(field(&entry, "Password"), Value::Protected(_)));
+        assert!(matches!(field(&entry, "PIN"), Value::Unprotected(_)));
+        assert_eq!(entry.history.len(), 1);
+        assert!(entry.history[0].fields.is_empty());
+
+        // custom fields that are protected stay protected
+        entry
+            .fields
+            .insert("PIN".to_owned(), Value::Protected("1234".as_bytes().into()));
+        entry.edit(&db.meta, |e| e.set_field(&db.meta, "PIN", "4321"));
+        assert!(matches!(field(&entry, "PIN"), Value::Protected(_)));
+        assert_eq!(entry.get("PIN"), Some("4321"));
+        assert_eq!(entry.history.len(), 2);
+
+        // edits that do not change anything do not add to the history
+        entry.edit(&db.meta, |e| e.set_title(&db.meta, "Mail"));
+        assert_eq!(entry.history.len(), 2);
+
+        assert!(entry.remove_field("PIN").is_some());
+        assert!(entry.remove_field("PIN").is_none());
+
+        db.meta.history_max_items = Some(3);
+        for i in 0..5 {
+            entry.edit(&db.meta, |e| e.set_password(&db.meta, &i.to_string()));
+        }
+        assert_eq!(entry.history.len(), 3);
+        assert_eq!(entry.history[2].get_password(), Some("3"));
+        assert!(entry.history.iter().all(|h| h.history.is_empty()));
+
+        // the oldest versions are dropped until the history fits, which can be all of them
+        db.meta.history_max_size = Some(100);
+        entry.edit(&db.meta, |e| {
+            e.set_field(&db.meta, "Notes", &"x".repeat(200))
+        });
+        assert_eq!(entry.history.len(), 3);
+        entry.edit(&db.meta, |e| e.set_password(&db.meta, "final"));
+        assert!(entry.history.is_empty());
+        Ok(())
+    }
+
+    fn field<'a>(entry: &'a Entry, key: &str) -> &'a Value {
+        entry.fields.get(key).unwrap()
+    }
 }
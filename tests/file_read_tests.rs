@@ -38,6 +38,47 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn open_kdbx3_meta() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_with_password.kdbx");
+        let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+
+        let meta = &db.meta;
+        assert_eq!(meta.generator.as_deref(), Some("KeePass"));
+        assert_eq!(
+            meta.database_name.as_deref(),
+            Some("Database Name for Demo Database")
+        );
+        assert_eq!(
+            meta.database_description.as_deref(),
+            Some("Database Description for Demo Database")
+        );
+        assert_eq!(
+            meta.database_name_changed,
+            Some(chrono::DateTime::from_utc(
+                chrono::NaiveDate::from_ymd(2016, 1, 13).and_hms(9, 34, 33),
+                chrono::Utc
+            ))
+        );
+        assert_eq!(meta.default_username.as_deref(), Some(""));
+        assert_eq!(meta.color.as_deref(), Some(""));
+        assert_eq!(meta.maintenance_history_days, Some(365));
+        assert_eq!(meta.master_key_change_rec, Some(-1));
+        assert_eq!(meta.master_key_change_force, Some(-1));
+        assert_eq!(meta.recyclebin_enabled, Some(true));
+        assert_eq!(meta.entry_templates_group, Some(Uuid::nil()));
+        assert_eq!(
+            meta.last_selected_group,
+            Some(Uuid::from_base64("3iV4JuSWskC/XVypjBrcGQ==")?)
+        );
+        assert_eq!(meta.history_max_items, Some(10));
+        assert_eq!(meta.history_max_size, Some(6291456));
+        assert_eq!(meta.settings_changed, None);
+        assert!(meta.custom_icons.is_empty());
+        assert!(meta.unhandled_fields.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn open_kdbx3_with_keyfile() -> Result<()> {
         let path = Path::new("tests/resources/test_db_with_keyfile.kdbx");
@@ -142,6 +183,28 @@ mod tests {
         assert_eq!(db.root.name, "Root");
         assert_eq!(db.root.children.len(), 1);
 
+        assert_eq!(db.root.notes.as_deref(), Some(""));
+        assert_eq!(db.root.icon_id, Some(48));
+        assert_eq!(db.root.custom_icon_uuid, None);
+        assert!(db.root.is_expanded);
+        assert_eq!(db.root.default_autotype_sequence.as_deref(), Some(""));
+        assert_eq!(db.root.enable_autotype, None);
+        assert_eq!(db.root.enable_searching, None);
+        assert_eq!(db.root.last_top_visible_entry, Some(Uuid::nil()));
+        assert!(db.root.unhandled_fields.is_empty());
+
+        if let Node::Entry(e) = &db.root.children[0] {
+            assert_eq!(e.icon_id, Some(0));
+            assert_eq!(e.custom_icon_uuid, None);
+            assert_eq!(e.foreground_color, None);
+            assert_eq!(e.background_color, None);
+            assert_eq!(e.override_url.as_deref(), Some(""));
+            assert!(e.tags.is_empty());
+            assert!(e.unhandled_fields.is_empty());
+        } else {
+            panic!("It should be an entry")
+        }
+
         Ok(())
     }
 
@@ -192,6 +255,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn open_with_database_key() -> Result<()> {
+        let kf_path = Path::new("tests/resources/test_key.key");
+        let key = DatabaseKey::new().with_keyfile(&mut File::open(kf_path)?)?;
+
+        // the same key can be used for several databases
+        for path in [
+            "tests/resources/test_db_with_keyfile.kdbx",
+            "tests/resources/test_db_kdbx4_with_keyfile.kdbx",
+        ] {
+            let db = Database::open_with_key(&mut File::open(path)?, &key)?;
+            assert_eq!(db.root.name, "Root");
+        }
+
+        let path = Path::new("tests/resources/test_db_kdbx4_with_keyfile.kdbx");
+        let res = Database::open_with_key(
+            &mut File::open(path)?,
+            &key.clone().with_key_component(b"extra"),
+        );
+        assert!(matches!(res, Err(Error::IncorrectKey)));
+
+        Ok(())
+    }
+
     #[test]
     #[should_panic(expected = r#"InvalidKDBXIdentifier"#)]
     fn open_broken_random_data() {
@@ -228,6 +315,7 @@ mod tests {
                     let user = e.get_username().unwrap();
                     let pass = e.get_password().unwrap();
                     println!("Entry '{0}': '{1}' : '{2}'", title, user, pass);
+                    assert!(!e.uuid.is_nil());
                     total_entries += 1;
                 }
             }
@@ -250,7 +338,14 @@ mod tests {
         println!("{:?} DB Opened", db);
 
         assert_eq!(db.root.name, "Root");
-        assert_eq!(db.meta.recyclebin_uuid, "VjFx/mWYQtyAA/mN3jLocg==");
+        assert_eq!(
+            db.meta.recyclebin_uuid,
+            Uuid::from_base64("VjFx/mWYQtyAA/mN3jLocg==")?
+        );
+        assert_eq!(
+            db.meta.recyclebin_uuid.to_string(),
+            "563171fe-6598-42dc-8003-f98dde32e872"
+        );
 
         let recycle_group: Vec<NodeRef> = db
             .root
@@ -282,7 +377,7 @@ mod tests {
         let mut total_groups = 0;
         let mut total_entries = 0;
         assert_eq!(db.meta.binaries.len(), 1);
-        assert_eq!(db.meta.binaries.get(0).unwrap().len(), 2097152);
+        assert_eq!(db.meta.binaries.first().unwrap().content.len(), 2097152);
         for node in &db.root {
             match node {
                 NodeRef::Group(_) => {
@@ -313,13 +408,8 @@ mod tests {
 
         let mut total_groups = 0;
         let mut total_entries = 0;
-        match &db.inner_header {
-            InnerHeader::None => panic!("Got no inner header"),
-            InnerHeader::KDBX4(h) => {
-                assert_eq!(h.binaries.len(), 1);
-                assert_eq!(h.binaries.get(0).unwrap().content.len(), 2122340);
-            }
-        }
+        assert_eq!(db.meta.binaries.len(), 1);
+        assert_eq!(db.meta.binaries.first().unwrap().content.len(), 2122340);
         for node in &db.root {
             match node {
                 NodeRef::Group(_) => {
@@ -349,4 +439,71 @@ mod tests {
 
         Ok(())
     }
+
+    /// A reader that hands out its data in small, odd-sized pieces
+    struct ShortReader<'a>(&'a [u8]);
+
+    impl std::io::Read for ShortReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(7);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn open_databases_from_short_reads() -> Result<()> {
+        for (path, password) in [
+            ("tests/resources/test_db_with_password.kdbx", "demopass"),
+            (
+                "tests/resources/test_db_kdbx4_with_password_aes.kdbx",
+                "demopass",
+            ),
+            (
+                "tests/resources/test_db_kdbx4_with_password_argon2_chacha20.kdbx",
+                "demopass",
+            ),
+        ] {
+            let data = std::fs::read(path)?;
+            let db = Database::open(&mut ShortReader(&data), Some(password), None)?;
+            let expected = Database::open(&mut &data[..], Some(password), None)?;
+            assert_eq!(db, expected, "{}", path);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_truncated_databases_does_not_panic() -> Result<()> {
+        // (path, password, length of the headers that are parsed before the key derivation)
+        for (path, password, header_length) in [
+            (
+                "tests/resources/test_db_kdb_with_password.kdb",
+                "foobar",
+                124,
+            ),
+            (
+                "tests/resources/test_db_with_password.kdbx",
+                "demopass",
+                222,
+            ),
+            // outer header followed by its SHA256 hash and HMAC
+            (
+                "tests/resources/test_db_kdbx4_with_password_aes.kdbx",
+                "demopass",
+                207 + 64,
+            ),
+        ] {
+            let data = std::fs::read(path)?;
+
+            // every truncation inside the headers, and a few in the payload
+            let lengths =
+                (0..header_length).chain((header_length..data.len()).step_by(data.len() / 4));
+            for len in lengths {
+                let res = Database::open(&mut &data[..len], Some(password), None);
+                assert!(res.is_err(), "{} truncated to {} bytes", path, len);
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,391 @@
+mod tests {
+    use keepass::result::*;
+    use keepass::*;
+
+    fn entry(db: &Database, title: &str) -> Result<Entry> {
+        let mut e = Entry::new()?;
+        e.set_title(&db.meta, title);
+        Ok(e)
+    }
+
+    /// A new database with a "General" group that contains an entry "Mail"
+    fn create_database() -> Result<(Database, Uuid, Uuid)> {
+        let mut db = Database::new(DatabaseConfig::default())?;
+        let mut general = Group::new("General")?;
+        let mail = entry(&db, "Mail")?;
+        let (general_uuid, mail_uuid) = (general.uuid, mail.uuid);
+        general.children.push(Node::Entry(mail));
+        db.root.children.push(Node::Group(general));
+        Ok((db, general_uuid, mail_uuid))
+    }
+
+    #[test]
+    fn move_node() -> Result<()> {
+        let (mut db, general, mail) = create_database()?;
+        let root = db.root.uuid;
+
+        db.move_node(&mail, &root)?;
+        match db.root.get(&["Mail"]) {
+            Some(NodeRef::Entry(e)) => {
+                assert_eq!(e.previous_parent_group, Some(general));
+                assert!(e.times.location_changed.is_some());
+            }
+            _ => panic!("Expected an entry"),
+        }
+        assert!(db.root.get(&["General", "Mail"]).is_none());
+
+        // groups cannot be moved into themselves, their subgroups or entries
+        assert!(matches!(
+            db.move_node(&general, &general),
+            Err(Error::InvalidMove { .. })
+        ));
+        assert!(matches!(
+            db.move_node(&general, &mail),
+            Err(Error::InvalidMove { .. })
+        ));
+        assert!(matches!(
+            db.move_node(&root, &general),
+            Err(Error::NodeNotFound { uuid }) if uuid == root
+        ));
+        let unknown = Uuid::new_v4()?;
+        assert!(matches!(
+            db.move_node(&mail, &unknown),
+            Err(Error::NodeNotFound { uuid }) if uuid == unknown
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn copy_node() -> Result<()> {
+        let (mut db, general, mail) = create_database()?;
+        let root = db.root.uuid;
+
+        let copy = db.copy_node(&mail, &general, true)?;
+        assert_ne!(copy, mail);
+        match db.root.get(&["General", "Mail - Copy"]) {
+            Some(NodeRef::Entry(e)) => assert_eq!(e.uuid, copy),
+            _ => panic!("Expected an entry"),
+        }
+
+        // copies of groups get new UUIDs throughout
+        let group_copy = db.copy_node(&general, &root, false)?;
+        let mut uuids: Vec<Uuid> = db
+            .root
+            .iter()
+            .map(|n| match n {
+                NodeRef::Group(g) => g.uuid,
+                NodeRef::Entry(e) => e.uuid,
+            })
+            .collect();
+        assert_eq!(uuids.len(), 7);
+        uuids.sort();
+        uuids.dedup();
+        assert_eq!(uuids.len(), 7);
+        match db.root.children.last() {
+            Some(Node::Group(g)) => {
+                assert_eq!(g.uuid, group_copy);
+                assert_eq!(g.name, "General");
+                assert_eq!(g.children.len(), 2);
+            }
+            _ => panic!("Expected a group"),
+        }
+
+        // unlike moving, a group can be copied into itself
+        let nested_copy = db.copy_node(&general, &general, true)?;
+        match db.root.get(&["General", "General - Copy", "Mail"]) {
+            Some(NodeRef::Entry(e)) => assert_ne!(e.uuid, mail),
+            _ => panic!("Expected an entry"),
+        }
+        assert!(matches!(
+            db.copy_node(&nested_copy, &mail, false),
+            Err(Error::InvalidMove { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn delete_and_restore_node() -> Result<()> {
+        let (mut db, general, mail) = create_database()?;
+        assert_eq!(db.meta.recyclebin_uuid, Uuid::nil());
+
+        // the recycle bin is created on the first deletion
+        db.delete_node(&mail)?;
+        let bin = db.meta.recyclebin_uuid;
+        match db.root.get(&["Recycle Bin", "Mail"]) {
+            Some(NodeRef::Entry(e)) => assert_eq!(e.previous_parent_group, Some(general)),
+            _ => panic!("Expected an entry in the recycle bin"),
+        }
+        assert!(db.deleted_objects.is_empty());
+
+        db.restore(&mail)?;
+        assert!(db.root.get(&["General", "Mail"]).is_some());
+
+        // nodes whose previous group is gone are restored into the root group
+        db.delete_node(&mail)?;
+        db.delete_node(&general)?;
+        db.restore(&mail)?;
+        assert!(db.root.get(&["Mail"]).is_some());
+
+        db.empty_recycle_bin();
+        match db
+            .root
+            .children
+            .iter()
+            .find(|n| matches!(n, Node::Group(g) if g.uuid == bin))
+        {
+            Some(Node::Group(g)) => assert!(g.children.is_empty()),
+            _ => panic!("Expected the recycle bin"),
+        }
+        let deleted: Vec<Uuid> = db.deleted_objects.iter().map(|d| d.uuid).collect();
+        assert_eq!(deleted, vec![general]);
+
+        // nodes in the recycle bin are deleted permanently
+        db.delete_node(&mail)?;
+        db.delete_node(&mail)?;
+        assert!(db.root.get(&["Recycle Bin", "Mail"]).is_none());
+        assert_eq!(db.deleted_objects.last().map(|d| d.uuid), Some(mail));
+        assert_eq!(db.meta.recyclebin_uuid, bin);
+        assert!(matches!(
+            db.delete_node(&mail),
+            Err(Error::NodeNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn delete_node_without_recycle_bin() -> Result<()> {
+        let (mut db, general, mail) = create_database()?;
+        db.meta.recyclebin_enabled = Some(false);
+
+        db.delete_node(&general)?;
+        assert!(db.root.children.is_empty());
+        let deleted: Vec<Uuid> = db.deleted_objects.iter().map(|d| d.uuid).collect();
+        assert_eq!(deleted, vec![general, mail]);
+        assert_eq!(db.meta.recyclebin_uuid, Uuid::nil());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_node_with_nil_group_uuids() -> Result<()> {
+        let (mut db, _, mail) = create_database()?;
+        // like in KDB databases, where groups have no UUIDs
+        if let Some(Node::Group(g)) = db.root.children.first_mut() {
+            g.uuid = Uuid::nil();
+        }
+
+        db.delete_node(&mail)?;
+        assert!(db.deleted_objects.is_empty());
+        assert!(!db.meta.recyclebin_uuid.is_nil());
+        assert!(db.root.get(&["Recycle Bin", "Mail"]).is_some());
+        assert!(db.root.get(&["General", "Mail"]).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_nested_node() -> Result<()> {
+        let (mut db, general, mail) = create_database()?;
+        let other_mail = entry(&db, "Other Mail")?;
+        let other_uuid = other_mail.uuid;
+        if let Some(NodeRefMut::Group(g)) = db.find_by_uuid_mut(&general) {
+            g.children.push(Node::Entry(other_mail));
+        }
+
+        match db.remove(&["General", "Mail"]) {
+            Some(Node::Entry(e)) => assert_eq!(e.uuid, mail),
+            _ => panic!("Expected an entry"),
+        }
+        assert!(db.root.get(&["General", "Mail"]).is_none());
+        assert!(db.find_by_uuid(&mail).is_none());
+        assert_eq!(db.path_of(&other_uuid), Some(vec!["General", "Other Mail"]));
+        let deleted: Vec<Uuid> = db.deleted_objects.iter().map(|d| d.uuid).collect();
+        assert_eq!(deleted, vec![mail]);
+
+        assert!(db.remove(&["General", "Mail"]).is_none());
+        assert!(db.remove(&["Unknown", "Other Mail"]).is_none());
+        assert_eq!(db.deleted_objects.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn find_by_uuid() -> Result<()> {
+        let (mut db, general, mail) = create_database()?;
+        let root = db.root.uuid;
+
+        // entries with the same title are told apart by their UUIDs
+        let other_mail = entry(&db, "Mail")?;
+        let other_uuid = other_mail.uuid;
+        db.root.children.push(Node::Entry(other_mail));
+
+        assert!(matches!(db.find_by_uuid(&mail), Some(NodeRef::Entry(e)) if e.uuid == mail));
+        assert!(
+            matches!(db.find_by_uuid(&other_uuid), Some(NodeRef::Entry(e)) if e.uuid == other_uuid)
+        );
+        assert!(
+            matches!(db.find_by_uuid(&general), Some(NodeRef::Group(g)) if g.name == "General")
+        );
+        assert!(matches!(db.find_by_uuid(&root), Some(NodeRef::Group(g)) if g.uuid == root));
+        assert!(db.find_by_uuid(&Uuid::new_v4()?).is_none());
+
+        assert_eq!(db.parent_of(&mail).map(|g| g.uuid), Some(general));
+        assert_eq!(db.parent_of(&other_uuid).map(|g| g.uuid), Some(root));
+        assert!(db.parent_of(&root).is_none());
+        assert_eq!(db.path_of(&mail), Some(vec!["General", "Mail"]));
+        assert_eq!(db.path_of(&general), Some(vec!["General"]));
+        assert_eq!(db.path_of(&root), Some(vec![]));
+
+        // lookups follow nodes that are moved or deleted
+        db.move_node(&mail, &root)?;
+        assert_eq!(db.path_of(&mail), Some(vec!["Mail"]));
+        db.delete_node(&general)?;
+        assert_eq!(db.path_of(&general), Some(vec!["Recycle Bin", "General"]));
+        db.delete_node(&general)?;
+        assert!(db.find_by_uuid(&general).is_none());
+
+        // and nodes that are changed without going through the database
+        db.root.children.reverse();
+        if let Some(NodeRefMut::Entry(e)) = db.find_by_uuid_mut(&mail) {
+            e.set_title(&Meta::default(), "Webmail");
+        }
+        assert_eq!(db.path_of(&mail), Some(vec!["Webmail"]));
+        assert_eq!(db.parent_of(&other_uuid).map(|g| g.uuid), Some(root));
+        Ok(())
+    }
+
+    #[test]
+    fn walk() -> Result<()> {
+        let (mut db, _, _) = create_database()?;
+        let mut servers = Group::new("Servers")?;
+        let mut linux = Group::new("Linux")?;
+        linux.children.push(Node::Entry(entry(&db, "Web")?));
+        servers.children.push(Node::Group(linux));
+        servers.children.push(Node::Entry(entry(&db, "Mail")?));
+        db.root.children.push(Node::Group(servers));
+        db.root.children.push(Node::Entry(entry(&db, "Bank")?));
+
+        // groups are visited before their children, unlike with `Group::iter`
+        let visited: Vec<(String, Vec<String>, usize)> = db
+            .root
+            .walk()
+            .map(|item| {
+                let name = match item.node {
+                    NodeRef::Group(g) => g.name.clone(),
+                    NodeRef::Entry(e) => e.get_title().unwrap_or_default().to_string(),
+                };
+                (name, item.path, item.depth)
+            })
+            .collect();
+        let expected = vec![
+            ("Root", vec![], 0),
+            ("General", vec![], 1),
+            ("Mail", vec!["General"], 2),
+            ("Servers", vec![], 1),
+            ("Linux", vec!["Servers"], 2),
+            ("Web", vec!["Servers", "Linux"], 3),
+            ("Mail", vec!["Servers"], 2),
+            ("Bank", vec![], 1),
+        ];
+        let expected: Vec<(String, Vec<String>, usize)> = expected
+            .into_iter()
+            .map(|(name, path, depth)| {
+                let path = path.into_iter().map(str::to_string).collect();
+                (name.to_string(), path, depth)
+            })
+            .collect();
+        assert_eq!(visited, expected);
+
+        let groups: Vec<&str> = db
+            .root
+            .walk()
+            .groups()
+            .map(|i| i.node.name.as_str())
+            .collect();
+        assert_eq!(groups, vec!["Root", "General", "Servers", "Linux"]);
+        for item in db.root.walk().entries() {
+            let mut path: Vec<&str> = item.path.iter().map(String::as_str).collect();
+            path.extend(item.node.get_title());
+            assert!(
+                matches!(db.root.get(&path), Some(NodeRef::Entry(e)) if e.uuid == item.node.uuid)
+            );
+        }
+
+        // change every password below a group
+        for item in db
+            .root
+            .iter_mut()
+            .entries()
+            .filter(|item| item.path.first().is_some_and(|g| g == "Servers"))
+        {
+            item.node.set_password(&db.meta, "rotated");
+        }
+        let rotated: Vec<(Vec<String>, usize)> = db
+            .root
+            .walk()
+            .entries()
+            .filter(|item| item.node.get_password() == Some("rotated"))
+            .map(|item| (item.path, item.depth))
+            .collect();
+        assert_eq!(
+            rotated,
+            vec![
+                (vec!["Servers".to_string(), "Linux".to_string()], 3),
+                (vec!["Servers".to_string()], 2)
+            ]
+        );
+
+        // groups are visited mutably without their children, in the same order as entries
+        let mut visited = Vec::new();
+        for item in db.root.iter_mut() {
+            match item.node {
+                NodeMut::Group(g) => {
+                    visited.push((g.name.clone(), item.depth));
+                    if *g.name == "Linux" {
+                        *g.notes = Some("rotated".to_owned());
+                        *g.is_expanded = true;
+                    }
+                }
+                NodeMut::Entry(e) => {
+                    visited.push((e.get_title().unwrap_or_default().to_string(), item.depth))
+                }
+            }
+        }
+        let names: Vec<(&str, usize)> = visited.iter().map(|(n, d)| (n.as_str(), *d)).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("Root", 0),
+                ("General", 1),
+                ("Mail", 2),
+                ("Servers", 1),
+                ("Linux", 2),
+                ("Web", 3),
+                ("Mail", 2),
+                ("Bank", 1)
+            ]
+        );
+        for item in db.root.iter_mut().groups() {
+            item.node.name.push_str(&format!(" ({})", item.depth));
+        }
+        match db.root.walk().groups().nth(3) {
+            Some(item) => {
+                assert_eq!(item.node.name, "Linux (2)");
+                assert_eq!(item.node.notes.as_deref(), Some("rotated"));
+                assert_eq!(item.path, vec!["Servers (1)"]);
+            }
+            None => panic!("Expected a group"),
+        }
+
+        // the children of groups can be changed while walking
+        let mut depths = Vec::new();
+        db.root.walk_mut(|item| {
+            depths.push(item.depth);
+            if let NodeRefMut::Group(g) = item.node {
+                if g.name == "Linux (2)" {
+                    g.children.clear();
+                }
+            }
+        });
+        assert_eq!(depths, vec![0, 1, 2, 1, 2, 2, 1]);
+        assert!(db.root.get(&["Servers (1)", "Linux (2)", "Web"]).is_none());
+        Ok(())
+    }
+}
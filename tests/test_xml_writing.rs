@@ -1,29 +1,530 @@
 mod tests {
+    use keepass::config::{Compression, InnerCipherSuite, KdfConfig, OuterCipherSuite};
     use keepass::*;
-    use std::io::Write;
     use std::{fs::File, path::Path};
 
+    /// Every save uses a new master seed, IV and KDF salt, so only the contents can be compared
+    fn assert_same_contents(parsed: &Database, db: &Database) {
+        assert_ne!(parsed.header, db.header);
+        assert_eq!(parsed.root, db.root);
+        assert_eq!(parsed.meta, db.meta);
+        assert_eq!(parsed.deleted_objects, db.deleted_objects);
+        assert_eq!(parsed.version, db.version);
+    }
+
     #[test]
     fn dump_kdbx3() -> Result<()> {
-        // not implemented yet
-        //let path = Path::new("tests/resources/test_db_with_password.kdbx");
-        //let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
-        //let res = db.dump(Some("demopass"), None)?;
-        //println!("{}", String::from_utf8(res).unwrap());
+        let path = Path::new("tests/resources/test_db_with_password.kdbx");
+        let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+        let res = db.dump(Some("demopass"), None)?;
+
+        let db_parsed = Database::open(&mut res.as_slice(), Some("demopass"), None)?;
+        assert_eq!(db_parsed.version, DBVersion::KDB3);
+        assert_same_contents(&db_parsed, &db);
+
+        if let Some(NodeRef::Entry(e)) = db_parsed.root.get(&["General", "Subgroup", "test entry"])
+        {
+            assert_eq!(e.get_username(), Some("jdoe"));
+            assert_eq!(e.get_password(), Some("nWuu5AtqsxqNhnYgLwoB"));
+        } else {
+            panic!("Expected an entry");
+        }
         Ok(())
     }
     #[test]
     fn dump_kdbx4() -> Result<()> {
         let path = Path::new("tests/resources/test_db_kdbx4_with_password_aes.kdbx");
-        let new_path = Path::new("kdbx4-dump.bin");
         let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
         let res = db.dump(Some("demopass"), None)?;
-        let mut f = std::fs::File::create(new_path)?;
-        f.write_all(&res)?;
-        f.flush()?;
 
-        let db_parsed = Database::open(&mut File::open(new_path)?, Some("demopass"), None)?;
-        assert_eq!(db_parsed, db);
+        let db_parsed = Database::open(&mut res.as_slice(), Some("demopass"), None)?;
+        assert_same_contents(&db_parsed, &db);
+        Ok(())
+    }
+
+    #[test]
+    fn save_kdbx4() -> Result<()> {
+        for path in [
+            "tests/resources/test_db_kdbx4_with_password_aes.kdbx",
+            "tests/resources/test_db_kdbx4_with_password_argon2_twofish.kdbx",
+            "tests/resources/test_db_kdbx4_with_password_argon2_chacha20.kdbx",
+        ] {
+            let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+
+            let mut res = Vec::new();
+            db.save(&mut res, &DatabaseKey::new().with_password("demopass"))?;
+
+            let db_parsed = Database::open(&mut res.as_slice(), Some("demopass"), None)?;
+            assert_same_contents(&db_parsed, &db);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn save_kdbx4_with_large_attachment() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_kdbx4_with_password_aes_largefile.kdbx");
+        let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+
+        let mut res = Vec::new();
+        db.save(&mut res, &DatabaseKey::new().with_password("demopass"))?;
+
+        // the attachment spans multiple blocks of the HMAC block stream
+        let db_parsed = Database::open(&mut res.as_slice(), Some("demopass"), None)?;
+        assert_ne!(db_parsed.header, db.header);
+        assert_eq!(db_parsed.meta.binaries, db.meta.binaries);
+        assert_eq!(db_parsed.meta, db.meta);
+        Ok(())
+    }
+
+    #[test]
+    fn save_with_keyfile() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_kdbx4_with_keyfile.kdbx");
+        let kf_path = Path::new("tests/resources/test_key.key");
+        let db = Database::open(
+            &mut File::open(path)?,
+            None,
+            Some(&mut File::open(kf_path)?),
+        )?;
+
+        let key = DatabaseKey::new().with_keyfile(&mut File::open(kf_path)?)?;
+        let mut res = Vec::new();
+        db.save(&mut res, &key)?;
+
+        let db_parsed = Database::open(&mut res.as_slice(), None, Some(&mut File::open(kf_path)?))?;
+        assert_same_contents(&db_parsed, &db);
+        Ok(())
+    }
+
+    #[test]
+    fn save_kdb_unsupported() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_kdb_with_password.kdb");
+        let db = Database::open(&mut File::open(path)?, Some("foobar"), None)?;
+        let res = db.save(&mut Vec::new(), &DatabaseKey::new().with_password("foobar"));
+        assert!(matches!(
+            res,
+            Err(Error::Serialization {
+                e: SerializationError::UnsupportedDatabaseVersion
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn save_with_challenge_response() -> Result<()> {
+        let key = DatabaseKey::new()
+            .with_password("demopass")
+            .with_challenge_response(LocalChallengeResponse::new(b"yubikey secret"));
+
+        for path in [
+            "tests/resources/test_db_with_password.kdbx",
+            "tests/resources/test_db_kdbx4_with_password_aes.kdbx",
+        ] {
+            let db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+
+            let mut res = Vec::new();
+            db.save(&mut res, &key)?;
+
+            let db_parsed = Database::open_with_key(&mut res.as_slice(), &key)?;
+            assert_ne!(db_parsed.header, db.header, "{}", path);
+            assert_eq!(db_parsed.meta, db.meta, "{}", path);
+
+            // both the password and the challenge-response secret are needed
+            for wrong_key in [
+                DatabaseKey::new().with_password("demopass"),
+                DatabaseKey::new()
+                    .with_password("demopass")
+                    .with_challenge_response(LocalChallengeResponse::new(b"other secret")),
+            ] {
+                let res = Database::open_with_key(&mut res.as_slice(), &wrong_key);
+                assert!(matches!(res, Err(Error::IncorrectKey)), "{}", path);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn save_uses_new_seeds() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_kdbx4_with_password_argon2_chacha20.kdbx");
+        let key = DatabaseKey::new().with_password("demopass");
+        let db = Database::open_with_key(&mut File::open(path)?, &key)?;
+
+        let mut first = Vec::new();
+        db.save(&mut first, &key)?;
+        let mut second = Vec::new();
+        db.save(&mut second, &key)?;
+
+        let first = Database::open_with_key(&mut first.as_slice(), &key)?;
+        let second = Database::open_with_key(&mut second.as_slice(), &key)?;
+        match (&first.header, &second.header) {
+            (Header::KDBX4(a), Header::KDBX4(b)) => {
+                assert_ne!(a.master_seed, b.master_seed);
+                assert_ne!(a.outer_iv, b.outer_iv);
+                assert_ne!(a.kdf, b.kdf);
+            }
+            _ => panic!("Expected KDBX4 headers"),
+        }
+        assert_eq!(first.root, second.root);
+        Ok(())
+    }
+
+    #[test]
+    fn change_key() -> Result<()> {
+        let old_key = DatabaseKey::new().with_password("demopass");
+        let new_key = DatabaseKey::new()
+            .with_password("rotated")
+            .with_keyfile(&mut File::open("tests/resources/test_key_xml.key")?)?;
+
+        for path in [
+            "tests/resources/test_db_with_password.kdbx",
+            "tests/resources/test_db_kdbx4_with_password_aes.kdbx",
+        ] {
+            let mut db = Database::open_with_key(&mut File::open(path)?, &old_key)?;
+            let old_header = format!("{:?}", db.header);
+
+            db.change_key(new_key.clone())?;
+            assert_ne!(format!("{:?}", db.header), old_header, "{}", path);
+
+            let mut res = Vec::new();
+            db.save_with_current_key(&mut res)?;
+
+            let db_parsed = Database::open_with_key(&mut res.as_slice(), &new_key)?;
+            assert_eq!(db_parsed.meta, db.meta, "{}", path);
+            assert!(
+                matches!(
+                    Database::open_with_key(&mut res.as_slice(), &old_key),
+                    Err(Error::IncorrectKey)
+                ),
+                "{}",
+                path
+            );
+
+            // the reopened database remembers the new key as well
+            let mut resaved = Vec::new();
+            db_parsed.save_with_current_key(&mut resaved)?;
+            Database::open_with_key(&mut resaved.as_slice(), &new_key)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn save_without_key() -> Result<()> {
+        let db = Database::new(DatabaseConfig::default())?;
+        let res = db.save_with_current_key(&mut Vec::new());
+        assert!(matches!(
+            res,
+            Err(Error::Serialization {
+                e: SerializationError::MissingKey
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_records_deleted_objects() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_with_password.kdbx");
+        let mut db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+        let previously_deleted = db.deleted_objects.len();
+
+        assert!(db.remove(&["does not exist"]).is_none());
+        let group = match db.remove(&["Internet"]) {
+            Some(Node::Group(g)) => g,
+            _ => panic!("Expected a group"),
+        };
+        assert!(db.root.get(&["Internet"]).is_none());
+
+        let entry = match &group.children[0] {
+            Node::Entry(e) => e,
+            _ => panic!("Expected an entry"),
+        };
+        let deleted: Vec<Uuid> = db.deleted_objects[previously_deleted..]
+            .iter()
+            .map(|d| d.uuid)
+            .collect();
+        assert_eq!(deleted, vec![group.uuid, entry.uuid]);
+
+        let parsed = Database::open(
+            &mut db.dump(Some("demopass"), None)?.as_slice(),
+            Some("demopass"),
+            None,
+        )?;
+        assert_eq!(parsed.deleted_objects, db.deleted_objects);
+        assert!(parsed.root.get(&["Internet"]).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_nested_records_deleted_objects() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_with_password.kdbx");
+        let mut db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+        let previously_deleted = db.deleted_objects.len();
+
+        let entry = match db.remove(&["General", "Subgroup", "test entry"]) {
+            Some(Node::Entry(e)) => e,
+            _ => panic!("Expected an entry"),
+        };
+        assert!(db
+            .root
+            .get(&["General", "Subgroup", "test entry"])
+            .is_none());
+        assert_eq!(db.deleted_objects.len(), previously_deleted + 1);
+        assert_eq!(db.deleted_objects.last().unwrap().uuid, entry.uuid);
+
+        let group = match db.remove(&["General", "Subgroup"]) {
+            Some(Node::Group(g)) => g,
+            _ => panic!("Expected a group"),
+        };
+        assert!(db.find_by_uuid(&group.uuid).is_none());
+        let removed: Vec<Uuid> = group
+            .iter()
+            .map(|n| match n {
+                NodeRef::Group(g) => g.uuid,
+                NodeRef::Entry(e) => e.uuid,
+            })
+            .collect();
+        let deleted: Vec<Uuid> = db.deleted_objects[previously_deleted + 1..]
+            .iter()
+            .map(|d| d.uuid)
+            .collect();
+        assert_eq!(deleted[0], group.uuid);
+        assert_eq!(deleted.len(), removed.len());
+        assert!(removed.iter().all(|uuid| deleted.contains(uuid)));
+
+        let parsed = Database::open(
+            &mut db.dump(Some("demopass"), None)?.as_slice(),
+            Some("demopass"),
+            None,
+        )?;
+        assert_eq!(parsed.deleted_objects, db.deleted_objects);
+        assert_eq!(parsed.root, db.root);
+        Ok(())
+    }
+
+    #[test]
+    fn custom_icons() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_kdbx4_with_password_aes.kdbx");
+        let mut db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+        assert!(db.meta.custom_icons.is_empty());
+
+        let used = db.meta.add_custom_icon(b"used icon".to_vec())?;
+        let unused = db.meta.add_custom_icon(b"unused icon".to_vec())?;
+        assert_ne!(used, unused);
+        assert_eq!(db.meta.add_custom_icon(b"used icon".to_vec())?, used);
+        assert_eq!(db.meta.custom_icons.len(), 2);
+
+        db.root.custom_icon_uuid = Some(used);
+        if let Node::Entry(e) = &mut db.root.children[0] {
+            e.custom_icon_uuid = Some(used);
+        }
+
+        let parsed = Database::open(
+            &mut db.dump(Some("demopass"), None)?.as_slice(),
+            Some("demopass"),
+            None,
+        )?;
+        assert_same_contents(&parsed, &db);
+        assert_eq!(
+            parsed.root.get_custom_icon(&parsed).map(|i| &i.data[..]),
+            Some(&b"used icon"[..])
+        );
+        if let Node::Entry(e) = &parsed.root.children[0] {
+            assert_eq!(e.get_custom_icon(&parsed).map(|i| i.uuid), Some(used));
+        } else {
+            panic!("Expected an entry");
+        }
+
+        let removed = db.remove_unused_custom_icons();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].uuid, unused);
+        assert!(db.remove_unused_custom_icons().is_empty());
+
+        assert!(db.remove_custom_icon(&unused).is_none());
+        assert_eq!(db.remove_custom_icon(&used).map(|i| i.uuid), Some(used));
+        assert!(db.meta.custom_icons.is_empty());
+        assert_eq!(db.root.custom_icon_uuid, None);
+        assert_eq!(db.root.get_custom_icon(&db), None);
+        if let Node::Entry(e) = &db.root.children[0] {
+            assert_eq!(e.custom_icon_uuid, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn attachments() -> Result<()> {
+        for (path, title) in [
+            ("tests/resources/test_db_with_password.kdbx", "Sample Entry"),
+            (
+                "tests/resources/test_db_kdbx4_with_password_aes.kdbx",
+                "ASDF",
+            ),
+        ] {
+            let mut db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+            let existing = db.meta.binaries.len();
+
+            if let Some(NodeRefMut::Entry(e)) = db.root.get_mut(&[title]) {
+                e.add_attachment(&mut db.meta, "a.txt", b"shared".to_vec(), false);
+                e.add_attachment(&mut db.meta, "b.txt", b"shared".to_vec(), false);
+                e.add_attachment(&mut db.meta, "key", b"shared".to_vec(), true);
+                e.add_attachment(&mut db.meta, "old.txt", b"replaced".to_vec(), false);
+                e.add_attachment(&mut db.meta, "old.txt", b"unused".to_vec(), false);
+                assert!(e.rename_attachment("a.txt", "c.txt"));
+                assert!(!e.rename_attachment("a.txt", "d.txt"));
+                assert!(!e.rename_attachment("c.txt", "b.txt"));
+                assert!(e.remove_attachment("old.txt"));
+                assert!(!e.remove_attachment("old.txt"));
+            } else {
+                panic!("Expected an entry");
+            }
+            // identical content is stored once, unless the protection differs
+            assert_eq!(db.meta.binaries.len(), existing + 4);
+
+            let removed = db.remove_unused_attachments();
+            assert_eq!(
+                removed,
+                vec![
+                    BinaryAttachment::new(b"replaced".to_vec(), false),
+                    BinaryAttachment::new(b"unused".to_vec(), false),
+                ]
+            );
+            assert_eq!(db.meta.binaries.len(), existing + 2);
+
+            let parsed = Database::open(
+                &mut db.dump(Some("demopass"), None)?.as_slice(),
+                Some("demopass"),
+                None,
+            )?;
+            assert_eq!(parsed.meta.binaries, db.meta.binaries);
+            if let Some(NodeRef::Entry(e)) = parsed.root.get(&[title]) {
+                let attachments: Vec<_> = e
+                    .attachments(&parsed.meta)
+                    .map(|(name, b)| (name, &b.content[..], b.is_protected()))
+                    .collect();
+                assert_eq!(
+                    attachments,
+                    vec![
+                        ("c.txt", &b"shared"[..], false),
+                        ("b.txt", &b"shared"[..], false),
+                        ("key", &b"shared"[..], true),
+                    ]
+                );
+                assert_eq!(e.get_binary("key", &parsed), Some(&b"shared"[..]));
+            } else {
+                panic!("Expected an entry");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_kdbx4_minor_version() -> Result<()> {
+        let minor_version = |db: &Database| match &db.header {
+            Header::KDBX4(h) => h.file_minor_version,
+            _ => panic!("Expected a KDBX4 header"),
+        };
+
+        let path = Path::new("tests/resources/test_db_kdbx4_with_password_aes.kdbx");
+        let mut db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+        assert_eq!(minor_version(&db), 0);
+
+        db.set_kdbx4_minor_version(1)?;
+        db.root.tags = vec!["tag".to_owned()];
+        let parsed = Database::open(
+            &mut db.dump(Some("demopass"), None)?.as_slice(),
+            Some("demopass"),
+            None,
+        )?;
+        assert_eq!(minor_version(&parsed), 1);
+        assert_eq!(parsed.root, db.root);
+
+        // databases are saved with the minor version they were opened with
+        let reparsed = Database::open(
+            &mut parsed.dump(Some("demopass"), None)?.as_slice(),
+            Some("demopass"),
+            None,
+        )?;
+        assert_eq!(minor_version(&reparsed), 1);
+
+        assert!(matches!(
+            db.set_kdbx4_minor_version(2),
+            Err(Error::Serialization {
+                e: SerializationError::UnsupportedDatabaseVersion
+            })
+        ));
+
+        let path = Path::new("tests/resources/test_db_with_password.kdbx");
+        let mut db = Database::open(&mut File::open(path)?, Some("demopass"), None)?;
+        assert!(db.set_kdbx4_minor_version(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dump_kdb_unsupported() -> Result<()> {
+        let path = Path::new("tests/resources/test_db_kdb_with_password.kdb");
+        let db = Database::open(&mut File::open(path)?, Some("foobar"), None)?;
+        let res = db.dump(Some("foobar"), None);
+        assert!(matches!(
+            res,
+            Err(Error::Serialization {
+                e: SerializationError::UnsupportedDatabaseVersion
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn create_new_database() -> Result<()> {
+        let config = DatabaseConfig {
+            outer_cipher: OuterCipherSuite::ChaCha20,
+            compression: Compression::GZip,
+            inner_cipher: InnerCipherSuite::ChaCha20,
+            kdf: KdfConfig::Aes { rounds: 100 },
+        };
+        let mut db = Database::new(config)?;
+        db.root.name = "Service vault".to_owned();
+
+        let mut entry = Entry::default();
+        entry.fields.insert(
+            "Title".to_owned(),
+            Value::Unprotected("Service account".to_owned()),
+        );
+        entry.fields.insert(
+            "Password".to_owned(),
+            Value::Protected("s3cr3t".as_bytes().into()),
+        );
+        db.root.children.push(Node::Entry(entry));
+
+        let res = db.dump(Some("provisioned"), None)?;
+        let db_parsed = Database::open(&mut res.as_slice(), Some("provisioned"), None)?;
+        assert_eq!(db_parsed.root, db.root);
+        assert_eq!(db_parsed.meta, db.meta);
+
+        if let Some(NodeRef::Entry(e)) = db_parsed.root.get(&["Service account"]) {
+            assert_eq!(e.get_password(), Some("s3cr3t"));
+        } else {
+            panic!("Expected an entry");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn create_new_database_argon2() -> Result<()> {
+        let config = DatabaseConfig {
+            kdf: KdfConfig::Argon2 {
+                memory: 1024 * 1024,
+                iterations: 2,
+                parallelism: 1,
+                version: argon2::Version::Version13,
+            },
+            ..Default::default()
+        };
+        let db = Database::new(config)?;
+        let other = Database::new(DatabaseConfig::default())?;
+        assert_ne!(db.header, other.header);
+        assert_ne!(db.root.uuid, other.root.uuid);
+
+        let res = db.dump(Some("provisioned"), None)?;
+        let db_parsed = Database::open(&mut res.as_slice(), Some("provisioned"), None)?;
+        assert_eq!(db_parsed.root, db.root);
+        assert_eq!(db_parsed.meta, db.meta);
         Ok(())
     }
 }